@@ -0,0 +1,357 @@
+//! Wire formats used to turn a [`DBson`](crate::DBson) into bytes.
+//!
+//! The codec is picked at the type level through the second type parameter of
+//! [`DBson`](crate::DBson), which defaults to [`Bson`].
+//! ```rust
+//! # #[cfg(feature = "json")]
+//! # {
+//! use dbson::{codec::Json, DBson};
+//! let data: DBson<Vec<u32>, Json> = DBson::with_codec(vec![1, 2, 3]);
+//! # }
+//! ```
+//!
+//! Every codec other than [`Bson`] lives behind a cargo feature of the same name
+//! (`msgpack`, `cbor`, `json`, `postcard` and `memcomparable`, with [`ExtJson`] also under
+//! `json`).
+//!
+//! Any binary codec can be wrapped in [`Compressed`] to compress the larger values, with the
+//! algorithms behind the `zstd`, `lz4` and `snappy` features. Small values of the same shape
+//! compress a lot better with a [trained zstd dictionary](crate::dictionary).
+//!
+//! Codecs with a [`Format::Json`] output are stored as json rather than as a blob where the
+//! database has a type for it, e.g. `jsonb` on postgres and `TEXT` on sqlite.
+
+use crate::error::{take_failed_path, Error, Tracked};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+pub(crate) mod compression;
+pub use compression::{
+    max_decompressed_size, set_max_decompressed_size, DecompressError,
+    DEFAULT_MAX_DECOMPRESSED_SIZE,
+};
+#[cfg(feature = "memcomparable")]
+mod memcomparable;
+
+/// Boxed error returned by the codecs.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The kind of bytes a [`Codec`] produces.
+///
+/// Backends use this to pick a column type, e.g. postgres stores [`Format::Json`] as `jsonb`.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Format {
+    /// Opaque bytes, stored as a blob.
+    Binary,
+    /// UTF-8 encoded JSON text.
+    Json,
+}
+
+/// A serialization format that [`DBson`](crate::DBson) can be stored as.
+pub trait Codec {
+    /// Name of the format, used in error messages.
+    const NAME: &'static str;
+
+    /// The kind of bytes produced by [`Codec::to_vec`].
+    const FORMAT: Format = Format::Binary;
+
+    /// Serialize `value` into a byte buffer.
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, BoxError>;
+
+    /// Deserialize a value previously written by [`Codec::to_vec`].
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BoxError>;
+}
+
+/// [BSON](https://bsonspec.org), the default codec.
+///
+/// Values that BSON can't represent natively are adapted on the way in and out: map keys are
+/// stored as strings (so `BTreeMap<u32, _>` works) and a top level sequence is stored as a
+/// document keyed by index.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bson;
+
+impl Codec for Bson {
+    const NAME: &'static str = "bson";
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(bson::to_vec(&crate::compat::SerializeCompat::new(value))?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BoxError> {
+        let crate::compat::DeserializeCompat(value) = bson::from_slice(bytes)?;
+        Ok(value)
+    }
+}
+
+/// [MessagePack](https://msgpack.org) using [rmp-serde](https://docs.rs/rmp-serde).
+#[cfg(feature = "msgpack")]
+#[cfg_attr(docsrs, doc(cfg(feature = "msgpack")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MsgPack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgPack {
+    const NAME: &'static str = "msgpack";
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// [CBOR](https://cbor.io) using [ciborium](https://docs.rs/ciborium).
+#[cfg(feature = "cbor")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cbor")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    const NAME: &'static str = "cbor";
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, BoxError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+}
+
+/// JSON using [serde_json](https://docs.rs/serde_json).
+#[cfg(feature = "json")]
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const NAME: &'static str = "json";
+    const FORMAT: Format = Format::Json;
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// [Relaxed Extended JSON](https://www.mongodb.com/docs/manual/reference/mongodb-extended-json/),
+/// the JSON dialect MongoDB uses to represent BSON.
+///
+/// Values are converted to BSON first, so `ObjectId` becomes `{"$oid": "..."}` and `DateTime`
+/// becomes `{"$date": "..."}` while numbers, strings and arrays stay plain JSON.
+/// This is the codec to use for postgres `jsonb` columns since the stored values can be queried
+/// and indexed with postgres' own json operators.
+/// ```rust
+/// let data = dbson::DBson::<_, dbson::codec::ExtJson>::with_codec(bson::oid::ObjectId::new());
+/// let json: serde_json::Value = serde_json::from_slice(&data.to_vec().unwrap()).unwrap();
+/// assert!(json["inner"]["$oid"].is_string());
+/// ```
+#[cfg(feature = "json")]
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExtJson;
+
+#[cfg(feature = "json")]
+impl Codec for ExtJson {
+    const NAME: &'static str = "extjson";
+    const FORMAT: Format = Format::Json;
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, BoxError> {
+        // JSON allows any value at the top level, so only the map keys need adapting
+        let bson = bson::to_bson(&crate::compat::SerializeCompat::nested(value))?;
+        Ok(serde_json::to_vec(&bson.into_relaxed_extjson())?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BoxError> {
+        let json: serde_json::Value = serde_json::from_slice(bytes)?;
+        let crate::compat::DeserializeCompat(value) = bson::from_bson(bson::Bson::try_from(json)?)?;
+        Ok(value)
+    }
+}
+
+/// [postcard](https://docs.rs/postcard), a compact non self-describing format.
+///
+/// Since postcard doesn't store field names or types, the stored bytes can only be read back
+/// with exactly the same type they were written with.
+#[cfg(feature = "postcard")]
+#[cfg_attr(docsrs, doc(cfg(feature = "postcard")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    const NAME: &'static str = "postcard";
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(postcard::to_allocvec(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+}
+
+/// An order preserving binary format: the bytes of two values compare like the values do.
+///
+/// Integers, strings, bytes, `bool`, `char`, `Option`s, tuples, sequences and maps with ordered
+/// keys (`BTreeMap`) and structs and enums with a derived `Ord` sort the same as their bytes,
+/// which sqlite and PostgreSQL compare with `memcmp`. So a
+/// `DBson<(u32, String), Memcomparable>` works as a primary key, in `ORDER BY` and as the key of
+/// an ordered key-value store. Floats sort like [`f64::total_cmp`].
+/// ```rust
+/// # #[cfg(feature = "memcomparable")]
+/// # {
+/// use dbson::{codec::Memcomparable, DBson};
+/// let key = |id: u32, name: &str| {
+///     DBson::<_, Memcomparable>::with_codec((id, name.to_string())).to_vec().unwrap()
+/// };
+/// assert!(key(1, "b") < key(2, "a"));
+/// assert!(key(2, "a") < key(2, "ab"));
+/// # }
+/// ```
+///
+/// Like [`Postcard`], the format doesn't store field names or types, so values can only be read
+/// back with the type they were written with. Structs are stored as their fields in declaration
+/// order, so reordering fields changes the sort order of the stored values.
+#[cfg(feature = "memcomparable")]
+#[cfg_attr(docsrs, doc(cfg(feature = "memcomparable")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Memcomparable;
+
+#[cfg(feature = "memcomparable")]
+impl Codec for Memcomparable {
+    const NAME: &'static str = "memcomparable";
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(memcomparable::to_vec(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(memcomparable::from_slice(bytes)?)
+    }
+}
+
+/// The bytes of the codec `C`, compressed with `A` when there are at least `MIN_SIZE` of them.
+///
+/// Every blob starts with a small header naming the algorithm, or saying the bytes of `C` follow
+/// as they are for smaller values and values which don't get smaller. Reading also accepts
+/// blobs without a header, so a column written with `C` can switch to `Compressed<A, C>`
+/// without rewriting the values already in it, and any algorithm can be read back whatever `A`
+/// is, as long as its feature is enabled. A blob decompressing to more than
+/// [`max_decompressed_size`] fails to read.
+/// ```rust
+/// # #[cfg(feature = "zstd")]
+/// # {
+/// use dbson::codec::{Compressed, Zstd};
+/// use dbson::DBson;
+/// type Zipped<T> = DBson<T, Compressed<Zstd>>;
+/// let pages = vec!["the same old page".to_string(); 100];
+/// let zipped = Zipped::with_codec(pages.clone()).to_vec().unwrap();
+/// let plain = DBson::new(pages.clone()).to_vec().unwrap();
+/// assert!(zipped.len() < plain.len() / 10);
+/// assert_eq!(Zipped::<Vec<String>>::from_slice(&zipped).unwrap().into_inner(), pages);
+/// // blobs written before compression was enabled still read
+/// assert_eq!(Zipped::<Vec<String>>::from_slice(&plain).unwrap().into_inner(), pages);
+/// # }
+/// ```
+///
+/// The [sql functions](crate::rusqlite) of the `rusqlite-functions` feature read compressed bson
+/// blobs too, so filters and indexes keep working on a compressed column. `C` should be a
+/// [`Format::Binary`] codec, the compressed bytes are always stored as a blob.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Compressed<A, C = Bson, const MIN_SIZE: usize = 256>(PhantomData<(A, C)>);
+
+impl<A: compression::Algorithm, C: Codec, const MIN_SIZE: usize> Compressed<A, C, MIN_SIZE> {
+    const NAME_BYTES: compression::NameBytes = compression::join_names(A::NAME, C::NAME);
+}
+
+impl<A: compression::Algorithm, C: Codec, const MIN_SIZE: usize> Codec
+    for Compressed<A, C, MIN_SIZE>
+{
+    const NAME: &'static str = compression::joined_name(&Self::NAME_BYTES);
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, BoxError> {
+        let bytes = C::to_vec(value)?;
+        match bytes.len() < MIN_SIZE {
+            true => Ok(compression::store(&bytes)),
+            false => compression::compress::<A>(bytes),
+        }
+    }
+
+    fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BoxError> {
+        C::from_slice(&compression::decompress(bytes)?)
+    }
+}
+
+/// [zstd](https://facebook.github.io/zstd) compression at `LEVEL`, see [`Compressed`].
+#[cfg(feature = "zstd")]
+#[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Zstd<const LEVEL: i32 = 3>;
+
+/// [zstd](https://facebook.github.io/zstd) compression at `LEVEL` with the trained dictionary
+/// `ID`, see [`dictionary`](crate::dictionary).
+///
+/// The id is written in the header of every compressed blob, so reading a blob uses the
+/// dictionary it was written with whatever `ID` the reading type has.
+#[cfg(feature = "zstd")]
+#[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ZstdDict<const ID: u32, const LEVEL: i32 = 3>;
+
+/// [LZ4](https://lz4.org) compression using [lz4_flex](https://docs.rs/lz4_flex), see
+/// [`Compressed`].
+#[cfg(feature = "lz4")]
+#[cfg_attr(docsrs, doc(cfg(feature = "lz4")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Lz4;
+
+/// [Snappy](https://google.github.io/snappy) compression using [snap](https://docs.rs/snap),
+/// see [`Compressed`].
+#[cfg(feature = "snappy")]
+#[cfg_attr(docsrs, doc(cfg(feature = "snappy")))]
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Snappy;
+
+/// Encode `value` with `C`, reporting failures against the wrapped type `T`.
+pub(crate) fn encode<T: ?Sized, C: Codec>(value: &impl Serialize) -> Result<Vec<u8>, Error> {
+    take_failed_path();
+    C::to_vec(&Tracked(value)).map_err(|source| Error::Serialize {
+        type_name: std::any::type_name::<T>(),
+        codec: C::NAME,
+        path: take_failed_path(),
+        source,
+    })
+}
+
+/// Decode `bytes` with `C`, reporting failures against the wrapped type `T`.
+pub(crate) fn decode<T: ?Sized, C: Codec, W: DeserializeOwned>(bytes: &[u8]) -> Result<W, Error> {
+    take_failed_path();
+    match C::from_slice::<Tracked<W>>(bytes) {
+        Ok(Tracked(value)) => Ok(value),
+        Err(source) => match source.downcast::<compression::DecompressError>() {
+            Ok(source) => Err(Error::Decompress {
+                type_name: std::any::type_name::<T>(),
+                codec: C::NAME,
+                len: bytes.len(),
+                source: *source,
+            }),
+            Err(source) => Err(Error::Deserialize {
+                type_name: std::any::type_name::<T>(),
+                codec: C::NAME,
+                len: bytes.len(),
+                path: take_failed_path(),
+                source,
+            }),
+        },
+    }
+}
@@ -0,0 +1,327 @@
+//! The header and algorithms of [`Compressed`](super::Compressed) blobs.
+//!
+//! Every blob written by `Compressed` is [`MAGIC`], one byte naming the algorithm and the
+//! compressed bytes, or [`STORED`] and the bytes of the inner codec for values which aren't
+//! compressed. Anything else is taken to be the bytes of the inner codec as they are, which is
+//! how blobs written before compression was enabled are read.
+
+use super::BoxError;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Start of every blob written by `Compressed`.
+///
+/// A bson document starting with these bytes would claim to be over a gigabyte long, so they
+/// can't be mistaken for a bson blob written before compression was enabled. Other codecs can
+/// start with them, which only matters for their blobs written before that.
+pub(crate) const MAGIC: [u8; 4] = *b"\xFFDBZ";
+
+/// The bytes of the inner codec, stored as they are.
+const STORED: u8 = 0;
+const ZSTD: u8 = 1;
+const LZ4: u8 = 2;
+const SNAPPY: u8 = 3;
+/// Followed by the little endian `u32` id of the dictionary.
+const ZSTD_DICT: u8 = 4;
+
+pub trait Algorithm {
+    /// The byte naming the algorithm in the header.
+    const ID: u8;
+    /// The name of the algorithm, the start of the [`Codec::NAME`](super::Codec::NAME) of a
+    /// `Compressed` codec.
+    const NAME: &'static str;
+
+    fn compress(bytes: &[u8]) -> Result<Vec<u8>, BoxError>;
+}
+
+#[cfg(feature = "zstd")]
+impl<const LEVEL: i32> Algorithm for super::Zstd<LEVEL> {
+    const ID: u8 = ZSTD;
+    const NAME: &'static str = "zstd";
+
+    fn compress(bytes: &[u8]) -> Result<Vec<u8>, BoxError> {
+        Ok(zstd::bulk::compress(bytes, LEVEL)?)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<const ID: u32, const LEVEL: i32> Algorithm for super::ZstdDict<ID, LEVEL> {
+    const ID: u8 = ZSTD_DICT;
+    const NAME: &'static str = "zstd-dict";
+
+    fn compress(bytes: &[u8]) -> Result<Vec<u8>, BoxError> {
+        let dictionary = crate::dictionary::require(ID)?;
+        let mut compressor = zstd::bulk::Compressor::with_dictionary(LEVEL, &dictionary)?;
+        // the header has the id already
+        compressor.set_parameter(zstd::zstd_safe::CParameter::DictIdFlag(false))?;
+        let mut compressed = ID.to_le_bytes().to_vec();
+        compressed.extend(compressor.compress(bytes)?);
+        Ok(compressed)
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Algorithm for super::Lz4 {
+    const ID: u8 = LZ4;
+    const NAME: &'static str = "lz4";
+
+    fn compress(bytes: &[u8]) -> Result<Vec<u8>, BoxError> {
+        Ok(lz4_flex::compress_prepend_size(bytes))
+    }
+}
+
+#[cfg(feature = "snappy")]
+impl Algorithm for super::Snappy {
+    const ID: u8 = SNAPPY;
+    const NAME: &'static str = "snappy";
+
+    fn compress(bytes: &[u8]) -> Result<Vec<u8>, BoxError> {
+        Ok(snap::raw::Encoder::new().compress_vec(bytes)?)
+    }
+}
+
+/// The default of [`set_max_decompressed_size`], 64 MiB.
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 64 << 20;
+
+static MAX_DECOMPRESSED_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_DECOMPRESSED_SIZE);
+
+/// Set the largest number of bytes a compressed blob may decompress to, reading a blob which
+/// decompresses to more fails instead of filling the memory.
+///
+/// This applies to every [`Compressed`](super::Compressed) codec and to the sql functions,
+/// [`DEFAULT_MAX_DECOMPRESSED_SIZE`] unless set.
+pub fn set_max_decompressed_size(bytes: usize) {
+    MAX_DECOMPRESSED_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+/// The largest number of bytes a compressed blob may decompress to, see
+/// [`set_max_decompressed_size`].
+pub fn max_decompressed_size() -> usize {
+    MAX_DECOMPRESSED_SIZE.load(Ordering::Relaxed)
+}
+
+/// `bytes` with a header, compressed with `A` if it makes them smaller.
+pub(crate) fn compress<A: Algorithm>(bytes: Vec<u8>) -> Result<Vec<u8>, BoxError> {
+    let compressed = A::compress(&bytes)?;
+    match compressed.len() < bytes.len() {
+        true => Ok(with_header(A::ID, &compressed)),
+        false => Ok(store(&bytes)),
+    }
+}
+
+/// `bytes` with a header saying they aren't compressed.
+pub(crate) fn store(bytes: &[u8]) -> Vec<u8> {
+    with_header(STORED, bytes)
+}
+
+fn with_header(id: u8, bytes: &[u8]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(MAGIC.len() + 1 + bytes.len());
+    blob.extend_from_slice(&MAGIC);
+    blob.push(id);
+    blob.extend_from_slice(bytes);
+    blob
+}
+
+/// The uncompressed bytes of a blob, which is borrowed if it isn't compressed.
+pub(crate) fn decompress(blob: &[u8]) -> Result<Cow<'_, [u8]>, DecompressError> {
+    let Some(rest) = blob.strip_prefix(&MAGIC) else {
+        return Ok(Cow::Borrowed(blob));
+    };
+    let Some((&id, bytes)) = rest.split_first() else {
+        return Err(DecompressError::Truncated {
+            offset: MAGIC.len(),
+        });
+    };
+    #[cfg_attr(
+        not(any(feature = "zstd", feature = "lz4", feature = "snappy")),
+        allow(unused_variables)
+    )]
+    let (max, offset) = (max_decompressed_size(), MAGIC.len() + 1);
+    match id {
+        STORED => Ok(Cow::Borrowed(bytes)),
+        #[cfg(feature = "zstd")]
+        ZSTD => {
+            let decoder = zstd::stream::read::Decoder::new(bytes).map_err(corrupt(id, offset))?;
+            read_at_most(decoder, id, offset, max)
+        }
+        #[cfg(feature = "zstd")]
+        ZSTD_DICT => {
+            let Some((dictionary, bytes)) = bytes.split_first_chunk() else {
+                return Err(DecompressError::Truncated { offset });
+            };
+            let id = u32::from_le_bytes(*dictionary);
+            let dictionary =
+                crate::dictionary::get(id).ok_or(DecompressError::MissingDictionary { id })?;
+            let offset = offset + 4;
+            let decoder = zstd::stream::read::Decoder::with_dictionary(bytes, &dictionary)
+                .map_err(corrupt(ZSTD_DICT, offset))?;
+            read_at_most(decoder, ZSTD_DICT, offset, max)
+        }
+        #[cfg(feature = "lz4")]
+        LZ4 => {
+            // the size is read from the blob, so it's checked before anything is allocated
+            let (size, bytes) =
+                lz4_flex::block::uncompressed_size(bytes).map_err(corrupt(id, offset))?;
+            check_size(size, max)?;
+            let decompressed =
+                lz4_flex::decompress(bytes, size).map_err(corrupt(id, offset + 4))?;
+            Ok(Cow::Owned(decompressed))
+        }
+        #[cfg(feature = "snappy")]
+        SNAPPY => {
+            check_size(
+                snap::raw::decompress_len(bytes).map_err(corrupt(id, offset))?,
+                max,
+            )?;
+            let decompressed = snap::raw::Decoder::new()
+                .decompress_vec(bytes)
+                .map_err(corrupt(id, offset))?;
+            Ok(Cow::Owned(decompressed))
+        }
+        id => Err(DecompressError::UnknownAlgorithm {
+            id,
+            feature: name(id),
+        }),
+    }
+}
+
+/// Why a compressed blob couldn't be decompressed, the source of an
+/// [`Error::Decompress`](crate::Error::Decompress).
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DecompressError {
+    /// The header ends before the byte at `offset`.
+    Truncated { offset: usize },
+    /// The header names an algorithm this version doesn't know (`feature` is `None`) or whose
+    /// cargo feature isn't enabled.
+    UnknownAlgorithm {
+        id: u8,
+        feature: Option<&'static str>,
+    },
+    /// The blob was compressed with a zstd dictionary which isn't registered.
+    MissingDictionary { id: u32 },
+    /// The blob decompresses to more than `max` bytes, see [`set_max_decompressed_size`].
+    TooLarge { max: usize },
+    /// The compressed bytes, starting at `offset` in the blob, are corrupt.
+    Corrupt {
+        algorithm: &'static str,
+        offset: usize,
+        source: BoxError,
+    },
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { offset } => {
+                write!(f, "the compression header is cut off at byte {offset}")
+            }
+            Self::UnknownAlgorithm {
+                feature: Some(feature),
+                ..
+            } => write!(
+                f,
+                "the blob is compressed with {feature}, enable the `{feature}` feature to read it"
+            ),
+            Self::UnknownAlgorithm { id, feature: None } => {
+                write!(f, "unknown compression algorithm {id}")
+            }
+            Self::MissingDictionary { id } => {
+                write!(f, "the zstd dictionary {id} isn't registered")
+            }
+            Self::TooLarge { max } => write!(
+                f,
+                "the blob decompresses to more than the maximum of {max} bytes"
+            ),
+            Self::Corrupt {
+                algorithm,
+                offset,
+                source,
+            } => write!(f, "corrupt {algorithm} data from byte {offset}: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for DecompressError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Corrupt { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+/// Wrap the error of the `id` decoder reading from `offset` in [`DecompressError::Corrupt`].
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "snappy"))]
+fn corrupt<E: Into<BoxError>>(id: u8, offset: usize) -> impl FnOnce(E) -> DecompressError {
+    move |source| DecompressError::Corrupt {
+        algorithm: name(id).unwrap_or("unknown"),
+        offset,
+        source: source.into(),
+    }
+}
+
+/// Everything the `id` decoder `reader` decompresses from `offset`, failing once it's more than
+/// `max` bytes.
+#[cfg(feature = "zstd")]
+fn read_at_most(
+    reader: impl std::io::Read,
+    id: u8,
+    offset: usize,
+    max: usize,
+) -> Result<Cow<'static, [u8]>, DecompressError> {
+    let mut decompressed = Vec::new();
+    let limit = u64::try_from(max).unwrap_or(u64::MAX).saturating_add(1);
+    std::io::Read::read_to_end(&mut reader.take(limit), &mut decompressed)
+        .map_err(corrupt(id, offset))?;
+    check_size(decompressed.len(), max)?;
+    Ok(Cow::Owned(decompressed))
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "snappy"))]
+fn check_size(size: usize, max: usize) -> Result<(), DecompressError> {
+    match size > max {
+        true => Err(DecompressError::TooLarge { max }),
+        false => Ok(()),
+    }
+}
+
+/// The bytes of `"{algorithm}+{codec}"` and their length, the name of a `Compressed` codec.
+pub(crate) type NameBytes = ([u8; 64], usize);
+
+pub(crate) const fn join_names(algorithm: &str, codec: &str) -> NameBytes {
+    let (algorithm, codec) = (algorithm.as_bytes(), codec.as_bytes());
+    let mut name = [0; 64];
+    let len = algorithm.len() + 1 + codec.len();
+    assert!(
+        len <= name.len(),
+        "the name of the compressed codec is too long"
+    );
+    let mut i = 0;
+    while i < len {
+        name[i] = match i {
+            i if i < algorithm.len() => algorithm[i],
+            i if i == algorithm.len() => b'+',
+            i => codec[i - algorithm.len() - 1],
+        };
+        i += 1;
+    }
+    (name, len)
+}
+
+pub(crate) const fn joined_name(name: &'static NameBytes) -> &'static str {
+    match std::str::from_utf8(name.0.split_at(name.1).0) {
+        Ok(name) => name,
+        Err(_) => panic!("codec names are utf-8"),
+    }
+}
+
+fn name(id: u8) -> Option<&'static str> {
+    match id {
+        ZSTD | ZSTD_DICT => Some("zstd"),
+        LZ4 => Some("lz4"),
+        SNAPPY => Some("snappy"),
+        _ => None,
+    }
+}
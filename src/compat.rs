@@ -0,0 +1,1015 @@
+//! Serde adapters that let any serde data model round trip through BSON.
+//!
+//! BSON only allows string keys and requires a document at the top level.
+//! When writing, map keys are turned into strings (`1` becomes `"1"`) and a top level sequence
+//! is written as a document keyed by index, which is exactly how BSON encodes arrays.
+//! When reading, the keys are parsed back into whatever type the map expects and a top level
+//! document is accepted wherever a sequence is expected.
+
+use serde::de::{
+    self, DeserializeSeed, Deserializer, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use serde::ser::{self, Error as _, Serialize, Serializer};
+use std::fmt;
+
+/// Serializes `T` in a way that is always representable as a BSON document.
+pub(crate) struct SerializeCompat<'a, T: ?Sized> {
+    value: &'a T,
+    top_level: bool,
+}
+
+impl<'a, T: ?Sized> SerializeCompat<'a, T> {
+    pub(crate) fn new(value: &'a T) -> Self {
+        Self {
+            value,
+            top_level: true,
+        }
+    }
+
+    /// Only adapt the map keys, leaving the top level as it is.
+    pub(crate) fn nested(value: &'a T) -> Self {
+        Self {
+            value,
+            top_level: false,
+        }
+    }
+}
+
+impl<T: Serialize + ?Sized> Serialize for SerializeCompat<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(CompatSerializer {
+            inner: serializer,
+            top_level: self.top_level,
+        })
+    }
+}
+
+struct Key<'a, T: ?Sized>(&'a T);
+
+impl<T: Serialize + ?Sized> Serialize for Key<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(KeySerializer(serializer))
+    }
+}
+
+struct CompatSerializer<S> {
+    inner: S,
+    top_level: bool,
+}
+
+macro_rules! forward_serialize {
+    ($($method: ident($ty: ty)),* $(,)?) => {
+        $(
+            fn $method(self, v: $ty) -> Result<Self::Ok, Self::Error> {
+                self.inner.$method(v)
+            }
+        )*
+    };
+}
+
+impl<S: Serializer> Serializer for CompatSerializer<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = CompatSeq<S::SerializeSeq, S::SerializeMap>;
+    type SerializeTuple = CompatSeq<S::SerializeTuple, S::SerializeMap>;
+    type SerializeTupleStruct = CompatSeq<S::SerializeTupleStruct, S::SerializeMap>;
+    type SerializeTupleVariant = Compat<S::SerializeTupleVariant>;
+    type SerializeMap = Compat<S::SerializeMap>;
+    type SerializeStruct = Compat<S::SerializeStruct>;
+    type SerializeStructVariant = Compat<S::SerializeStructVariant>;
+
+    forward_serialize! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+        serialize_unit_struct(&'static str),
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_none()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_some(&SerializeCompat::nested(value))
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.inner
+            .serialize_unit_variant(name, variant_index, variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        if self.top_level {
+            // Keep the top level so `struct Ids(Vec<u32>)` is laid out like a `Vec<u32>`.
+            self.inner
+                .serialize_newtype_struct(name, &SerializeCompat::new(value))
+        } else {
+            self.inner
+                .serialize_newtype_struct(name, &SerializeCompat::nested(value))
+        }
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_newtype_variant(
+            name,
+            variant_index,
+            variant,
+            &SerializeCompat::nested(value),
+        )
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        if self.top_level {
+            Ok(CompatSeq::Indexed(self.inner.serialize_map(len)?, 0))
+        } else {
+            Ok(CompatSeq::Seq(self.inner.serialize_seq(len)?))
+        }
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        if self.top_level {
+            Ok(CompatSeq::Indexed(self.inner.serialize_map(Some(len))?, 0))
+        } else {
+            Ok(CompatSeq::Seq(self.inner.serialize_tuple(len)?))
+        }
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        if self.top_level {
+            Ok(CompatSeq::Indexed(self.inner.serialize_map(Some(len))?, 0))
+        } else {
+            Ok(CompatSeq::Seq(
+                self.inner.serialize_tuple_struct(name, len)?,
+            ))
+        }
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        self.inner
+            .serialize_tuple_variant(name, variant_index, variant, len)
+            .map(Compat)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        self.inner.serialize_map(len).map(Compat)
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        self.inner.serialize_struct(name, len).map(Compat)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        self.inner
+            .serialize_struct_variant(name, variant_index, variant, len)
+            .map(Compat)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+/// A sequence, or a top level sequence written as a document keyed by index.
+enum CompatSeq<S, M> {
+    Seq(S),
+    Indexed(M, usize),
+}
+
+fn serialize_indexed<M: ser::SerializeMap, T: Serialize + ?Sized>(
+    map: &mut M,
+    index: &mut usize,
+    value: &T,
+) -> Result<(), M::Error> {
+    map.serialize_entry(&index.to_string(), &SerializeCompat::nested(value))?;
+    *index += 1;
+    Ok(())
+}
+
+macro_rules! impl_compat_seq {
+    ($($trait: ident::$method: ident),*) => {
+        $(
+            impl<S, M> ser::$trait for CompatSeq<S, M>
+            where
+                S: ser::$trait,
+                M: ser::SerializeMap<Ok = S::Ok, Error = S::Error>,
+            {
+                type Ok = S::Ok;
+                type Error = S::Error;
+
+                fn $method<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+                    match self {
+                        Self::Seq(seq) => seq.$method(&SerializeCompat::nested(value)),
+                        Self::Indexed(map, index) => serialize_indexed(map, index, value),
+                    }
+                }
+
+                fn end(self) -> Result<Self::Ok, Self::Error> {
+                    match self {
+                        Self::Seq(seq) => seq.end(),
+                        Self::Indexed(map, _) => map.end(),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_compat_seq!(
+    SerializeSeq::serialize_element,
+    SerializeTuple::serialize_element,
+    SerializeTupleStruct::serialize_field
+);
+
+/// Wraps every nested value of a compound type.
+struct Compat<S>(S);
+
+impl<S: ser::SerializeTupleVariant> ser::SerializeTupleVariant for Compat<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.0.serialize_field(&SerializeCompat::nested(value))
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.0.end()
+    }
+}
+
+impl<S: ser::SerializeMap> ser::SerializeMap for Compat<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Self::Error> {
+        self.0.serialize_key(&Key(key))
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.0.serialize_value(&SerializeCompat::nested(value))
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.0.end()
+    }
+}
+
+impl<S: ser::SerializeStruct> ser::SerializeStruct for Compat<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.0.serialize_field(key, &SerializeCompat::nested(value))
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        self.0.skip_field(key)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.0.end()
+    }
+}
+
+impl<S: ser::SerializeStructVariant> ser::SerializeStructVariant for Compat<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.0.serialize_field(key, &SerializeCompat::nested(value))
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        self.0.skip_field(key)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.0.end()
+    }
+}
+
+/// Writes map keys as strings.
+struct KeySerializer<S>(S);
+
+macro_rules! serialize_key_to_string {
+    ($($method: ident($ty: ty)),* $(,)?) => {
+        $(
+            fn $method(self, v: $ty) -> Result<Self::Ok, Self::Error> {
+                self.0.serialize_str(&v.to_string())
+            }
+        )*
+    };
+}
+
+impl<S: Serializer> KeySerializer<S> {
+    fn unsupported(kind: &str) -> S::Error {
+        S::Error::custom(format_args!("{kind} cannot be used as a map key"))
+    }
+}
+
+impl<S: Serializer> Serializer for KeySerializer<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = ser::Impossible<S::Ok, S::Error>;
+    type SerializeTuple = ser::Impossible<S::Ok, S::Error>;
+    type SerializeTupleStruct = ser::Impossible<S::Ok, S::Error>;
+    type SerializeTupleVariant = ser::Impossible<S::Ok, S::Error>;
+    type SerializeMap = ser::Impossible<S::Ok, S::Error>;
+    type SerializeStruct = ser::Impossible<S::Ok, S::Error>;
+    type SerializeStructVariant = ser::Impossible<S::Ok, S::Error>;
+
+    serialize_key_to_string! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        self.0.serialize_str(v)
+    }
+
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Err(Self::unsupported("bytes"))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Err(Self::unsupported("none"))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _: &T) -> Result<Self::Ok, Self::Error> {
+        Err(Self::unsupported("an option"))
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Err(Self::unsupported("unit"))
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Self::Ok, Self::Error> {
+        Err(Self::unsupported(name))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.0.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        Err(Self::unsupported(name))
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(Self::unsupported("a sequence"))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Err(Self::unsupported("a tuple"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(Self::unsupported(name))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(Self::unsupported(name))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Err(Self::unsupported("a map"))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Err(Self::unsupported(name))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(Self::unsupported(name))
+    }
+}
+
+/// Deserializes `T` from data written through [`SerializeCompat`].
+pub(crate) struct DeserializeCompat<T>(pub(crate) T);
+
+impl<'de, T: de::Deserialize<'de>> de::Deserialize<'de> for DeserializeCompat<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(CompatDeserializer {
+            inner: deserializer,
+            top_level: true,
+        })
+        .map(DeserializeCompat)
+    }
+}
+
+struct CompatDeserializer<D> {
+    inner: D,
+    top_level: bool,
+}
+
+impl<D> CompatDeserializer<D> {
+    fn nested(inner: D) -> Self {
+        Self {
+            inner,
+            top_level: false,
+        }
+    }
+}
+
+macro_rules! forward_deserialize {
+    ($($method: ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.inner.$method(CompatVisitor::nested(visitor))
+            }
+        )*
+    };
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for CompatDeserializer<D> {
+    type Error = D::Error;
+
+    forward_deserialize! {
+        deserialize_any,
+        deserialize_bool,
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_i128,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_u128,
+        deserialize_f32,
+        deserialize_f64,
+        deserialize_char,
+        deserialize_str,
+        deserialize_string,
+        deserialize_bytes,
+        deserialize_byte_buf,
+        deserialize_option,
+        deserialize_unit,
+        deserialize_map,
+        deserialize_identifier,
+        deserialize_ignored_any,
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.inner
+            .deserialize_unit_struct(name, CompatVisitor::nested(visitor))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.inner.deserialize_newtype_struct(
+            name,
+            CompatVisitor {
+                inner: visitor,
+                top_level: self.top_level,
+            },
+        )
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.top_level {
+            self.inner.deserialize_map(IndexedVisitor(visitor))
+        } else {
+            self.inner.deserialize_seq(CompatVisitor::nested(visitor))
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        if self.top_level {
+            self.inner.deserialize_map(IndexedVisitor(visitor))
+        } else {
+            self.inner
+                .deserialize_tuple(len, CompatVisitor::nested(visitor))
+        }
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        if self.top_level {
+            self.inner.deserialize_map(IndexedVisitor(visitor))
+        } else {
+            self.inner
+                .deserialize_tuple_struct(name, len, CompatVisitor::nested(visitor))
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.inner
+            .deserialize_struct(name, fields, CompatVisitor::nested(visitor))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.inner
+            .deserialize_enum(name, variants, CompatVisitor::nested(visitor))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+struct CompatVisitor<V> {
+    inner: V,
+    top_level: bool,
+}
+
+impl<V> CompatVisitor<V> {
+    fn nested(inner: V) -> Self {
+        Self {
+            inner,
+            top_level: false,
+        }
+    }
+}
+
+macro_rules! forward_visit {
+    ($($method: ident($ty: ty)),* $(,)?) => {
+        $(
+            fn $method<E: de::Error>(self, v: $ty) -> Result<Self::Value, E> {
+                self.inner.$method(v)
+            }
+        )*
+    };
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for CompatVisitor<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.expecting(formatter)
+    }
+
+    forward_visit! {
+        visit_bool(bool),
+        visit_i8(i8),
+        visit_i16(i16),
+        visit_i32(i32),
+        visit_i64(i64),
+        visit_i128(i128),
+        visit_u8(u8),
+        visit_u16(u16),
+        visit_u32(u32),
+        visit_u64(u64),
+        visit_u128(u128),
+        visit_f32(f32),
+        visit_f64(f64),
+        visit_char(char),
+        visit_str(&str),
+        visit_borrowed_str(&'de str),
+        visit_string(String),
+        visit_bytes(&[u8]),
+        visit_borrowed_bytes(&'de [u8]),
+        visit_byte_buf(Vec<u8>),
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_none()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.inner
+            .visit_some(CompatDeserializer::nested(deserializer))
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        self.inner.visit_newtype_struct(CompatDeserializer {
+            inner: deserializer,
+            top_level: self.top_level,
+        })
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        self.inner.visit_seq(CompatSeqAccess(seq))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        self.inner.visit_map(CompatMapAccess(map))
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        self.inner.visit_enum(CompatEnumAccess(data))
+    }
+}
+
+/// Reads a top level document keyed by index (or a plain sequence) as a sequence.
+struct IndexedVisitor<V>(V);
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for IndexedVisitor<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.0.expecting(formatter)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        self.0.visit_seq(IndexedSeqAccess(map))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        self.0.visit_seq(CompatSeqAccess(seq))
+    }
+}
+
+struct IndexedSeqAccess<A>(A);
+
+impl<'de, A: MapAccess<'de>> SeqAccess<'de> for IndexedSeqAccess<A> {
+    type Error = A::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        match self.0.next_key::<de::IgnoredAny>()? {
+            Some(_) => self.0.next_value_seed(CompatSeed(seed)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.0.size_hint()
+    }
+}
+
+struct CompatSeed<S>(S);
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for CompatSeed<S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.0.deserialize(CompatDeserializer::nested(deserializer))
+    }
+}
+
+struct KeySeed<S>(S);
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for KeySeed<S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.0.deserialize(KeyDeserializer(deserializer))
+    }
+}
+
+struct CompatSeqAccess<A>(A);
+
+impl<'de, A: SeqAccess<'de>> SeqAccess<'de> for CompatSeqAccess<A> {
+    type Error = A::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        self.0.next_element_seed(CompatSeed(seed))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.0.size_hint()
+    }
+}
+
+struct CompatMapAccess<A>(A);
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for CompatMapAccess<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        self.0.next_key_seed(KeySeed(seed))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.next_value_seed(CompatSeed(seed))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.0.size_hint()
+    }
+}
+
+struct CompatEnumAccess<A>(A);
+
+impl<'de, A: EnumAccess<'de>> EnumAccess<'de> for CompatEnumAccess<A> {
+    type Error = A::Error;
+    type Variant = CompatVariantAccess<A::Variant>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), Self::Error> {
+        self.0
+            .variant_seed(seed)
+            .map(|(value, variant)| (value, CompatVariantAccess(variant)))
+    }
+}
+
+struct CompatVariantAccess<A>(A);
+
+impl<'de, A: VariantAccess<'de>> VariantAccess<'de> for CompatVariantAccess<A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        self.0.unit_variant()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, Self::Error> {
+        self.0.newtype_variant_seed(CompatSeed(seed))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.tuple_variant(len, CompatVisitor::nested(visitor))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0
+            .struct_variant(fields, CompatVisitor::nested(visitor))
+    }
+}
+
+/// Reads map keys written by [`KeySerializer`] back into the type the map expects.
+struct KeyDeserializer<D>(D);
+
+#[derive(Clone, Copy)]
+enum KeyKind {
+    Bool,
+    Signed,
+    Signed128,
+    Unsigned,
+    Unsigned128,
+    Float,
+}
+
+macro_rules! deserialize_parsed_key {
+    ($($method: ident => $kind: ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.0.deserialize_str(ParsedKeyVisitor {
+                    inner: visitor,
+                    kind: KeyKind::$kind,
+                })
+            }
+        )*
+    };
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for KeyDeserializer<D> {
+    type Error = D::Error;
+
+    deserialize_parsed_key! {
+        deserialize_bool => Bool,
+        deserialize_i8 => Signed,
+        deserialize_i16 => Signed,
+        deserialize_i32 => Signed,
+        deserialize_i64 => Signed,
+        deserialize_i128 => Signed128,
+        deserialize_u8 => Unsigned,
+        deserialize_u16 => Unsigned,
+        deserialize_u32 => Unsigned,
+        deserialize_u64 => Unsigned,
+        deserialize_u128 => Unsigned128,
+        deserialize_f32 => Float,
+        deserialize_f64 => Float,
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.0.deserialize_any(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.deserialize_str(EnumKeyVisitor(visitor))
+    }
+
+    serde::forward_to_deserialize_any! {
+        char str string bytes byte_buf option unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct ParsedKeyVisitor<V> {
+    inner: V,
+    kind: KeyKind,
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for ParsedKeyVisitor<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.expecting(formatter)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        // Keys that fail to parse are handed over as they are so the visitor reports the error.
+        match self.kind {
+            KeyKind::Bool => match v.parse() {
+                Ok(v) => self.inner.visit_bool(v),
+                Err(_) => self.inner.visit_str(v),
+            },
+            KeyKind::Signed => match v.parse() {
+                Ok(v) => self.inner.visit_i64(v),
+                Err(_) => self.inner.visit_str(v),
+            },
+            KeyKind::Signed128 => match v.parse() {
+                Ok(v) => self.inner.visit_i128(v),
+                Err(_) => self.inner.visit_str(v),
+            },
+            KeyKind::Unsigned => match v.parse() {
+                Ok(v) => self.inner.visit_u64(v),
+                Err(_) => self.inner.visit_str(v),
+            },
+            KeyKind::Unsigned128 => match v.parse() {
+                Ok(v) => self.inner.visit_u128(v),
+                Err(_) => self.inner.visit_str(v),
+            },
+            KeyKind::Float => match v.parse() {
+                Ok(v) => self.inner.visit_f64(v),
+                Err(_) => self.inner.visit_str(v),
+            },
+        }
+    }
+}
+
+struct EnumKeyVisitor<V>(V);
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for EnumKeyVisitor<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.0.expecting(formatter)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        self.0.visit_enum(v.into_deserializer())
+    }
+}
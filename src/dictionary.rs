@@ -0,0 +1,87 @@
+//! Trained [zstd](https://facebook.github.io/zstd) dictionaries, used by the
+//! [`ZstdDict`](crate::codec::ZstdDict) algorithm of [`Compressed`](crate::codec::Compressed).
+//!
+//! Small documents of the same shape barely compress on their own, since most of what they
+//! have in common is in every one of them. A dictionary trained from a sample of them holds
+//! those common parts once, so each document only stores what's different.
+//!
+//! Dictionaries are stored in the [`TABLE`] table, next to the values they compress, and used
+//! from a registry shared by the whole process: a dictionary has to be
+//! [registered](register) (or loaded from its table) before a value compressed with it can be
+//! written or read. The dictionary id is part of the type of the column,
+//! `DBson<T, Compressed<ZstdDict<ID>>>`, and of every blob compressed with it, so a column can
+//! move to a newly trained dictionary while its older values stay readable with the old one.
+//! ```rust
+//! use dbson::codec::{Compressed, ZstdDict};
+//! use dbson::DBson;
+//! #[derive(serde::Serialize, serde::Deserialize)]
+//! struct Event {
+//!     kind: String,
+//!     user: String,
+//!     at: i64,
+//! }
+//! let event = |i: i64| Event {
+//!     kind: ["open", "close"][i as usize % 2].into(),
+//!     user: format!("user{}", i % 50),
+//!     at: 1_700_000_000 + i,
+//! };
+//! let samples: Vec<_> = (0..500).map(|i| DBson::new(event(i)).to_vec().unwrap()).collect();
+//! dbson::dictionary::register(1, dbson::dictionary::train(&samples, 4096).unwrap());
+//!
+//! type Stored = DBson<Event, Compressed<ZstdDict<1>, dbson::codec::Bson, 0>>;
+//! let plain = DBson::new(event(1234)).to_vec().unwrap();
+//! let compressed = Stored::with_codec(event(1234)).to_vec().unwrap();
+//! assert!(compressed.len() < plain.len() * 2 / 3);
+//! assert_eq!(Stored::from_slice(&compressed).unwrap().into_inner().at, 1_700_001_234);
+//! // without the dictionary the event doesn't get any smaller, so it's stored as it is after the header
+//! let zstd = DBson::<_, Compressed<dbson::codec::Zstd, dbson::codec::Bson, 0>>::with_codec(event(1234));
+//! assert_eq!(zstd.to_vec().unwrap()[5..], plain);
+//! ```
+//!
+//! With rusqlite, [`train_dictionary`](crate::rusqlite::train_dictionary) and
+//! [`load_dictionaries`](crate::rusqlite::load_dictionaries) do the sampling and the storage.
+
+use crate::codec::{compression, BoxError};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// The table dictionaries are stored in, with an `id` and a `dictionary` blob column.
+pub const TABLE: &str = "dbson_dictionaries";
+
+fn registry() -> &'static RwLock<HashMap<u32, Arc<[u8]>>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<u32, Arc<[u8]>>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Train a dictionary of at most `max_size` bytes from sample blobs of a column.
+///
+/// The samples can be written with any codec, compressed blobs are decompressed first. zstd
+/// needs a few hundred samples to train a useful dictionary and fails with too few of them; a
+/// few kilobytes to a hundred kilobytes is the usual dictionary size.
+pub fn train(samples: &[impl AsRef<[u8]>], max_size: usize) -> Result<Vec<u8>, BoxError> {
+    let samples = samples
+        .iter()
+        .map(|sample| compression::decompress(sample.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(zstd::dict::from_samples(&samples, max_size)?)
+}
+
+/// Make the dictionary `id` available to the [`ZstdDict`](crate::codec::ZstdDict) codecs,
+/// replacing any dictionary registered with the same id.
+pub fn register(id: u32, dictionary: impl Into<Arc<[u8]>>) {
+    let mut registry = registry().write().unwrap_or_else(|e| e.into_inner());
+    registry.insert(id, dictionary.into());
+}
+
+/// The dictionary registered as `id`.
+pub fn get(id: u32) -> Option<Arc<[u8]>> {
+    let registry = registry().read().unwrap_or_else(|e| e.into_inner());
+    registry.get(&id).cloned()
+}
+
+/// The dictionary `id`, failing with a message saying how to register it.
+pub(crate) fn require(id: u32) -> Result<Arc<[u8]>, BoxError> {
+    get(id).ok_or_else(|| {
+        format!("the zstd dictionary {id} isn't registered, load it from `{TABLE}` first").into()
+    })
+}
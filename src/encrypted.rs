@@ -0,0 +1,63 @@
+//! Field-level encryption with `#[serde(with = "dbson::encrypted")]`.
+//!
+//! A field serialized with this module is stored like an
+//! [`Encrypted`](crate::encryption::Encrypted) field, as BSON binary subtype 6, without
+//! changing its type. [`deterministic`] encrypts with
+//! [`Aes256GcmSiv`](crate::encryption::Aes256GcmSiv) instead, for fields looked up by
+//! equality.
+//! ```rust
+//! use dbson::encryption::KeyRing;
+//! use dbson::DBsonDoc;
+//! #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+//! struct Card {
+//!     holder: String,
+//!     #[serde(with = "dbson::encrypted")]
+//!     number: String,
+//!     #[serde(with = "dbson::encrypted::deterministic")]
+//!     last_four: u16,
+//! }
+//! dbson::encryption::set_key_provider(KeyRing::new("1", [3; 32]));
+//! let card = Card { holder: "Ann".into(), number: "4111111111111111".into(), last_four: 1111 };
+//! let document = bson::to_document(&card).unwrap();
+//! assert_eq!(document.get_str("holder").unwrap(), "Ann");
+//! let Some(bson::Bson::Binary(number)) = document.get("number") else { unreachable!() };
+//! assert_eq!(number.subtype, bson::spec::BinarySubtype::Encrypted);
+//!
+//! let blob = DBsonDoc::new(card).to_vec().unwrap();
+//! assert!(!blob.windows(16).any(|w| w == b"4111111111111111"));
+//! let card = DBsonDoc::<Card>::from_slice(&blob).unwrap().into_inner();
+//! assert_eq!(card.last_four, 1111);
+//! ```
+
+use crate::encryption::{Aes256Gcm, Aes256GcmSiv, Encrypted};
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+
+pub fn serialize<T: Serialize, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    Encrypted::<&T, Aes256Gcm>::with_cipher(value).serialize(serializer)
+}
+
+pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    Encrypted::<T>::deserialize(deserializer).map(Encrypted::into_inner)
+}
+
+/// Deterministic field-level encryption with
+/// `#[serde(with = "dbson::encrypted::deterministic")]`.
+pub mod deterministic {
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Encrypted::<&T, Aes256GcmSiv>::with_cipher(value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        Encrypted::<T, Aes256GcmSiv>::deserialize(deserializer).map(Encrypted::into_inner)
+    }
+}
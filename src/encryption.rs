@@ -0,0 +1,537 @@
+//! Authenticated encryption of stored values, see [`EncryptedDBson`].
+//!
+//! Values are encrypted with AES-256-GCM ([`Aes256Gcm`]), XChaCha20-Poly1305
+//! ([`XChaCha20Poly1305`]) or deterministically with AES-256-GCM-SIV ([`Aes256GcmSiv`]) under keys handed out by a [`KeyProvider`]. An encrypted blob starts
+//! with a header naming the cipher, the id of the key and the nonce, followed by the encrypted
+//! [`DBson`] bytes of the value and the authentication tag:
+//!
+//! | bytes | content |
+//! |-------|---------|
+//! | 4 | `FF 44 42 45` (`\xFFDBE`) |
+//! | 1 | the cipher, `1` for AES-256-GCM, `2` for XChaCha20-Poly1305 and `3` for AES-256-GCM-SIV |
+//! | 1 | the length of the key id |
+//! | .. | the key id, in UTF-8 |
+//! | 12 or 24 | the nonce |
+//! | .. | the ciphertext and the 16 byte tag |
+//!
+//! The header is authenticated along with the optional associated data of the value, so
+//! neither can be changed without decryption failing. Associated data isn't stored: binding a
+//! value to its table and row id (`b"users/42"`) means a blob copied to another row, or
+//! another table, can't be decrypted there.
+//!
+//! Keys are rotated with a [`KeyRing`]: new values are encrypted with its current key while
+//! the older ones still decrypt what was written before, until a [`Reencryption`] has moved
+//! every blob of a column to the current key.
+//!
+//! [`EncryptedDBson`] encrypts a whole value, which hides it from the `bson_*` sql functions
+//! as well. [`Encrypted`] fields, or fields serialized
+//! [`with = "dbson::encrypted"`](crate::encrypted), are encrypted on their own inside of a
+//! document that stays queryable.
+
+use crate::codec::{self, BoxError, Codec};
+use crate::{DBson, Error};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, OnceLock, RwLock};
+
+mod field;
+mod key_ring;
+mod reencryption;
+
+pub use field::Encrypted;
+pub use key_ring::KeyRing;
+pub use reencryption::{Reencrypted, Reencryption, CHECKPOINT_TABLE};
+
+/// Start of every encrypted blob.
+const MAGIC: [u8; 4] = *b"\xFFDBE";
+
+/// A 256 bit key.
+pub type Key = [u8; 32];
+
+/// Where [`EncryptedDBson`] gets its keys from.
+/// ```rust
+/// use dbson::codec::BoxError;
+/// use dbson::encryption::{Key, KeyProvider};
+/// struct FromEnv;
+///
+/// impl KeyProvider for FromEnv {
+///     fn current_key_id(&self) -> Result<String, BoxError> {
+///         Ok(std::env::var("DB_KEY_ID")?)
+///     }
+///
+///     fn key(&self, id: &str) -> Result<Key, BoxError> {
+///         let hex = std::env::var(format!("DB_KEY_{id}"))?;
+///         let bytes: Vec<u8> = (0..hex.len())
+///             .step_by(2)
+///             .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+///             .collect::<Result<_, _>>()?;
+///         Ok(bytes.try_into().map_err(|_| "keys are 32 bytes")?)
+///     }
+/// }
+/// dbson::encryption::set_key_provider(FromEnv);
+/// ```
+pub trait KeyProvider: Send + Sync {
+    /// The id of the key new values are encrypted with, at most 255 bytes long.
+    fn current_key_id(&self) -> Result<String, BoxError>;
+
+    /// The key `id`, for values encrypted with it now or at any point in the past.
+    fn key(&self, id: &str) -> Result<Key, BoxError>;
+}
+
+impl<K: KeyProvider + ?Sized> KeyProvider for Arc<K> {
+    fn current_key_id(&self) -> Result<String, BoxError> {
+        (**self).current_key_id()
+    }
+
+    fn key(&self, id: &str) -> Result<Key, BoxError> {
+        (**self).key(id)
+    }
+}
+
+fn provider() -> &'static RwLock<Option<Arc<dyn KeyProvider>>> {
+    static PROVIDER: OnceLock<RwLock<Option<Arc<dyn KeyProvider>>>> = OnceLock::new();
+    PROVIDER.get_or_init(Default::default)
+}
+
+/// Use `keys` for every [`EncryptedDBson`] encrypted or decrypted without a provider of its
+/// own, replacing the provider set before.
+pub fn set_key_provider(keys: impl KeyProvider + 'static) {
+    let mut provider = provider().write().unwrap_or_else(|e| e.into_inner());
+    *provider = Some(Arc::new(keys));
+}
+
+fn key_provider() -> Result<Arc<dyn KeyProvider>, BoxError> {
+    let provider = provider().read().unwrap_or_else(|e| e.into_inner());
+    provider
+        .clone()
+        .ok_or_else(|| "no key provider, see `dbson::encryption::set_key_provider`".into())
+}
+
+/// The id of the key `bytes` were encrypted with, `None` if they aren't an encrypted blob.
+pub fn key_id(bytes: &[u8]) -> Option<&str> {
+    Header::parse(bytes).ok().map(|(header, _)| header.key_id)
+}
+
+/// An authenticated cipher used by [`EncryptedDBson`].
+pub trait Cipher: sealed::Cipher {}
+
+impl Cipher for Aes256Gcm {}
+
+impl Cipher for XChaCha20Poly1305 {}
+
+impl Cipher for Aes256GcmSiv {}
+
+/// AES-256 in Galois/Counter Mode, with random 96 bit nonces.
+///
+/// The fastest choice where the CPU has AES instructions. Random nonces this short shouldn't
+/// encrypt more than a few billion values with the same key.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Aes256Gcm;
+
+/// XChaCha20-Poly1305, with random 192 bit nonces.
+///
+/// Fast without dedicated instructions, and the nonces are long enough that a key can encrypt
+/// any number of values.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct XChaCha20Poly1305;
+
+/// AES-256-GCM-SIV with a fixed nonce: deterministic encryption.
+///
+/// The same value encrypted with the same key and associated data always gives the same blob,
+/// so encrypted values can be looked up by equality, see [`Encrypted`]. That equality is all it
+/// reveals, but it's revealed to anyone who can read the blobs: values with few possible
+/// plaintexts (a boolean, a country) are better encrypted with one of the other ciphers. The
+/// key is derived from the provider's key, so the same key can be used with every cipher.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Aes256GcmSiv;
+
+mod sealed {
+    pub trait Cipher {
+        /// The byte naming the cipher in the header.
+        const ID: u8;
+    }
+
+    impl Cipher for super::Aes256Gcm {
+        const ID: u8 = super::AES_256_GCM;
+    }
+
+    impl Cipher for super::XChaCha20Poly1305 {
+        const ID: u8 = super::XCHACHA20_POLY1305;
+    }
+
+    impl Cipher for super::Aes256GcmSiv {
+        const ID: u8 = super::AES_256_GCM_SIV;
+    }
+}
+
+const AES_256_GCM: u8 = 1;
+const XCHACHA20_POLY1305: u8 = 2;
+const AES_256_GCM_SIV: u8 = 3;
+
+const AES_256_GCM_NONCE_LEN: usize = 12;
+const XCHACHA20_POLY1305_NONCE_LEN: usize = 24;
+const AES_256_GCM_SIV_NONCE_LEN: usize = 12;
+
+/// The AES-256-GCM-SIV key derived from `key`.
+///
+/// AES-GCM uses the encryption of the zero block as its hash key, which AES-GCM-SIV with a zero
+/// nonce would use as a message key: the two can't share a key.
+fn siv_key(key: &Key) -> Key {
+    use aes_gcm::aes::cipher::{BlockEncrypt, KeyInit};
+    let aes = aes_gcm::aes::Aes256::new(key.into());
+    let mut derived = *b"dbson siv key\0\0\x01dbson siv key\0\0\x02";
+    let (first, second) = derived.split_at_mut(16);
+    aes.encrypt_block(first.into());
+    aes.encrypt_block(second.into());
+    derived
+}
+
+/// The header of an encrypted blob.
+struct Header<'a> {
+    cipher: u8,
+    key_id: &'a str,
+}
+
+impl<'a> Header<'a> {
+    fn to_vec(&self) -> Result<Vec<u8>, BoxError> {
+        let len = u8::try_from(self.key_id.len())
+            .map_err(|_| format!("the key id `{}` is over 255 bytes long", self.key_id))?;
+        let mut header = MAGIC.to_vec();
+        header.extend([self.cipher, len]);
+        header.extend_from_slice(self.key_id.as_bytes());
+        Ok(header)
+    }
+
+    /// The header and the rest of the blob, starting with the nonce.
+    fn parse(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), BoxError> {
+        let rest = bytes
+            .strip_prefix(&MAGIC)
+            .ok_or("the blob isn't encrypted")?;
+        let [cipher, len, rest @ ..] = rest else {
+            return Err("the encryption header is truncated".into());
+        };
+        if rest.len() < usize::from(*len) {
+            return Err("the encryption header is truncated".into());
+        }
+        let (key_id, rest) = rest.split_at(usize::from(*len));
+        let key_id = std::str::from_utf8(key_id)?;
+        let cipher = *cipher;
+        Ok((Self { cipher, key_id }, rest))
+    }
+}
+
+/// Decrypt the blob `bytes`, authenticated along with `associated_data`.
+pub(crate) fn decrypt(
+    keys: &dyn KeyProvider,
+    bytes: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, BoxError> {
+    let (header, rest) = Header::parse(bytes)?;
+    let aad = [&bytes[..bytes.len() - rest.len()], associated_data].concat();
+    let key = keys.key(header.key_id)?;
+    let open = |nonce_len: usize| {
+        if rest.len() < nonce_len {
+            return Err(BoxError::from("the encrypted blob is truncated"));
+        }
+        let (nonce, msg) = rest.split_at(nonce_len);
+        Ok((nonce, Payload { msg, aad: &aad }))
+    };
+    let failed = |_| BoxError::from(format!("unable to decrypt with key `{}`", header.key_id));
+    match header.cipher {
+        AES_256_GCM => {
+            let (nonce, payload) = open(AES_256_GCM_NONCE_LEN)?;
+            let cipher = aes_gcm::Aes256Gcm::new((&key).into());
+            cipher.decrypt(nonce.into(), payload).map_err(failed)
+        }
+        XCHACHA20_POLY1305 => {
+            let (nonce, payload) = open(XCHACHA20_POLY1305_NONCE_LEN)?;
+            let cipher = chacha20poly1305::XChaCha20Poly1305::new((&key).into());
+            cipher.decrypt(nonce.into(), payload).map_err(failed)
+        }
+        AES_256_GCM_SIV => {
+            let (nonce, payload) = open(AES_256_GCM_SIV_NONCE_LEN)?;
+            let cipher = aes_gcm_siv::Aes256GcmSiv::new((&siv_key(&key)).into());
+            cipher.decrypt(nonce.into(), payload).map_err(failed)
+        }
+        cipher => Err(format!("unknown cipher {cipher}").into()),
+    }
+}
+
+/// Encrypt `plaintext` with `cipher` and the current key of `keys`.
+pub(crate) fn encrypt(
+    keys: &dyn KeyProvider,
+    cipher: u8,
+    plaintext: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, BoxError> {
+    let key_id = keys.current_key_id()?;
+    let key = keys.key(&key_id)?;
+    let mut blob = Header {
+        cipher,
+        key_id: &key_id,
+    }
+    .to_vec()?;
+    let aad = [&blob[..], associated_data].concat();
+    let msg = plaintext;
+    let aad = &aad;
+    let (nonce, ciphertext) = match cipher {
+        AES_256_GCM => {
+            let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+            let cipher = aes_gcm::Aes256Gcm::new((&key).into());
+            (nonce.to_vec(), cipher.encrypt(&nonce, Payload { msg, aad }))
+        }
+        XCHACHA20_POLY1305 => {
+            let nonce = chacha20poly1305::XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let cipher = chacha20poly1305::XChaCha20Poly1305::new((&key).into());
+            (nonce.to_vec(), cipher.encrypt(&nonce, Payload { msg, aad }))
+        }
+        AES_256_GCM_SIV => {
+            let nonce = aes_gcm_siv::Nonce::default();
+            let cipher = aes_gcm_siv::Aes256GcmSiv::new((&siv_key(&key)).into());
+            (nonce.to_vec(), cipher.encrypt(&nonce, Payload { msg, aad }))
+        }
+        cipher => return Err(format!("unknown cipher {cipher}").into()),
+    };
+    blob.extend(nonce);
+    blob.extend(ciphertext.map_err(|_| "unable to encrypt")?);
+    Ok(blob)
+}
+
+/// The blob `bytes` encrypted again, with the same cipher and the current key of `keys`.
+///
+/// This is `None` when the blob is encrypted with the current key already, so nothing has to be
+/// rewritten. The associated data has to be the one the blob was encrypted with, it's
+/// authenticated along with the new blob too.
+pub fn reencrypt(
+    keys: &dyn KeyProvider,
+    bytes: &[u8],
+    associated_data: &[u8],
+) -> Result<Option<Vec<u8>>, BoxError> {
+    let (header, _) = Header::parse(bytes)?;
+    if header.key_id == keys.current_key_id()? {
+        return Ok(None);
+    }
+    let plaintext = decrypt(keys, bytes, associated_data)?;
+    encrypt(keys, header.cipher, &plaintext, associated_data).map(Some)
+}
+
+/// A [`DBson`] encrypted with the cipher `A` before it's stored.
+///
+/// The value is serialized with `C` like a `DBson<T, C>` would be, so it can be compressed
+/// first with [`Compressed`](crate::codec::Compressed), and encrypted with the current key of
+/// the [key provider](set_key_provider). Reading a blob uses the key named in its header,
+/// so keys can be rotated without rewriting the values encrypted with the older ones.
+/// ```rust
+/// use dbson::encryption::{EncryptedDBson, Key, KeyProvider};
+/// # struct Keys;
+/// # impl KeyProvider for Keys {
+/// #     fn current_key_id(&self) -> Result<String, dbson::codec::BoxError> {
+/// #         Ok("2024".into())
+/// #     }
+/// #     fn key(&self, _id: &str) -> Result<Key, dbson::codec::BoxError> {
+/// #         Ok([7; 32])
+/// #     }
+/// # }
+/// dbson::encryption::set_key_provider(Keys);
+/// let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// conn.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, email BLOB)", []).unwrap();
+/// let email = EncryptedDBson::new("ann@example.com".to_string());
+/// conn.execute("INSERT INTO users VALUES (1, ?1)", [&email]).unwrap();
+/// let email: EncryptedDBson<String> = conn
+///     .query_row("SELECT email FROM users", [], |row| row.get(0))
+///     .unwrap();
+/// assert_eq!(email.into_inner(), "ann@example.com");
+///
+/// // values bound to their row have to be read with the same associated data
+/// let email = EncryptedDBson::new("bob@example.com".to_string()).with_associated_data(b"users/2");
+/// conn.execute("INSERT INTO users VALUES (2, ?1)", [&email]).unwrap();
+/// let blob: Vec<u8> = conn.query_row("SELECT email FROM users WHERE id = 2", [], |row| row.get(0)).unwrap();
+/// assert!(EncryptedDBson::<String>::decrypt(&blob, b"users/1").is_err());
+/// let email = EncryptedDBson::<String>::decrypt(&blob, b"users/2").unwrap();
+/// assert_eq!(email.into_inner(), "bob@example.com");
+/// ```
+///
+/// [`FromSql`](::rusqlite::types::FromSql) and sqlx's `Decode` can't know the associated data
+/// of a row, so they only read values stored without any. Values bound to their row are read
+/// as bytes and decrypted with [`decrypt`](Self::decrypt).
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct EncryptedDBson<T, A = Aes256Gcm, C = codec::Bson> {
+    inner: T,
+    associated_data: Vec<u8>,
+    marker: PhantomData<(A, C)>,
+}
+
+/// Only the sizes, the value and the associated data stay out of logs.
+impl<T, A, C> fmt::Debug for EncryptedDBson<T, A, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedDBson")
+            .field("inner", &format_args!("<redacted>"))
+            .field(
+                "associated_data",
+                &format_args!("<{} bytes>", self.associated_data.len()),
+            )
+            .finish()
+    }
+}
+
+impl<T> EncryptedDBson<T> {
+    pub fn new(inner: T) -> Self {
+        Self::with_cipher(inner)
+    }
+}
+
+impl<T, A, C> EncryptedDBson<T, A, C> {
+    /// Create an encrypted value with a cipher and a codec other than the default ones.
+    pub fn with_cipher(inner: T) -> Self {
+        Self {
+            inner,
+            associated_data: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Authenticate the value along with `associated_data`, which has to be given again to
+    /// [`decrypt`](Self::decrypt) it.
+    pub fn with_associated_data(mut self, associated_data: impl Into<Vec<u8>>) -> Self {
+        self.associated_data = associated_data.into();
+        self
+    }
+
+    pub fn associated_data(&self) -> &[u8] {
+        &self.associated_data
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Serialize, A: Cipher, C: Codec> EncryptedDBson<T, A, C> {
+    /// Serialize and encrypt the value with the key provider set with [`set_key_provider`].
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        let keys = key_provider().map_err(|source| self.encrypt_error(source))?;
+        self.encrypt_with(&*keys)
+    }
+
+    /// Serialize and encrypt the value with the current key of `keys`.
+    pub fn encrypt_with(&self, keys: &dyn KeyProvider) -> Result<Vec<u8>, Error> {
+        let plaintext = codec::encode::<T, C>(&DBson::<&T, C>::with_codec(&self.inner))?;
+        encrypt(keys, A::ID, &plaintext, &self.associated_data)
+            .map_err(|source| self.encrypt_error(source))
+    }
+
+    fn encrypt_error(&self, source: BoxError) -> Error {
+        Error::Encrypt {
+            type_name: std::any::type_name::<T>(),
+            source,
+        }
+    }
+}
+
+impl<T: DeserializeOwned, A, C: Codec> EncryptedDBson<T, A, C> {
+    /// Decrypt and deserialize a value stored without associated data.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, Error> {
+        Self::decrypt(bytes, &[])
+    }
+
+    /// Decrypt and deserialize a value stored with `associated_data`.
+    pub fn decrypt(bytes: &[u8], associated_data: &[u8]) -> Result<Self, Error> {
+        let keys = key_provider().map_err(|source| decrypt_error::<T>(bytes, source))?;
+        Self::decrypt_with(&*keys, bytes, associated_data)
+    }
+
+    /// Decrypt and deserialize a value stored with `associated_data`, with the keys of `keys`.
+    pub fn decrypt_with(
+        keys: &dyn KeyProvider,
+        bytes: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Self, Error> {
+        let plaintext = decrypt(keys, bytes, associated_data)
+            .map_err(|source| decrypt_error::<T>(bytes, source))?;
+        let DBson { inner, .. } = codec::decode::<T, C, DBson<T, C>>(&plaintext)?;
+        Ok(Self::with_cipher(inner).with_associated_data(associated_data))
+    }
+}
+
+fn decrypt_error<T>(bytes: &[u8], source: BoxError) -> Error {
+    Error::Decrypt {
+        type_name: std::any::type_name::<T>(),
+        key_id: key_id(bytes).map(str::to_string),
+        source,
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+mod impl_rusqlite {
+    use super::{Cipher, EncryptedDBson};
+    use crate::codec::Codec;
+    use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+    use rusqlite::ToSql;
+
+    impl<T: serde::Serialize, A: Cipher, C: Codec> ToSql for EncryptedDBson<T, A, C> {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+            let bytes = self
+                .to_vec()
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            Ok(ToSqlOutput::from(bytes))
+        }
+    }
+
+    impl<T: serde::de::DeserializeOwned, A, C: Codec> FromSql for EncryptedDBson<T, A, C> {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            Self::from_slice(value.as_blob()?).map_err(|e| FromSqlError::Other(Box::new(e)))
+        }
+    }
+}
+
+#[cfg(feature = "sqlx")]
+mod impl_sqlx {
+    use super::{Cipher, EncryptedDBson};
+    use crate::codec::Codec;
+    use sqlx::{
+        database::Database, decode::Decode, encode::Encode, error::BoxDynError, types::Type,
+    };
+
+    impl<'a, T, A, C, DB: Database> Type<DB> for EncryptedDBson<T, A, C>
+    where
+        &'a [u8]: Type<DB>,
+    {
+        fn type_info() -> DB::TypeInfo {
+            <&[u8] as Type<DB>>::type_info()
+        }
+
+        fn compatible(ty: &DB::TypeInfo) -> bool {
+            *ty == <&[u8] as Type<DB>>::type_info()
+        }
+    }
+
+    impl<'a, T: serde::Serialize, A: Cipher, C: Codec, DB: Database> Encode<'a, DB>
+        for EncryptedDBson<T, A, C>
+    where
+        Vec<u8>: Encode<'a, DB>,
+    {
+        fn encode_by_ref(
+            &self,
+            buf: &mut <DB as Database>::ArgumentBuffer<'a>,
+        ) -> Result<sqlx::encode::IsNull, BoxDynError> {
+            <Vec<u8> as Encode<'a, DB>>::encode(self.to_vec()?, buf)
+        }
+    }
+
+    impl<'r, T: serde::de::DeserializeOwned, A, C: Codec, DB: Database> Decode<'r, DB>
+        for EncryptedDBson<T, A, C>
+    where
+        &'r [u8]: Decode<'r, DB>,
+    {
+        fn decode(value: <DB as Database>::ValueRef<'r>) -> Result<Self, BoxDynError> {
+            let bytes = <&[u8] as Decode<'r, DB>>::decode(value)?;
+            Ok(Self::from_slice(bytes)?)
+        }
+    }
+}
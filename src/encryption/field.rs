@@ -0,0 +1,152 @@
+//! [`Encrypted`] fields, encrypted on their own inside of a document.
+
+use super::{decrypt_error, Aes256Gcm, Cipher, EncryptedDBson, KeyProvider};
+use crate::Error;
+use bson::spec::BinarySubtype;
+use bson::{Binary, Bson};
+use serde::de::{DeserializeOwned, Deserializer, Error as _};
+use serde::ser::{Error as _, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// A field encrypted with the cipher `A` whenever the value holding it is serialized.
+///
+/// The field is stored as BSON binary subtype 6, the subtype of encrypted values, holding an
+/// [encrypted blob](super) of the field. The rest of the document is stored as it is and can
+/// still be filtered, indexed and read with the `bson_*` sql functions. Fields are encrypted
+/// with the [key provider](super::set_key_provider) and without associated data, serde doesn't
+/// know which row they belong to.
+///
+/// With [`Aes256GcmSiv`](super::Aes256GcmSiv) the encryption is deterministic: the same value
+/// always gives the same binary, which makes encrypted fields usable in `$eq` and `$in` filters
+/// through [`to_bson`](Self::to_bson). Those compile to a plain comparison of the field in
+/// sqlite, so an [`Index`](crate::filter::Index) on it turns the lookup into an index search.
+/// The key id is part of the binary, so after a
+/// key rotation a lookup only finds the values encrypted with the current key: the values
+/// written before it are found with [`to_bson_with`](Self::to_bson_with) and the older key.
+/// ```rust
+/// use bson::doc;
+/// use dbson::encryption::{Aes256GcmSiv, Encrypted, KeyRing};
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct Patient {
+///     name: String,
+///     ward: u32,
+///     ssn: Encrypted<String, Aes256GcmSiv>,
+///     notes: Encrypted<Vec<String>>,
+/// }
+/// dbson::encryption::set_key_provider(KeyRing::new("2024", [7; 32]));
+/// let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// dbson::rusqlite::register_functions(&conn).unwrap();
+/// let patients = dbson::rusqlite::Collection::<Patient>::new(&conn, "patients").unwrap();
+/// patients
+///     .insert_one(&Patient {
+///         name: "Ann".into(),
+///         ward: 3,
+///         ssn: Encrypted::with_cipher("123-45-6789".into()),
+///         notes: Encrypted::new(vec!["allergic to penicillin".into()]),
+///     })
+///     .unwrap();
+///
+/// // plain fields stay queryable, encrypted ones can be looked up by equality
+/// let ssn = Encrypted::<_, Aes256GcmSiv>::with_cipher("123-45-6789").to_bson().unwrap();
+/// let (_, ann) = patients.find_one(&doc! { "ward": 3, "ssn": ssn }).unwrap().unwrap();
+/// assert_eq!(ann.notes.into_inner(), ["allergic to penicillin"]);
+/// let kind: String = conn
+///     .query_row("SELECT bson_type(data, '$.notes') FROM patients", [], |row| row.get(0))
+///     .unwrap();
+/// assert_eq!(kind, "binData");
+/// ```
+#[derive(Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Encrypted<T, A = Aes256Gcm> {
+    inner: T,
+    marker: PhantomData<A>,
+}
+
+/// The value stays out of logs.
+impl<T, A> fmt::Debug for Encrypted<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Encrypted")
+            .field(&format_args!("<redacted>"))
+            .finish()
+    }
+}
+
+impl<T> Encrypted<T> {
+    pub fn new(inner: T) -> Self {
+        Self::with_cipher(inner)
+    }
+}
+
+impl<T, A> Encrypted<T, A> {
+    /// Create an encrypted field with a cipher other than [`Aes256Gcm`].
+    pub fn with_cipher(inner: T) -> Self {
+        Self {
+            inner,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, A> From<T> for Encrypted<T, A> {
+    fn from(inner: T) -> Self {
+        Self::with_cipher(inner)
+    }
+}
+
+impl<T: Serialize, A: Cipher> Encrypted<T, A> {
+    /// The field as it's stored, to compare it with in a filter.
+    pub fn to_bson(&self) -> Result<Bson, Error> {
+        to_binary::<T, A>(&self.inner).map(Bson::Binary)
+    }
+
+    /// The field as it would be stored with the current key of `keys`.
+    pub fn to_bson_with(&self, keys: &dyn KeyProvider) -> Result<Bson, Error> {
+        let bytes = EncryptedDBson::<&T, A>::with_cipher(&self.inner).encrypt_with(keys)?;
+        Ok(Bson::Binary(Binary {
+            subtype: BinarySubtype::Encrypted,
+            bytes,
+        }))
+    }
+}
+
+impl<T: Serialize, A: Cipher> Serialize for Encrypted<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        to_binary::<T, A>(&self.inner)
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de, T: DeserializeOwned, A> Deserialize<'de> for Encrypted<T, A> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let binary = Binary::deserialize(deserializer)?;
+        from_binary(&binary)
+            .map(Self::with_cipher)
+            .map_err(D::Error::custom)
+    }
+}
+
+fn to_binary<T: Serialize, A: Cipher>(value: &T) -> Result<Binary, Error> {
+    let bytes = EncryptedDBson::<&T, A>::with_cipher(value).to_vec()?;
+    Ok(Binary {
+        subtype: BinarySubtype::Encrypted,
+        bytes,
+    })
+}
+
+fn from_binary<T: DeserializeOwned>(binary: &Binary) -> Result<T, Error> {
+    if binary.subtype != BinarySubtype::Encrypted {
+        let source = format!("expected binary subtype 6, found {:?}", binary.subtype);
+        return Err(decrypt_error::<T>(&binary.bytes, source.into()));
+    }
+    EncryptedDBson::<T>::from_slice(&binary.bytes).map(EncryptedDBson::into_inner)
+}
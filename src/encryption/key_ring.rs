@@ -0,0 +1,128 @@
+//! [`KeyRing`], a [`KeyProvider`] that keys can be rotated in while it's in use.
+
+use super::{Key, KeyProvider};
+use crate::codec::BoxError;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+
+/// Keys held in memory: one to encrypt new values with, and any number of older ones that
+/// are only used to decrypt.
+///
+/// Every method takes `&self`, so a ring shared as an `Arc<KeyRing>` can be
+/// [set as the provider](super::set_key_provider) and rotated afterwards without stopping the
+/// writers. Rotating only changes the key new values are encrypted with: the older keys have
+/// to stay in the ring until every value encrypted with them has been
+/// [re-encrypted](super::Reencryption).
+/// ```rust
+/// use dbson::encryption::{EncryptedDBson, KeyRing};
+/// use std::sync::Arc;
+/// let ring = Arc::new(KeyRing::new("2023", [1; 32]));
+/// dbson::encryption::set_key_provider(ring.clone());
+/// let old = EncryptedDBson::new(42).to_vec().unwrap();
+///
+/// ring.rotate("2024", [2; 32]);
+/// let new = EncryptedDBson::new(42).to_vec().unwrap();
+/// assert_eq!(dbson::encryption::key_id(&old), Some("2023"));
+/// assert_eq!(dbson::encryption::key_id(&new), Some("2024"));
+/// assert_eq!(EncryptedDBson::<i32>::from_slice(&old).unwrap().into_inner(), 42);
+///
+/// // the current key can't be removed, and values encrypted with removed keys can't be read
+/// assert!(ring.remove("2024").is_err());
+/// ring.remove("2023").unwrap();
+/// assert!(EncryptedDBson::<i32>::from_slice(&old).is_err());
+/// ```
+pub struct KeyRing {
+    ring: RwLock<Ring>,
+}
+
+struct Ring {
+    current: String,
+    keys: HashMap<String, Key>,
+}
+
+impl KeyRing {
+    /// A ring encrypting with `key`, named `id`.
+    pub fn new(id: impl Into<String>, key: Key) -> Self {
+        let id = id.into();
+        let ring = Ring {
+            keys: HashMap::from([(id.clone(), key)]),
+            current: id,
+        };
+        Self {
+            ring: RwLock::new(ring),
+        }
+    }
+
+    /// Add a key used to decrypt values, replacing any key with the same id.
+    pub fn add(&self, id: impl Into<String>, key: Key) {
+        let mut ring = self.ring.write().unwrap_or_else(|e| e.into_inner());
+        ring.keys.insert(id.into(), key);
+    }
+
+    /// Add a key and encrypt new values with it from now on.
+    pub fn rotate(&self, id: impl Into<String>, key: Key) {
+        let id = id.into();
+        let mut ring = self.ring.write().unwrap_or_else(|e| e.into_inner());
+        ring.keys.insert(id.clone(), key);
+        ring.current = id;
+    }
+
+    /// Encrypt new values with the key `id`, which has to be in the ring already.
+    pub fn set_current(&self, id: &str) -> Result<(), BoxError> {
+        let mut ring = self.ring.write().unwrap_or_else(|e| e.into_inner());
+        if !ring.keys.contains_key(id) {
+            return Err(format!("the key `{id}` isn't in the key ring").into());
+        }
+        ring.current = id.to_string();
+        Ok(())
+    }
+
+    /// Remove the key `id`, once no value is encrypted with it anymore.
+    ///
+    /// The key new values are encrypted with can't be removed.
+    pub fn remove(&self, id: &str) -> Result<(), BoxError> {
+        let mut ring = self.ring.write().unwrap_or_else(|e| e.into_inner());
+        if ring.current == id {
+            return Err(format!("the key `{id}` is the current key").into());
+        }
+        ring.keys.remove(id);
+        Ok(())
+    }
+
+    /// The ids of the keys in the ring, sorted.
+    pub fn key_ids(&self) -> Vec<String> {
+        let ring = self.ring.read().unwrap_or_else(|e| e.into_inner());
+        let mut ids: Vec<_> = ring.keys.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+}
+
+impl KeyProvider for KeyRing {
+    fn current_key_id(&self) -> Result<String, BoxError> {
+        let ring = self.ring.read().unwrap_or_else(|e| e.into_inner());
+        Ok(ring.current.clone())
+    }
+
+    fn key(&self, id: &str) -> Result<Key, BoxError> {
+        let ring = self.ring.read().unwrap_or_else(|e| e.into_inner());
+        ring.keys
+            .get(id)
+            .copied()
+            .ok_or_else(|| format!("the key `{id}` isn't in the key ring").into())
+    }
+}
+
+/// Only the ids, the keys stay out of logs.
+impl fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ring = self.ring.read().unwrap_or_else(|e| e.into_inner());
+        let mut ids: Vec<_> = ring.keys.keys().collect();
+        ids.sort();
+        f.debug_struct("KeyRing")
+            .field("current", &ring.current)
+            .field("keys", &ids)
+            .finish()
+    }
+}
@@ -0,0 +1,111 @@
+//! [`Reencryption`], the description of a column to move to the current key.
+
+use std::fmt;
+
+/// The table keeping the progress of the re-encryptions, so an interrupted one resumes where
+/// it stopped.
+pub const CHECKPOINT_TABLE: &str = "dbson_reencryption";
+
+/// A column of encrypted blobs to rewrite with the current key of a [`KeyProvider`](super::KeyProvider),
+/// run with
+#[cfg_attr(
+    feature = "rusqlite",
+    doc = "[`rusqlite::reencrypt`](crate::rusqlite::reencrypt)"
+)]
+#[cfg_attr(not(feature = "rusqlite"), doc = "`rusqlite::reencrypt`")]
+/// or
+#[cfg_attr(
+    any(feature = "sqlx-sqlite", feature = "sqlx-postgres"),
+    doc = "[`sqlx::reencrypt`](crate::sqlx::reencrypt)."
+)]
+#[cfg_attr(
+    not(any(feature = "sqlx-sqlite", feature = "sqlx-postgres")),
+    doc = "`sqlx::reencrypt`."
+)]
+///
+/// Rows are read in batches, in the order of their integer id column (`id` unless
+/// [set otherwise](Self::id_column), sqlite tables without one can use `rowid`). Blobs still
+/// encrypted with an older key are decrypted and encrypted again with the current one, keeping
+/// their cipher; a blob is only replaced if the row still holds it, so values written in the
+/// meantime aren't lost. Each batch is committed along with the last id it reached in the
+/// [checkpoint table](CHECKPOINT_TABLE), and running the same re-encryption again after an
+/// interruption starts from there. Re-encrypting is idempotent, a batch that was rewritten but
+/// not checkpointed only costs the time to check it again.
+pub struct Reencryption<'a> {
+    pub(crate) table: String,
+    pub(crate) column: String,
+    pub(crate) id_column: String,
+    pub(crate) batch_size: usize,
+    #[allow(clippy::type_complexity)]
+    associated_data: Option<Box<dyn Fn(i64) -> Vec<u8> + Send + Sync + 'a>>,
+}
+
+impl<'a> Reencryption<'a> {
+    /// Re-encrypt the blobs of `column` in `table`, 500 rows at a time.
+    pub fn new(table: impl Into<String>, column: impl Into<String>) -> Self {
+        Self {
+            table: table.into(),
+            column: column.into(),
+            id_column: "id".into(),
+            batch_size: 500,
+            associated_data: None,
+        }
+    }
+
+    /// The integer column rows are read in the order of, `id` by default.
+    pub fn id_column(mut self, id_column: impl Into<String>) -> Self {
+        self.id_column = id_column.into();
+        self
+    }
+
+    /// The number of rows read and committed at a time.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// The associated data each blob was encrypted with, from the id of its row.
+    pub fn associated_data(
+        mut self,
+        associated_data: impl Fn(i64) -> Vec<u8> + Send + Sync + 'a,
+    ) -> Self {
+        self.associated_data = Some(Box::new(associated_data));
+        self
+    }
+
+    #[cfg_attr(
+        not(any(
+            feature = "rusqlite",
+            feature = "sqlx-sqlite",
+            feature = "sqlx-postgres"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn associated_data_of(&self, id: i64) -> Vec<u8> {
+        self.associated_data
+            .as_ref()
+            .map_or_else(Vec::new, |f| f(id))
+    }
+}
+
+impl fmt::Debug for Reencryption<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reencryption")
+            .field("table", &self.table)
+            .field("column", &self.column)
+            .field("id_column", &self.id_column)
+            .field("batch_size", &self.batch_size)
+            .field("associated_data", &self.associated_data.is_some())
+            .finish()
+    }
+}
+
+/// What a re-encryption did.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+pub struct Reencrypted {
+    /// Blobs encrypted again with the current key.
+    pub rewritten: u64,
+    /// Blobs that were encrypted with the current key already, or changed while they were
+    /// re-encrypted.
+    pub up_to_date: u64,
+}
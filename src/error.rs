@@ -0,0 +1,211 @@
+//! The error type shared by every database backend.
+
+use crate::codec::BoxError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::RefCell;
+use std::fmt;
+
+/// An error encountered while turning a value into a blob or back.
+///
+/// Both the rusqlite and the sqlx implementations report this type (boxed inside of their own
+/// error types), so it can be recovered with `downcast_ref::<dbson::Error>()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The value couldn't be serialized by the codec.
+    Serialize {
+        /// `std::any::type_name` of the wrapped type.
+        type_name: &'static str,
+        /// [`Codec::NAME`](crate::Codec::NAME) of the codec that failed.
+        codec: &'static str,
+        /// Path of the field that failed, e.g. `inner.users[2].id`.
+        path: Option<String>,
+        source: BoxError,
+    },
+    /// The blob couldn't be deserialized into the wrapped type.
+    Deserialize {
+        /// `std::any::type_name` of the wrapped type.
+        type_name: &'static str,
+        /// [`Codec::NAME`](crate::Codec::NAME) of the codec that failed.
+        codec: &'static str,
+        /// Length of the blob in bytes.
+        len: usize,
+        /// Path of the field that failed, e.g. `inner.users[2].id`.
+        path: Option<String>,
+        source: BoxError,
+    },
+    /// The blob couldn't be decompressed, see [`Compressed`](crate::codec::Compressed).
+    Decompress {
+        /// `std::any::type_name` of the wrapped type.
+        type_name: &'static str,
+        /// [`Codec::NAME`](crate::Codec::NAME) of the compressed codec, e.g. `zstd+bson`.
+        codec: &'static str,
+        /// Length of the blob in bytes.
+        len: usize,
+        source: crate::codec::DecompressError,
+    },
+    /// An update document couldn't be applied to the value, see
+    /// [`DBson::apply_update`](crate::DBson::apply_update).
+    Update {
+        /// `std::any::type_name` of the wrapped type.
+        type_name: &'static str,
+        /// The field of the update document that failed, e.g. `address.zip`.
+        path: Option<String>,
+        source: BoxError,
+    },
+    /// The value couldn't be encrypted, see
+    /// [`EncryptedDBson`](crate::encryption::EncryptedDBson).
+    Encrypt {
+        /// `std::any::type_name` of the wrapped type.
+        type_name: &'static str,
+        source: BoxError,
+    },
+    /// The blob couldn't be decrypted: it isn't encrypted, the key is missing, or it or its
+    /// associated data were changed.
+    Decrypt {
+        /// `std::any::type_name` of the wrapped type.
+        type_name: &'static str,
+        /// The id of the key named in the header of the blob.
+        key_id: Option<String>,
+        source: BoxError,
+    },
+}
+
+impl Error {
+    /// `std::any::type_name` of the type that failed.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Serialize { type_name, .. }
+            | Self::Deserialize { type_name, .. }
+            | Self::Decompress { type_name, .. }
+            | Self::Update { type_name, .. }
+            | Self::Encrypt { type_name, .. }
+            | Self::Decrypt { type_name, .. } => type_name,
+        }
+    }
+
+    /// Path of the field that failed, if the failure happened inside of the value.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Self::Serialize { path, .. }
+            | Self::Deserialize { path, .. }
+            | Self::Update { path, .. } => path.as_deref(),
+            Self::Decompress { .. } | Self::Encrypt { .. } | Self::Decrypt { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Serialize {
+                type_name, codec, ..
+            } => write!(f, "failed to serialize `{type_name}` as {codec}")?,
+            Self::Deserialize {
+                type_name,
+                codec,
+                len,
+                ..
+            } => write!(
+                f,
+                "failed to deserialize `{type_name}` from a {len} byte {codec} blob"
+            )?,
+            Self::Decompress {
+                type_name,
+                codec,
+                len,
+                ..
+            } => write!(
+                f,
+                "failed to decompress `{type_name}` from a {len} byte {codec} blob"
+            )?,
+            Self::Update { type_name, .. } => write!(f, "failed to update `{type_name}`")?,
+            Self::Encrypt { type_name, .. } => write!(f, "failed to encrypt `{type_name}`")?,
+            Self::Decrypt {
+                type_name, key_id, ..
+            } => {
+                write!(f, "failed to decrypt `{type_name}`")?;
+                if let Some(key_id) = key_id {
+                    write!(f, " with key `{key_id}`")?;
+                }
+            }
+        }
+        if let Some(path) = self.path() {
+            write!(f, " at `{path}`")?;
+        }
+        match self {
+            Self::Serialize { source, .. }
+            | Self::Deserialize { source, .. }
+            | Self::Update { source, .. }
+            | Self::Encrypt { source, .. }
+            | Self::Decrypt { source, .. } => write!(f, ": {source}"),
+            Self::Decompress { source, .. } => write!(f, ": {source}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Serialize { source, .. }
+            | Self::Deserialize { source, .. }
+            | Self::Update { source, .. }
+            | Self::Encrypt { source, .. }
+            | Self::Decrypt { source, .. } => Some(&**source),
+            Self::Decompress { source, .. } => Some(source),
+        }
+    }
+}
+
+thread_local! {
+    /// Path of the last failure seen by [`Tracked`] on this thread.
+    ///
+    /// Codecs only hand back their own error type, so the path is passed around them through here.
+    static FAILED_PATH: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn record_path(track: serde_path_to_error::Track) {
+    let path = track.path().to_string();
+    let path = (path != ".").then_some(path);
+    FAILED_PATH.with(|failed| *failed.borrow_mut() = path);
+}
+
+/// Takes the path recorded by the last failed [`Tracked`] (de)serialization.
+pub(crate) fn take_failed_path() -> Option<String> {
+    FAILED_PATH.with(|failed| failed.borrow_mut().take())
+}
+
+/// Records the path of the field that failed to (de)serialize.
+pub(crate) struct Tracked<T>(pub(crate) T);
+
+impl<T: Serialize> Serialize for Tracked<&T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut track = serde_path_to_error::Track::new();
+        match self
+            .0
+            .serialize(serde_path_to_error::Serializer::new(serializer, &mut track))
+        {
+            Ok(ok) => Ok(ok),
+            Err(e) => {
+                record_path(track);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Tracked<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut track = serde_path_to_error::Track::new();
+        match T::deserialize(serde_path_to_error::Deserializer::new(
+            deserializer,
+            &mut track,
+        )) {
+            Ok(value) => Ok(Self(value)),
+            Err(e) => {
+                record_path(track);
+                Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,495 @@
+//! MongoDB [query filters](https://www.mongodb.com/docs/manual/reference/operator/query/)
+//! evaluated against documents.
+//!
+//! A [`Filter`] is parsed once from a filter document and can then be matched against any
+//! number of documents, either in Rust with [`Filter::matches`] or in sqlite with the
+//! `bson_match(blob, filter)` function (see [`crate::rusqlite`]).
+//! ```rust
+//! use bson::doc;
+//! use dbson::filter::Filter;
+//! let filter = Filter::new(&doc! { "age": { "$gt": 30 }, "tags": { "$in": ["a"] } }).unwrap();
+//! assert!(filter.matches(&doc! { "age": 31, "tags": ["a", "b"] }));
+//! assert!(!filter.matches(&doc! { "age": 31, "tags": ["b"] }));
+//! assert!(!filter.matches(&doc! { "tags": ["a"] }));
+//! ```
+//!
+//! The supported operators are
+//! - comparison: `$eq`, `$ne`, `$gt`, `$gte`, `$lt`, `$lte`, `$in` and `$nin`
+//! - logical: `$and`, `$or`, `$nor` and `$not`
+//! - element: `$exists` and `$type`
+//! - array: `$all`, `$elemMatch` and `$size`
+//! - evaluation: `$regex` (with `$options`, needs the `regex` feature) and `$mod`
+//!
+//! Fields are addressed with dotted paths which descend into arrays the way MongoDB does, so
+//! `{"items.price": {"$lt": 10}}` matches if any document of the `items` array is cheap enough.
+//! Values of different types never compare as greater or less than each other, except for
+//! numbers which compare by value whatever their type.
+//!
+//! Filters can also be compiled to sql `WHERE` clauses with [`SqlFilter`], which lets sqlite and
+//! PostgreSQL use their indexes to find the matching rows, and sqlite indexes on the fields the
+//! compiled filters read are declared with [`Index`].
+
+use crate::order::{self, Number};
+use crate::stored;
+use bson::{Bson, Document};
+#[cfg(feature = "regex")]
+use regex::{Regex, RegexBuilder};
+use std::cmp::Ordering;
+use std::fmt;
+
+mod sql;
+
+pub use sql::{Dialect, Index, IndexOrder, SqlFilter, SqlParam};
+
+/// A filter document that couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterError {
+    message: String,
+}
+
+impl FilterError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid filter: {}", self.message)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+/// A parsed MongoDB query filter, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Filter {
+    /// Every clause has to match, like the fields of the filter document.
+    clauses: Vec<Clause>,
+}
+
+#[derive(Debug, Clone)]
+enum Clause {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Nor(Vec<Filter>),
+    Field(String, Vec<Condition>),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Condition {
+    Eq(Bson),
+    Ne(Bson),
+    Cmp(Ordering, bool, Bson),
+    In(Vec<Pattern>),
+    Nin(Vec<Pattern>),
+    Exists(bool),
+    Type(Vec<&'static str>),
+    Size(usize),
+    All(Vec<Bson>),
+    ElemMatch(ElemMatch),
+    Regex(Regex),
+    Mod(i64, i64),
+    Not(Vec<Condition>),
+}
+
+/// An element of `$in` or `$nin`.
+#[derive(Debug, Clone)]
+pub(crate) enum Pattern {
+    Value(Bson),
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum ElemMatch {
+    /// `{"$elemMatch": {"$gt": 1}}`, conditions on the elements themselves.
+    Values(Vec<Condition>),
+    /// `{"$elemMatch": {"name": "a"}}`, a filter on documents inside of the array.
+    Documents(Filter),
+}
+
+impl Filter {
+    /// Parse a filter document.
+    pub fn new(filter: &Document) -> Result<Self, FilterError> {
+        let mut clauses = Vec::new();
+        for (key, value) in filter {
+            let clause = match key.as_str() {
+                "$and" => Clause::And(filters(key, value)?),
+                "$or" => Clause::Or(filters(key, value)?),
+                "$nor" => Clause::Nor(filters(key, value)?),
+                "$comment" => continue,
+                key if key.starts_with('$') => {
+                    return Err(FilterError::new(format!("unsupported operator `{key}`")))
+                }
+                key => Clause::Field(key.to_string(), conditions(value)?),
+            };
+            clauses.push(clause);
+        }
+        Ok(Self { clauses })
+    }
+
+    /// Whether `doc` matches the filter.
+    pub fn matches(&self, doc: &Document) -> bool {
+        self.clauses.iter().all(|clause| clause.matches(doc))
+    }
+
+    /// Whether a stored value matches, only documents can match a filter with fields.
+    pub(crate) fn matches_value(&self, value: &Bson) -> bool {
+        match value {
+            Bson::Document(doc) => self.matches(doc),
+            _ => self.clauses.is_empty(),
+        }
+    }
+}
+
+impl Clause {
+    fn matches(&self, doc: &Document) -> bool {
+        match self {
+            Self::And(filters) => filters.iter().all(|filter| filter.matches(doc)),
+            Self::Or(filters) => filters.iter().any(|filter| filter.matches(doc)),
+            Self::Nor(filters) => !filters.iter().any(|filter| filter.matches(doc)),
+            Self::Field(path, conditions) => {
+                let mut values = Vec::new();
+                let keys: Vec<_> = path.split('.').collect();
+                if let Some(value) = doc.get(keys[0]) {
+                    lookup(value, &keys[1..], &mut values);
+                }
+                conditions
+                    .iter()
+                    .all(|condition| condition.matches(&values))
+            }
+        }
+    }
+}
+
+/// Every value at `keys` below `value`, descending into the documents of arrays on the way.
+fn lookup<'a>(value: &'a Bson, keys: &[&str], values: &mut Vec<&'a Bson>) {
+    let Some((key, rest)) = keys.split_first() else {
+        values.push(value);
+        return;
+    };
+    match value {
+        Bson::Document(doc) => {
+            if let Some(value) = doc.get(*key) {
+                lookup(value, rest, values);
+            }
+        }
+        Bson::Array(array) => {
+            if let Some(value) = key.parse::<usize>().ok().and_then(|i| array.get(i)) {
+                lookup(value, rest, values);
+            }
+            for item in array {
+                if let Bson::Document(_) = item {
+                    lookup(item, keys, values);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The value itself and, for arrays, each of its elements.
+fn expand(value: &Bson) -> impl Iterator<Item = &Bson> {
+    let elements = match value {
+        Bson::Array(array) => array.as_slice(),
+        _ => &[],
+    };
+    std::iter::once(value).chain(elements)
+}
+
+/// Equality as MongoDB sees it: `1`, `1i64` and `1.0` are all equal.
+pub(crate) fn equals(a: &Bson, b: &Bson) -> bool {
+    order::type_rank(a) == order::type_rank(b) && order::compare(a, b) == Ordering::Equal
+}
+
+/// `{field: expected}`, where `null` also matches a missing field.
+fn eq(expected: &Bson, values: &[&Bson]) -> bool {
+    let mut found = values.iter().flat_map(|v| expand(v));
+    match expected {
+        Bson::Null => values.is_empty() || found.any(|v| matches!(v, Bson::Null)),
+        expected => found.any(|v| equals(v, expected)),
+    }
+}
+
+impl Condition {
+    /// Whether the values found at the field's path match, `values` is empty for a missing field.
+    fn matches(&self, values: &[&Bson]) -> bool {
+        let any = |f: &dyn Fn(&Bson) -> bool| values.iter().flat_map(|v| expand(v)).any(f);
+        match self {
+            Self::Eq(expected) => eq(expected, values),
+            Self::Ne(expected) => !eq(expected, values),
+            Self::Cmp(ordering, or_equal, bound) => any(&|v| {
+                order::type_rank(v) == order::type_rank(bound) && {
+                    let actual = order::compare(v, bound);
+                    actual == *ordering || (*or_equal && actual == Ordering::Equal)
+                }
+            }),
+            Self::In(patterns) => patterns.iter().any(|pattern| match pattern {
+                Pattern::Value(expected) => eq(expected, values),
+                Pattern::Regex(regex) => any(&|v| is_match(regex, v)),
+            }),
+            Self::Nin(patterns) => !patterns.iter().any(|pattern| match pattern {
+                Pattern::Value(expected) => eq(expected, values),
+                Pattern::Regex(regex) => any(&|v| is_match(regex, v)),
+            }),
+            Self::Exists(exists) => values.is_empty() != *exists,
+            Self::Type(types) => any(&|v| {
+                let name = stored::type_name(v);
+                types
+                    .iter()
+                    .any(|t| *t == name || (*t == "number" && Number::new(v).is_some()))
+            }),
+            Self::Size(size) => values
+                .iter()
+                .any(|v| matches!(v, Bson::Array(array) if array.len() == *size)),
+            Self::All(expected) => {
+                !expected.is_empty() && expected.iter().all(|e| Self::Eq(e.clone()).matches(values))
+            }
+            Self::ElemMatch(elem_match) => values.iter().any(|v| match v {
+                Bson::Array(array) => array.iter().any(|item| elem_match.matches(item)),
+                _ => false,
+            }),
+            Self::Regex(regex) => any(&|v| is_match(regex, v)),
+            Self::Mod(divisor, remainder) => any(&|v| match Number::new(v) {
+                Some(Number::Int(i)) => i.wrapping_rem(*divisor) == *remainder,
+                Some(Number::Double(f)) if f.is_finite() => {
+                    (f.trunc() as i64).wrapping_rem(*divisor) == *remainder
+                }
+                _ => false,
+            }),
+            Self::Not(conditions) => !conditions.iter().all(|c| c.matches(values)),
+        }
+    }
+}
+
+impl ElemMatch {
+    pub(crate) fn matches(&self, item: &Bson) -> bool {
+        match self {
+            Self::Values(conditions) => conditions.iter().all(|c| c.matches(&[item])),
+            Self::Documents(filter) => {
+                matches!(item, Bson::Document(_)) && filter.matches_value(item)
+            }
+        }
+    }
+
+    /// Parse the argument of `$elemMatch`, or of `$pull` when it holds a condition.
+    pub(crate) fn new(argument: &Document) -> Result<Self, FilterError> {
+        let operators = argument
+            .keys()
+            .all(|key| key.starts_with('$') && !matches!(key.as_str(), "$and" | "$or" | "$nor"));
+        if operators && !argument.is_empty() {
+            Ok(Self::Values(operators_of(argument)?))
+        } else {
+            Ok(Self::Documents(Filter::new(argument)?))
+        }
+    }
+}
+
+fn is_match(regex: &Regex, value: &Bson) -> bool {
+    match value {
+        Bson::String(s) | Bson::Symbol(s) => regex.is_match(s),
+        _ => false,
+    }
+}
+
+/// The filters of `$and`, `$or` and `$nor`.
+fn filters(operator: &str, value: &Bson) -> Result<Vec<Filter>, FilterError> {
+    let error = || FilterError::new(format!("`{operator}` needs a non-empty array of documents"));
+    match value {
+        Bson::Array(array) if !array.is_empty() => array
+            .iter()
+            .map(|filter| Filter::new(filter.as_document().ok_or_else(error)?))
+            .collect(),
+        _ => Err(error()),
+    }
+}
+
+/// The conditions on a field, either an operator document or a value to compare with.
+fn conditions(value: &Bson) -> Result<Vec<Condition>, FilterError> {
+    match value {
+        Bson::Document(doc) if doc.keys().next().is_some_and(|key| key.starts_with('$')) => {
+            operators_of(doc)
+        }
+        Bson::RegularExpression(regex) => Ok(vec![Condition::Regex(build_regex(
+            &regex.pattern,
+            &regex.options,
+        )?)]),
+        value => Ok(vec![Condition::Eq(value.clone())]),
+    }
+}
+
+fn operators_of(doc: &Document) -> Result<Vec<Condition>, FilterError> {
+    let mut parsed = Vec::new();
+    for (operator, argument) in doc {
+        let condition = match operator.as_str() {
+            "$eq" => Condition::Eq(argument.clone()),
+            "$ne" => Condition::Ne(argument.clone()),
+            "$gt" => Condition::Cmp(Ordering::Greater, false, argument.clone()),
+            "$gte" => Condition::Cmp(Ordering::Greater, true, argument.clone()),
+            "$lt" => Condition::Cmp(Ordering::Less, false, argument.clone()),
+            "$lte" => Condition::Cmp(Ordering::Less, true, argument.clone()),
+            "$in" => Condition::In(patterns(operator, argument)?),
+            "$nin" => Condition::Nin(patterns(operator, argument)?),
+            "$exists" => Condition::Exists(match argument {
+                Bson::Boolean(b) => *b,
+                other => Number::new(other).is_some_and(|n| n.as_f64() != 0.0),
+            }),
+            "$type" => Condition::Type(match argument {
+                Bson::Array(types) => types.iter().map(type_alias).collect::<Result<_, _>>()?,
+                single => vec![type_alias(single)?],
+            }),
+            "$size" => match Number::new(argument) {
+                Some(Number::Int(size)) if size >= 0 => Condition::Size(size as usize),
+                _ => return Err(FilterError::new("`$size` needs a non-negative integer")),
+            },
+            "$all" => match argument {
+                Bson::Array(values) => Condition::All(values.clone()),
+                _ => return Err(FilterError::new("`$all` needs an array")),
+            },
+            "$elemMatch" => match argument {
+                Bson::Document(doc) => Condition::ElemMatch(ElemMatch::new(doc)?),
+                _ => return Err(FilterError::new("`$elemMatch` needs a document")),
+            },
+            "$regex" => {
+                let options = match doc.get("$options") {
+                    Some(Bson::String(options)) => options.as_str(),
+                    Some(_) => return Err(FilterError::new("`$options` needs a string")),
+                    None => "",
+                };
+                match argument {
+                    Bson::String(pattern) => Condition::Regex(build_regex(pattern, options)?),
+                    Bson::RegularExpression(regex) => Condition::Regex(build_regex(
+                        &regex.pattern,
+                        &format!("{}{options}", regex.options),
+                    )?),
+                    _ => return Err(FilterError::new("`$regex` needs a string")),
+                }
+            }
+            "$options" if doc.contains_key("$regex") => continue,
+            "$mod" => match argument.as_array().map(|a| a.as_slice()) {
+                Some([divisor, remainder]) => {
+                    match (Number::new(divisor), Number::new(remainder)) {
+                        (Some(divisor), Some(remainder)) if divisor.as_f64().trunc() != 0.0 => {
+                            Condition::Mod(divisor.as_f64() as i64, remainder.as_f64() as i64)
+                        }
+                        _ => return Err(FilterError::new("`$mod` needs a non-zero divisor")),
+                    }
+                }
+                _ => return Err(FilterError::new("`$mod` needs `[divisor, remainder]`")),
+            },
+            "$not" => match argument {
+                Bson::Document(doc) => Condition::Not(operators_of(doc)?),
+                Bson::RegularExpression(_) => Condition::Not(conditions(argument)?),
+                _ => {
+                    return Err(FilterError::new(
+                        "`$not` needs an operator document or a regex",
+                    ))
+                }
+            },
+            operator if operator.starts_with('$') => {
+                return Err(FilterError::new(format!(
+                    "unsupported operator `{operator}`"
+                )))
+            }
+            key => {
+                return Err(FilterError::new(format!(
+                    "the field `{key}` can't be mixed with operators"
+                )))
+            }
+        };
+        parsed.push(condition);
+    }
+    Ok(parsed)
+}
+
+fn patterns(operator: &str, argument: &Bson) -> Result<Vec<Pattern>, FilterError> {
+    let Bson::Array(values) = argument else {
+        return Err(FilterError::new(format!("`{operator}` needs an array")));
+    };
+    values
+        .iter()
+        .map(|value| match value {
+            Bson::RegularExpression(regex) => {
+                build_regex(&regex.pattern, &regex.options).map(Pattern::Regex)
+            }
+            value => Ok(Pattern::Value(value.clone())),
+        })
+        .collect()
+}
+
+/// The name of a `$type` argument, given by its alias or its number.
+fn type_alias(value: &Bson) -> Result<&'static str, FilterError> {
+    const TYPES: [(i64, &str); 21] = [
+        (1, "double"),
+        (2, "string"),
+        (3, "object"),
+        (4, "array"),
+        (5, "binData"),
+        (6, "undefined"),
+        (7, "objectId"),
+        (8, "bool"),
+        (9, "date"),
+        (10, "null"),
+        (11, "regex"),
+        (12, "dbPointer"),
+        (13, "javascript"),
+        (14, "symbol"),
+        (15, "javascriptWithScope"),
+        (16, "int"),
+        (17, "timestamp"),
+        (18, "long"),
+        (19, "decimal"),
+        (-1, "minKey"),
+        (127, "maxKey"),
+    ];
+    let found = match (value, Number::new(value)) {
+        (Bson::String(alias), _) if alias == "number" => Some("number"),
+        (Bson::String(alias), _) => TYPES.iter().find(|(_, name)| name == alias).map(|t| t.1),
+        (_, Some(Number::Int(code))) => TYPES
+            .iter()
+            .find(|(number, _)| *number == code)
+            .map(|t| t.1),
+        _ => None,
+    };
+    found.ok_or_else(|| FilterError::new(format!("unknown `$type` {value}")))
+}
+
+/// Stands in for [`regex::Regex`] without the `regex` feature, where regexes are rejected.
+#[cfg(not(feature = "regex"))]
+#[derive(Debug, Clone)]
+pub(crate) enum Regex {}
+
+#[cfg(not(feature = "regex"))]
+impl Regex {
+    fn is_match(&self, _: &str) -> bool {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "regex"))]
+fn build_regex(_: &str, _: &str) -> Result<Regex, FilterError> {
+    Err(FilterError::new("regexes need the `regex` feature"))
+}
+
+#[cfg(feature = "regex")]
+fn build_regex(pattern: &str, options: &str) -> Result<Regex, FilterError> {
+    let mut builder = RegexBuilder::new(pattern);
+    for option in options.chars() {
+        match option {
+            'i' => builder.case_insensitive(true),
+            'm' => builder.multi_line(true),
+            's' => builder.dot_matches_new_line(true),
+            'x' => builder.ignore_whitespace(true),
+            option => {
+                return Err(FilterError::new(format!(
+                    "unsupported regex option `{option}`"
+                )))
+            }
+        };
+    }
+    builder
+        .build()
+        .map_err(|e| FilterError::new(format!("invalid regex `{pattern}`: {e}")))
+}
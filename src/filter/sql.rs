@@ -0,0 +1,666 @@
+//! Filters compiled to sql `WHERE` clauses.
+
+use super::{Filter, FilterError};
+use crate::order::Number;
+use crate::stored::{Layout, Stored};
+use bson::spec::BinarySubtype;
+use bson::{Bson, Document};
+use std::fmt::Write;
+
+/// The sql a filter is compiled to, see [`SqlFilter::new`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// sqlite, with the functions of [`register_functions`](crate::rusqlite::register_functions)
+    /// over a bson column.
+    Sqlite,
+    /// PostgreSQL over a `jsonb` column written with the [`ExtJson`](crate::codec::ExtJson) or
+    /// [`Json`](crate::codec::Json) codec, `envelope` is `true` for [`DBson`](crate::DBson)
+    /// columns and `false` for [`DBsonDoc`](crate::DBsonDoc) ones.
+    Postgres { envelope: bool },
+}
+
+/// A value bound to a placeholder of a [`SqlFilter`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlParam {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// A filter compiled to a parameterized sql expression.
+///
+/// Fields are read with `bson_extract(column, '$.path')` in sqlite and with
+/// `(column #> '{path}')` in PostgreSQL. The path is written into the sql and only the values are
+/// bound, so an index on the same expression (or a generated column defined by it) can be used
+/// by the query planner:
+/// ```rust
+/// # #[cfg(feature = "rusqlite-functions")] {
+/// use bson::doc;
+/// use dbson::filter::{Dialect, SqlFilter};
+/// let filter = SqlFilter::new(&doc! { "age": { "$gte": 18 } }, "data", Dialect::Sqlite).unwrap();
+/// assert!(filter.sql.starts_with(
+///     "(((bson_extract(data, '$.age') >= ?1 \
+///      AND bson_type(data, '$.age') IN ('int', 'long', 'double'))"
+/// ));
+///
+/// let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// dbson::rusqlite::register_functions(&conn).unwrap();
+/// conn.execute_batch(
+///     "CREATE TABLE people (data BLOB);
+///      CREATE INDEX people_age ON people (bson_extract(data, '$.age'));",
+/// )
+/// .unwrap();
+/// for age in [12, 40] {
+///     let person = dbson::DBson::new(doc! { "age": age });
+///     conn.execute("INSERT INTO people VALUES (?1)", [person]).unwrap();
+/// }
+/// let query = format!("SELECT count(*) FROM people WHERE {}", filter.sql);
+/// let count: i64 = conn
+///     .query_row(&query, rusqlite::params_from_iter(&filter.params), |row| row.get(0))
+///     .unwrap();
+/// assert_eq!(count, 1);
+/// # }
+/// ```
+///
+/// Placeholders are numbered from 1 (`?1` in sqlite, `$1` in PostgreSQL) in the order of
+/// [`params`](Self::params). PostgreSQL parameters are json text, cast with `$1::text::jsonb`.
+///
+/// Equality and comparisons (`$eq`, `$ne`, `$gt`, `$gte`, `$lt`, `$lte`, `$in`, `$nin`) with
+/// numbers, strings, booleans, `null` and ObjectIds, `$exists`, `$not` and the logical operators
+/// are compiled. Anything else falls back to `bson_match(column, ?)` in sqlite, and so do the
+/// rows where the field may be in an array (it extracts as a blob, or as `NULL` under a dotted
+/// path), so the results are the same as with [`Filter::matches`]. PostgreSQL has no such
+/// function: the clauses that can't be compiled are returned as the
+/// [`residual`](Self::residual) filter, to be applied to the rows in Rust, and so are the
+/// compiled ones, whose sql also keeps the rows where the field may be in an array.
+#[derive(Debug, Clone)]
+pub struct SqlFilter {
+    /// A boolean sql expression, `TRUE` for an empty filter.
+    pub sql: String,
+    pub params: Vec<SqlParam>,
+    /// The part of the filter [`sql`](Self::sql) doesn't check exactly, always `None` for sqlite.
+    pub residual: Option<Filter>,
+}
+
+impl SqlFilter {
+    /// Compile `filter` over the bson or json `column` (any sql expression).
+    pub fn new(filter: &Document, column: &str, dialect: Dialect) -> Result<Self, FilterError> {
+        // reject invalid filters up front, the compiler only deals with valid ones
+        Filter::new(filter)?;
+        let mut compiler = Compiler {
+            column,
+            dialect,
+            params: Vec::new(),
+            inexact: false,
+        };
+        let (parts, residual) = compiler.document(filter);
+        let residual = if residual.is_empty() {
+            None
+        } else {
+            Some(Filter::new(&residual)?)
+        };
+        Ok(Self {
+            sql: and(parts),
+            params: compiler.params,
+            residual,
+        })
+    }
+}
+
+/// The direction of a key of an [`Index`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexOrder {
+    Ascending,
+    Descending,
+}
+
+/// A sqlite index over fields of a bson column, used by the queries of a [`SqlFilter`].
+///
+/// Every key is indexed with the `bson_extract(column, '$.path')` expression the compiler
+/// reads it with, so sqlite keeps the index up to date on every write and picks it for the
+/// filters on its fields like for any other index.
+/// ```rust
+/// use dbson::filter::Index;
+/// let by_email = Index::ascending("email").unique();
+/// let by_city_and_age = Index::ascending("address.city").and_descending("age");
+/// let by_nickname = Index::ascending("nickname").sparse();
+/// assert_eq!(by_city_and_age.name_or_default(), "address.city_1_age_-1");
+/// ```
+///
+/// A `unique` index rejects two values with equal keys. As in any sqlite index a missing field
+/// (or a `null` one) never equals another, so unlike MongoDB any number of values can lack the
+/// fields of a unique index. A `sparse` index leaves out the values where every field is
+/// missing or `null`; sqlite only uses it for filters which can't match those values, like
+/// comparisons of its field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    keys: Vec<(String, IndexOrder)>,
+    name: Option<String>,
+    unique: bool,
+    sparse: bool,
+}
+
+impl Index {
+    /// An index on the field at the dotted `path`, in ascending order.
+    pub fn ascending(path: impl Into<String>) -> Self {
+        Self::new(path.into(), IndexOrder::Ascending)
+    }
+
+    /// An index on the field at the dotted `path`, in descending order.
+    pub fn descending(path: impl Into<String>) -> Self {
+        Self::new(path.into(), IndexOrder::Descending)
+    }
+
+    fn new(path: String, order: IndexOrder) -> Self {
+        Self {
+            keys: vec![(path, order)],
+            name: None,
+            unique: false,
+            sparse: false,
+        }
+    }
+
+    /// Add the field at `path` as the next key of a compound index, in ascending order.
+    pub fn and_ascending(mut self, path: impl Into<String>) -> Self {
+        self.keys.push((path.into(), IndexOrder::Ascending));
+        self
+    }
+
+    /// Add the field at `path` as the next key of a compound index, in descending order.
+    pub fn and_descending(mut self, path: impl Into<String>) -> Self {
+        self.keys.push((path.into(), IndexOrder::Descending));
+        self
+    }
+
+    /// Name the index, instead of the MongoDB style default of [`name_or_default`](Self::name_or_default).
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Reject values with the same keys as a value already in the index.
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    /// Leave out the values which have none of the fields of the index.
+    pub fn sparse(mut self) -> Self {
+        self.sparse = true;
+        self
+    }
+
+    pub fn keys(&self) -> &[(String, IndexOrder)] {
+        &self.keys
+    }
+
+    pub fn is_unique(&self) -> bool {
+        self.unique
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.sparse
+    }
+
+    /// The name of the index, by default its keys and orders joined by `_` (`"a_1_b_-1"`).
+    pub fn name_or_default(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .keys
+                .iter()
+                .map(|(path, order)| match order {
+                    IndexOrder::Ascending => format!("{path}_1"),
+                    IndexOrder::Descending => format!("{path}_-1"),
+                })
+                .collect::<Vec<_>>()
+                .join("_"),
+        }
+    }
+
+    /// The sqlite statement creating the index `name` (a quoted identifier) on `column` of
+    /// `table`, if it doesn't exist yet.
+    #[cfg(any(feature = "rusqlite-functions", feature = "sqlx-sqlite"))]
+    pub(crate) fn create_sql(
+        &self,
+        name: &str,
+        table: &str,
+        column: &str,
+    ) -> Result<String, FilterError> {
+        let compiler = Compiler {
+            column,
+            dialect: Dialect::Sqlite,
+            params: Vec::new(),
+            inexact: false,
+        };
+        let mut keys = Vec::new();
+        let mut present = Vec::new();
+        for (path, order) in &self.keys {
+            if path.contains('"') {
+                let message = format!("the field `{path}` can't be indexed, its path has a `\"`");
+                return Err(FilterError::new(message));
+            }
+            let field = compiler.extract(path);
+            keys.push(match order {
+                IndexOrder::Ascending => format!("{field} ASC"),
+                IndexOrder::Descending => format!("{field} DESC"),
+            });
+            present.push(format!("{field} IS NOT NULL"));
+        }
+        let mut sql = format!(
+            "CREATE {}INDEX IF NOT EXISTS {name} ON {table} ({})",
+            if self.unique { "UNIQUE " } else { "" },
+            keys.join(", ")
+        );
+        if self.sparse {
+            let _ = write!(sql, " WHERE {}", present.join(" OR "));
+        }
+        Ok(sql)
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl ::rusqlite::ToSql for SqlParam {
+    fn to_sql(&self) -> ::rusqlite::Result<::rusqlite::types::ToSqlOutput<'_>> {
+        use ::rusqlite::types::{ToSqlOutput, ValueRef};
+        Ok(ToSqlOutput::Borrowed(match self {
+            Self::Integer(i) => ValueRef::Integer(*i),
+            Self::Real(f) => ValueRef::Real(*f),
+            Self::Text(s) => ValueRef::Text(s.as_bytes()),
+            Self::Blob(b) => ValueRef::Blob(b),
+        }))
+    }
+}
+
+const NUMBER_TYPES: &str = "IN ('int', 'long', 'double')";
+
+struct Compiler<'a> {
+    column: &'a str,
+    dialect: Dialect,
+    params: Vec<SqlParam>,
+    /// Whether the sql compiled since this was last reset also keeps rows that don't match.
+    inexact: bool,
+}
+
+impl Compiler<'_> {
+    /// The sql of every clause of `filter` that could be compiled and a filter of the others.
+    fn document(&mut self, filter: &Document) -> (Vec<String>, Document) {
+        let mut parts = Vec::new();
+        let mut residual = Document::new();
+        for (key, value) in filter {
+            let params = self.params.len();
+            self.inexact = false;
+            let sql = match key.as_str() {
+                "$comment" => continue,
+                "$and" => self.all(value).map(and),
+                "$or" => self.all(value).map(or),
+                "$nor" => self.all(value).map(|parts| not(&or(parts))),
+                path => self.field(path, value),
+            };
+            match sql {
+                Some(sql) => {
+                    parts.push(sql);
+                    // the rows kept by the sql still have to be checked against the clause
+                    if self.inexact {
+                        residual.insert(key, value.clone());
+                    }
+                }
+                None => {
+                    self.params.truncate(params);
+                    residual.insert(key, value.clone());
+                }
+            }
+        }
+        (parts, residual)
+    }
+
+    /// The sql of a filter that has to be compiled whole, because it's negated or alternated.
+    fn exact(&mut self, filter: &Document) -> Option<String> {
+        let params = self.params.len();
+        match self.document(filter) {
+            (parts, residual) if residual.is_empty() => Some(and(parts)),
+            _ => {
+                self.params.truncate(params);
+                None
+            }
+        }
+    }
+
+    fn all(&mut self, filters: &Bson) -> Option<Vec<String>> {
+        let Bson::Array(filters) = filters else {
+            return None;
+        };
+        filters
+            .iter()
+            .map(|filter| self.exact(filter.as_document()?))
+            .collect()
+    }
+
+    fn field(&mut self, path: &str, value: &Bson) -> Option<String> {
+        if self.dialect == Dialect::Sqlite && path.contains('"') {
+            // sqlite json paths can't quote a `"`
+            return self.fallback(path, value, |_, _| None);
+        }
+        let operators = match value {
+            Bson::Document(doc) if doc.keys().next().is_some_and(|key| key.starts_with('$')) => doc,
+            value => return self.fallback(path, value, |p, s| s.operator(p, "$eq", value)),
+        };
+        let mut parts = Vec::new();
+        for (operator, argument) in operators {
+            let sql = match operator.as_str() {
+                // compiled along with `$regex`, which falls back
+                "$options" => continue,
+                "$regex" => None,
+                operator => self.operator(path, operator, argument),
+            };
+            let sql = match sql {
+                Some(sql) => sql,
+                None => {
+                    let mut condition = Document::new();
+                    condition.insert(operator, argument.clone());
+                    if operator == "$regex" {
+                        if let Some(options) = operators.get("$options") {
+                            condition.insert("$options", options.clone());
+                        }
+                    }
+                    self.fallback(path, &Bson::Document(condition), |_, _| None)?
+                }
+            };
+            parts.push(sql);
+        }
+        Some(and(parts))
+    }
+
+    /// Compile a field clause with `compile`, or check it with `bson_match` in sqlite.
+    fn fallback(
+        &mut self,
+        path: &str,
+        condition: &Bson,
+        compile: impl FnOnce(&str, &mut Self) -> Option<String>,
+    ) -> Option<String> {
+        let params = self.params.len();
+        if let Some(sql) = compile(path, self) {
+            return Some(sql);
+        }
+        self.params.truncate(params);
+        match self.dialect {
+            Dialect::Sqlite => self.bson_match(path, condition),
+            Dialect::Postgres { .. } => None,
+        }
+    }
+
+    /// `bson_match(column, ?)` with the filter `{path: condition}`.
+    fn bson_match(&mut self, path: &str, condition: &Bson) -> Option<String> {
+        let mut filter = Document::new();
+        filter.insert(path, condition.clone());
+        // always written in an envelope, a field named `inner` would look like one
+        let blob = Stored::new(Bson::Document(filter), Layout::Envelope)
+            .to_vec()
+            .ok()?;
+        let blob = self.param(SqlParam::Blob(blob));
+        Some(format!("bson_match({}, {blob})", self.column))
+    }
+
+    fn operator(&mut self, path: &str, operator: &str, argument: &Bson) -> Option<String> {
+        let direct = match operator {
+            "$eq" => self.eq(path, argument)?,
+            "$ne" => return self.negated(|s| s.operator(path, "$eq", argument)),
+            "$gt" => self.compare(path, ">", argument)?,
+            "$gte" => self.compare(path, ">=", argument)?,
+            "$lt" => self.compare(path, "<", argument)?,
+            "$lte" => self.compare(path, "<=", argument)?,
+            "$in" => self.any_of(path, argument)?,
+            "$nin" => return self.negated(|s| s.operator(path, "$in", argument)),
+            "$exists" => {
+                let exists = match argument {
+                    Bson::Boolean(b) => *b,
+                    other => Number::new(other).is_some_and(|n| n.as_f64() != 0.0),
+                };
+                let null = if exists { "IS NOT NULL" } else { "IS NULL" };
+                format!("{} {null}", self.type_of(path))
+            }
+            "$not" => {
+                let Bson::Document(conditions) = argument else {
+                    return None;
+                };
+                return self.negated(|s| {
+                    let mut parts = Vec::new();
+                    for (operator, argument) in conditions {
+                        parts.push(s.operator(path, operator, argument)?);
+                    }
+                    Some(and(parts))
+                });
+            }
+            _ => return None,
+        };
+        // encrypted fields hold a single binary, never an array, and the plain comparison is
+        // what lets a deterministic lookup use an index on the field
+        let encrypted = match (operator, argument) {
+            ("$eq", value) => is_encrypted(value),
+            ("$in", Bson::Array(values)) => values.iter().all(is_encrypted),
+            _ => false,
+        };
+        if encrypted {
+            return Some(direct);
+        }
+        let mut condition = Document::new();
+        condition.insert(operator, argument.clone());
+        self.arrays(path, &condition, direct)
+    }
+
+    /// `NOT` the sql of `compile`, which has to be exact to be negated.
+    fn negated(&mut self, compile: impl FnOnce(&mut Self) -> Option<String>) -> Option<String> {
+        let inexact = std::mem::replace(&mut self.inexact, false);
+        let sql = compile(self).filter(|_| !self.inexact);
+        self.inexact = inexact;
+        sql.map(|sql| not(&sql))
+    }
+
+    /// `direct`, the sql of `condition` on the field at `path` when neither the field nor a
+    /// document above it is an array, extended to look into arrays like [`Filter::matches`].
+    ///
+    /// The field extracts as a blob when it's an array (or any other blob) and as `NULL` when a
+    /// document above it is one, those rows are checked with `bson_match` in sqlite. Both read
+    /// the same `bson_extract` as `direct`, so an index on it is still used. PostgreSQL keeps
+    /// these rows for the [`residual`](SqlFilter::residual) filter to check instead.
+    fn arrays(&mut self, path: &str, condition: &Document, direct: String) -> Option<String> {
+        let field = self.extract(path);
+        let mut array = match self.dialect {
+            // few values are blobs, without the hint sqlite prefers a scan to the index
+            Dialect::Sqlite => format!("likelihood({field} >= X'', 0.001)"),
+            Dialect::Postgres { .. } => format!("{} = 'array'", self.type_of(path)),
+        };
+        if path.contains('.') {
+            array = format!("({array} OR {field} IS NULL)");
+        }
+        match self.dialect {
+            Dialect::Sqlite => {
+                let matches = self.bson_match(path, &Bson::Document(condition.clone()))?;
+                Some(format!(
+                    "(({direct} AND {}) OR ({array} AND {matches}))",
+                    not(&array)
+                ))
+            }
+            Dialect::Postgres { .. } => {
+                self.inexact = true;
+                Some(format!("({direct} OR {array})"))
+            }
+        }
+    }
+
+    fn any_of(&mut self, path: &str, values: &Bson) -> Option<String> {
+        let Bson::Array(values) = values else {
+            return None;
+        };
+        let parts = values
+            .iter()
+            .map(|value| self.eq(path, value))
+            .collect::<Option<_>>()?;
+        Some(or(parts))
+    }
+
+    fn eq(&mut self, path: &str, value: &Bson) -> Option<String> {
+        let field = self.extract(path);
+        let type_of = self.type_of(path);
+        match self.dialect {
+            Dialect::Sqlite => {
+                let (param, types) = match value {
+                    // null matches missing fields as well, both extract as NULL
+                    Bson::Null => return Some(format!("{field} IS NULL")),
+                    Bson::Boolean(b) => (SqlParam::Integer((*b).into()), "= 'bool'"),
+                    Bson::ObjectId(oid) => (SqlParam::Text(oid.to_hex()), "= 'objectId'"),
+                    // binaries extract as their bytes, without the subtype: only encrypted ones
+                    // start with a header no other value is expected to have, so the bytes are
+                    // compared on their own, like the index on the field stores them
+                    Bson::Binary(binary) if binary.subtype == BinarySubtype::Encrypted => {
+                        let param = self.param(SqlParam::Blob(binary.bytes.clone()));
+                        return Some(format!("{field} = {param}"));
+                    }
+                    value => sqlite_ordered(value)?,
+                };
+                let param = self.param(param);
+                Some(format!("({field} = {param} AND {type_of} {types})"))
+            }
+            Dialect::Postgres { .. } => match value {
+                Bson::Null => Some(format!("coalesce({type_of}, 'null') = 'null'")),
+                value => {
+                    let param = self.json_param(value);
+                    Some(format!("{field} = {param}"))
+                }
+            },
+        }
+    }
+
+    fn compare(&mut self, path: &str, operator: &str, value: &Bson) -> Option<String> {
+        let field = self.extract(path);
+        let type_of = self.type_of(path);
+        let (param, types) = match self.dialect {
+            Dialect::Sqlite => {
+                let (param, types) = sqlite_ordered(value)?;
+                (self.param(param), types)
+            }
+            Dialect::Postgres { .. } => {
+                let types = match value {
+                    Bson::Int32(_) | Bson::Int64(_) => "= 'number'",
+                    Bson::Double(f) if f.is_finite() => "= 'number'",
+                    Bson::String(_) => "= 'string'",
+                    _ => return None,
+                };
+                (self.json_param(value), types)
+            }
+        };
+        Some(format!(
+            "({field} {operator} {param} AND {type_of} {types})"
+        ))
+    }
+
+    /// The sql reading the field at the dotted `path`.
+    fn extract(&self, path: &str) -> String {
+        match self.dialect {
+            Dialect::Sqlite => format!("bson_extract({}, {})", self.column, json_path(path)),
+            Dialect::Postgres { envelope } => {
+                format!("({} #> {})", self.column, text_array(path, envelope))
+            }
+        }
+    }
+
+    /// The sql for the type of the field at `path`, `NULL` if it's missing.
+    fn type_of(&self, path: &str) -> String {
+        match self.dialect {
+            Dialect::Sqlite => format!("bson_type({}, {})", self.column, json_path(path)),
+            Dialect::Postgres { envelope } => {
+                format!(
+                    "jsonb_typeof({} #> {})",
+                    self.column,
+                    text_array(path, envelope)
+                )
+            }
+        }
+    }
+
+    fn param(&mut self, param: SqlParam) -> String {
+        self.params.push(param);
+        match self.dialect {
+            Dialect::Sqlite => format!("?{}", self.params.len()),
+            Dialect::Postgres { .. } => format!("${}", self.params.len()),
+        }
+    }
+
+    fn json_param(&mut self, value: &Bson) -> String {
+        let json = value.clone().into_relaxed_extjson().to_string();
+        format!("{}::text::jsonb", self.param(SqlParam::Text(json)))
+    }
+}
+
+/// The sqlite value and `bson_type` condition of a number or a string, the values sqlite orders
+/// the way MongoDB does.
+fn sqlite_ordered(value: &Bson) -> Option<(SqlParam, &'static str)> {
+    Some(match value {
+        Bson::Int32(i) => (SqlParam::Integer((*i).into()), NUMBER_TYPES),
+        Bson::Int64(i) => (SqlParam::Integer(*i), NUMBER_TYPES),
+        Bson::Double(f) if !f.is_nan() => (SqlParam::Real(*f), NUMBER_TYPES),
+        Bson::String(s) => (SqlParam::Text(s.clone()), "= 'string'"),
+        _ => return None,
+    })
+}
+
+fn and(parts: Vec<String>) -> String {
+    match parts.len() {
+        0 => "TRUE".to_string(),
+        1 => parts.into_iter().next().unwrap_or_default(),
+        _ => format!("({})", parts.join(" AND ")),
+    }
+}
+
+fn or(parts: Vec<String>) -> String {
+    match parts.len() {
+        0 => "FALSE".to_string(),
+        1 => parts.into_iter().next().unwrap_or_default(),
+        _ => format!("({})", parts.join(" OR ")),
+    }
+}
+
+/// `NOT sql`, with a missing field (`NULL` in sql) counting as not matching `sql`.
+fn not(sql: &str) -> String {
+    format!("NOT coalesce({sql}, FALSE)")
+}
+
+/// Whether `value` is an encrypted binary, like the fields of the `encryption` feature.
+fn is_encrypted(value: &Bson) -> bool {
+    matches!(value, Bson::Binary(binary) if binary.subtype == BinarySubtype::Encrypted)
+}
+
+/// The dotted `path` as a quoted sqlite json path, `'$.a.b'`.
+fn json_path(path: &str) -> String {
+    let mut json = String::from("$");
+    for key in path.split('.') {
+        if key.is_empty() || key.contains('[') {
+            let _ = write!(json, ".\"{key}\"");
+        } else {
+            let _ = write!(json, ".{key}");
+        }
+    }
+    quote(&json)
+}
+
+/// The dotted `path` as a quoted PostgreSQL text array, `'{inner,a,b}'`.
+fn text_array(path: &str, envelope: bool) -> String {
+    let keys = envelope
+        .then_some(crate::stored::ENVELOPE_KEY)
+        .into_iter()
+        .chain(path.split('.'))
+        .map(|key| {
+            let plain = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_');
+            match plain {
+                true => key.to_string(),
+                false => format!("\"{}\"", key.replace('\\', "\\\\").replace('"', "\\\"")),
+            }
+        })
+        .collect::<Vec<_>>();
+    quote(&format!("{{{}}}", keys.join(",")))
+}
+
+fn quote(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
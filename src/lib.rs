@@ -8,7 +8,19 @@
 //!
 //!
 //!
-//! Currently only supports [rusqlite](https://docs.rs/rusqlite) and [sqlx](https://docs.rs/sqlx)
+//! Currently supports [rusqlite](https://docs.rs/rusqlite), [sqlx](https://docs.rs/sqlx) and
+//! [diesel](https://docs.rs/diesel) (enable `diesel-sqlite`, `diesel-postgres` and/or
+//! `diesel-mysql` for the backends you use) and [postgres](https://docs.rs/postgres) /
+//! [tokio-postgres](https://docs.rs/tokio-postgres) through `postgres-types`
+//!
+//! With rusqlite, [`rusqlite::register_functions`] adds sql functions (`bson_extract`,
+//! `bson_type`, ...) to query and index the fields inside of the stored blobs.
+//! MongoDB style [filters](filter) and [updates](DBson::apply_update) work on the stored values
+//! both from Rust and from sql.
+//! With `sqlx-sqlite` or `sqlx-postgres`, [`sqlx::Collection`] stores values in a table behind an
+//! async MongoDB like API.
+//! With `encryption`, [`encryption::EncryptedDBson`] encrypts the stored values, and
+//! [`encryption::Encrypted`] fields (or [`encrypted`]) single fields inside of them.
 //!
 //! It's basically a newtype wrapper over T
 //! So it implements many of the same traits as T
@@ -35,29 +47,198 @@
 //!
 //! However do note that since the data is just a blob if you insert a hashmap and then try to
 //! query it back out as a vector it will fail.
+//!
+//! The bytes are produced by a [`Codec`], [BSON](codec::Bson) by default.
+//! Other formats can be picked per column with the second type parameter, see [`codec`].
+//!
+//! [`DBson`] stores the value inside of an envelope document (`{"inner": ...}`), which is what
+//! allows it to hold values that aren't documents themselves, like the vector above.
+//! [`DBsonDoc`] stores the value as the document itself, which is only possible if `T`
+//! serializes as a map, a struct or a sequence. The layout is part of the type, so both can be used side by
+//! side in the same program (or even the same table) without affecting each other.
+
+pub mod codec;
+mod compat;
+#[cfg(feature = "zstd")]
+#[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+pub mod dictionary;
+#[cfg(feature = "encryption")]
+#[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+pub mod encrypted;
+#[cfg(feature = "encryption")]
+#[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+pub mod encryption;
+mod error;
+pub mod filter;
+mod order;
+#[cfg(feature = "rusqlite-functions")]
+mod path;
+#[cfg(feature = "rusqlite")]
+#[cfg_attr(docsrs, doc(cfg(feature = "rusqlite")))]
+pub mod rusqlite;
+#[cfg(any(feature = "sqlx-sqlite", feature = "sqlx-postgres"))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(any(feature = "sqlx-sqlite", feature = "sqlx-postgres")))
+)]
+pub mod sqlx;
+mod stored;
+mod update;
 
-use serde::{Deserialize, Serialize};
+pub use codec::Codec;
+pub use error::Error;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::marker::PhantomData;
 
 /// A wrapper type for serializable data.
 ///
 /// Any type that implements serde::Deserialize && serde::Serialize can be wrapped by this type.
 /// and used inside of a database as a blob.
+///
+/// `C` is the [`Codec`] used to encode the blob, [`codec::Bson`] unless specified otherwise.
+///
+/// The value is stored inside of an envelope as `{"inner": <value>}`.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-#[cfg_attr(feature = "transparent", serde(transparent))]
+#[cfg_attr(
+    feature = "diesel",
+    derive(diesel::expression::AsExpression, diesel::deserialize::FromSqlRow),
+    diesel(sql_type = diesel::sql_types::Binary)
+)]
 #[repr(transparent)]
-pub struct DBson<T> {
+pub struct DBson<T, C = codec::Bson> {
     inner: T,
+    #[serde(skip)]
+    codec: PhantomData<C>,
 }
 
-impl<T> From<T> for DBson<T> {
+impl<T, C> From<T> for DBson<T, C> {
     fn from(inner: T) -> Self {
-        Self { inner }
+        Self::with_codec(inner)
     }
 }
 
 impl<T> DBson<T> {
     pub fn new(inner: T) -> Self {
-        Self { inner }
+        Self::with_codec(inner)
+    }
+}
+
+impl<T, C> DBson<T, C> {
+    /// Wrap `inner` to be stored using the codec `C`.
+    pub fn with_codec(inner: T) -> Self {
+        Self {
+            inner,
+            codec: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Serialize, C: Codec> DBson<T, C> {
+    /// Encode the value into the bytes that get stored in the database.
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        codec::encode::<T, C>(self)
+    }
+}
+
+impl<T: DeserializeOwned, C: Codec> DBson<T, C> {
+    /// Decode a value from bytes previously produced by [`DBson::to_vec`].
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, Error> {
+        codec::decode::<T, C, Self>(bytes)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, C> DBson<T, C> {
+    /// Apply a MongoDB style [update document](https://www.mongodb.com/docs/manual/reference/operator/update/)
+    /// to the value.
+    ///
+    /// Supports `$set`, `$unset`, `$inc`, `$mul`, `$min`, `$max`, `$rename`, `$push` (with
+    /// `$each`, `$position` and `$slice`), `$addToSet` and `$pull` on dotted field paths like
+    /// `"address.city"` or `"tags.0"`. Setting an index past the end of an array pads it with
+    /// nulls, and fails if that takes more than 10 000 of them.
+    /// The updated value is read back as `T`, so an update that doesn't fit the type (like
+    /// setting a number field to a string) fails and leaves the value untouched.
+    /// ```rust
+    /// #[derive(serde::Serialize, serde::Deserialize)]
+    /// struct Counter {
+    ///     hits: u32,
+    ///     pages: Vec<String>,
+    /// }
+    /// let mut data = dbson::DBson::new(Counter { hits: 1, pages: vec![] });
+    /// data.apply_update(&bson::doc! { "$inc": { "hits": 1 }, "$push": { "pages": "/" } })
+    ///     .unwrap();
+    /// assert!(data.apply_update(&bson::doc! { "$set": { "hits": "many" } }).is_err());
+    /// let data = data.into_inner();
+    /// assert_eq!((data.hits, data.pages), (2, vec!["/".to_string()]));
+    /// ```
+    pub fn apply_update(&mut self, update: &bson::Document) -> Result<(), Error> {
+        *self = update::apply_to::<T, Self>(self, stored::Layout::Envelope, update)?;
+        Ok(())
+    }
+}
+
+impl<T, C> From<DBsonDoc<T, C>> for DBson<T, C> {
+    fn from(doc: DBsonDoc<T, C>) -> Self {
+        Self::with_codec(doc.into_inner())
+    }
+}
+
+/// A wrapper type for serializable data stored without an envelope.
+///
+/// Works exactly like [`DBson`] except that the value is written as the top level document
+/// instead of being wrapped in `{"inner": <value>}`.
+/// This makes the stored blobs interchangeable with documents written by other tools, but `T`
+/// needs to serialize as a struct, a map or a sequence for the [`codec::Bson`] codec since a
+/// bare scalar can't be a BSON document.
+/// ```rust
+/// use std::collections::HashMap;
+/// let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// conn.execute("CREATE TABLE test (data BLOB)", []).unwrap();
+/// let data = HashMap::from([("key".to_string(), 1)]);
+/// conn.execute("INSERT INTO test (data) VALUES (?)", [dbson::DBsonDoc::new(&data)]).unwrap();
+/// let doc: bson::Document = conn
+///     .query_row("SELECT data FROM test", [], |row| row.get(0))
+///     .map(|raw: Vec<u8>| bson::from_slice(&raw).unwrap())
+///     .unwrap();
+/// assert_eq!(doc, bson::doc! { "key": 1 });
+/// ```
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+#[cfg_attr(
+    feature = "diesel",
+    derive(diesel::expression::AsExpression, diesel::deserialize::FromSqlRow),
+    diesel(sql_type = diesel::sql_types::Binary)
+)]
+#[repr(transparent)]
+pub struct DBsonDoc<T, C = codec::Bson> {
+    inner: T,
+    #[serde(skip)]
+    codec: PhantomData<C>,
+}
+
+impl<T, C> From<T> for DBsonDoc<T, C> {
+    fn from(inner: T) -> Self {
+        Self::with_codec(inner)
+    }
+}
+
+impl<T> DBsonDoc<T> {
+    pub fn new(inner: T) -> Self {
+        Self::with_codec(inner)
+    }
+}
+
+impl<T, C> DBsonDoc<T, C> {
+    /// Wrap `inner` to be stored using the codec `C`.
+    pub fn with_codec(inner: T) -> Self {
+        Self {
+            inner,
+            codec: PhantomData,
+        }
     }
 
     pub fn into_inner(self) -> T {
@@ -65,92 +246,325 @@ impl<T> DBson<T> {
     }
 }
 
+impl<T: Serialize, C: Codec> DBsonDoc<T, C> {
+    /// Encode the value into the bytes that get stored in the database.
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        codec::encode::<T, C>(self)
+    }
+}
+
+impl<T: DeserializeOwned, C: Codec> DBsonDoc<T, C> {
+    /// Decode a value from bytes previously produced by [`DBsonDoc::to_vec`].
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, Error> {
+        codec::decode::<T, C, Self>(bytes)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, C> DBsonDoc<T, C> {
+    /// Apply a MongoDB style update document to the value, see [`DBson::apply_update`].
+    pub fn apply_update(&mut self, update: &bson::Document) -> Result<(), Error> {
+        *self = update::apply_to::<T, Self>(self, stored::Layout::Bare, update)?;
+        Ok(())
+    }
+}
+
+impl<T, C> From<DBson<T, C>> for DBsonDoc<T, C> {
+    fn from(dbson: DBson<T, C>) -> Self {
+        Self::with_codec(dbson.into_inner())
+    }
+}
+
 #[cfg(feature = "rusqlite")]
 #[cfg_attr(docsrs, doc(cfg(feature = "rusqlite")))]
 mod impl_rusqlite {
+    use crate::codec::{Codec, Format};
     use rusqlite::{types::FromSql, ToSql};
-    impl<T: serde::Serialize> ToSql for super::DBson<T> {
-        fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
-            let bytes = bson::to_vec(&self)
-                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
-            Ok(rusqlite::types::ToSqlOutput::Owned(
-                rusqlite::types::Value::Blob(bytes),
-            ))
-        }
-    }
 
-    impl<T: for<'de> serde::de::Deserialize<'de>> FromSql for super::DBson<T> {
-        fn column_result(
-            value: rusqlite::types::ValueRef<'_>,
-        ) -> rusqlite::types::FromSqlResult<Self> {
-            let bytes = value.as_blob()?;
-            let inner = bson::from_slice(bytes)
-                .map_err(|e| rusqlite::types::FromSqlError::Other(Box::new(e)))?;
-            Ok(inner)
-        }
+    macro_rules! impl_rusqlite {
+        ($($wrapper: ident),*) => {
+            $(
+                impl<T: serde::Serialize, C: Codec> ToSql for crate::$wrapper<T, C> {
+                    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+                        let bytes = self
+                            .to_vec()
+                            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                        let value = match C::FORMAT {
+                            Format::Binary => rusqlite::types::Value::Blob(bytes),
+                            // Stored as TEXT so sqlite's own json functions can read it
+                            Format::Json => rusqlite::types::Value::Text(
+                                String::from_utf8(bytes)
+                                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?,
+                            ),
+                        };
+                        Ok(rusqlite::types::ToSqlOutput::Owned(value))
+                    }
+                }
+
+                impl<T: for<'de> serde::de::Deserialize<'de>, C: Codec> FromSql
+                    for crate::$wrapper<T, C>
+                {
+                    fn column_result(
+                        value: rusqlite::types::ValueRef<'_>,
+                    ) -> rusqlite::types::FromSqlResult<Self> {
+                        let bytes = match C::FORMAT {
+                            Format::Binary => value.as_blob()?,
+                            Format::Json => value.as_bytes()?,
+                        };
+                        Self::from_slice(bytes)
+                            .map_err(|e| rusqlite::types::FromSqlError::Other(Box::new(e)))
+                    }
+                }
+            )*
+        };
     }
+
+    impl_rusqlite!(DBson, DBsonDoc);
 }
 
 #[cfg(feature = "sqlx")]
 #[cfg_attr(docsrs, doc(cfg(feature = "sqlx")))]
 mod impl_sqlx {
-    use super::DBson;
+    //! Codecs producing [`Format::Json`] are bound through [`sqlx::types::Json`], so they end up
+    //! in `jsonb` on postgres, `json` on mysql and `TEXT` on sqlite.
+
+    use crate::codec::{Codec, Format};
     use serde::Serialize;
+    use serde_json::value::RawValue;
     use sqlx::{
-        database::{HasArguments, HasValueRef},
+        database::Database,
         decode::Decode,
         encode::Encode,
-        types::Type,
+        error::BoxDynError,
+        types::{Json, Type},
     };
 
-    impl<'a, T: Serialize + serde::de::DeserializeOwned, DB: sqlx::database::Database> Type<DB>
-        for DBson<T>
-    where
-        &'a [u8]: Type<DB>,
-    {
-        fn type_info() -> DB::TypeInfo {
-            <&[u8] as ::sqlx::types::Type<DB>>::type_info()
-        }
+    fn json(bytes: Vec<u8>) -> Result<Json<Box<RawValue>>, BoxDynError> {
+        Ok(Json(RawValue::from_string(String::from_utf8(bytes)?)?))
     }
 
-    impl<'a, T: Serialize + serde::de::DeserializeOwned, DB: sqlx::database::Database>
-        Encode<'a, DB> for DBson<T>
-    where
-        Vec<u8>: Type<DB>,
-        Vec<u8>: Encode<'a, DB>,
-    {
-        fn encode_by_ref(
-            &self,
-            buf: &mut <DB as HasArguments<'a>>::ArgumentBuffer,
-        ) -> sqlx::encode::IsNull {
-            let Ok(bytes) = bson::to_vec(&self) else {
-                return sqlx::encode::IsNull::Yes;
-            };
-            <Vec<u8> as Encode<'a, DB>>::encode_by_ref(&bytes, buf)
-        }
-        fn encode(
-            self,
-            buf: &mut <DB as HasArguments<'a>>::ArgumentBuffer,
-        ) -> sqlx::encode::IsNull {
-            let Ok(bytes) = bson::to_vec(&self) else {
-                return sqlx::encode::IsNull::Yes;
-            };
-            <Vec<u8> as Encode<'a, DB>>::encode(bytes, buf)
-        }
+    macro_rules! impl_sqlx {
+        ($($wrapper: ident),*) => {
+            $(
+                impl<'a, T, C: Codec, DB: Database> Type<DB> for crate::$wrapper<T, C>
+                where
+                    &'a [u8]: Type<DB>,
+                    Json<Box<RawValue>>: Type<DB>,
+                {
+                    fn type_info() -> DB::TypeInfo {
+                        match C::FORMAT {
+                            Format::Binary => <&[u8] as Type<DB>>::type_info(),
+                            Format::Json => <Json<Box<RawValue>> as Type<DB>>::type_info(),
+                        }
+                    }
+
+                    fn compatible(ty: &DB::TypeInfo) -> bool {
+                        match C::FORMAT {
+                            Format::Binary => *ty == <&[u8] as Type<DB>>::type_info(),
+                            Format::Json => <Json<Box<RawValue>> as Type<DB>>::compatible(ty),
+                        }
+                    }
+                }
+
+                impl<'a, T: Serialize, C: Codec, DB: Database> Encode<'a, DB>
+                    for crate::$wrapper<T, C>
+                where
+                    Vec<u8>: Type<DB>,
+                    Vec<u8>: Encode<'a, DB>,
+                    Json<Box<RawValue>>: Encode<'a, DB>,
+                {
+                    fn encode_by_ref(
+                        &self,
+                        buf: &mut <DB as Database>::ArgumentBuffer<'a>,
+                    ) -> Result<sqlx::encode::IsNull, BoxDynError> {
+                        let bytes = self.to_vec()?;
+                        match C::FORMAT {
+                            Format::Binary => <Vec<u8> as Encode<'a, DB>>::encode(bytes, buf),
+                            Format::Json => {
+                                <Json<Box<RawValue>> as Encode<'a, DB>>::encode(json(bytes)?, buf)
+                            }
+                        }
+                    }
+                }
+
+                impl<'r, T: serde::de::DeserializeOwned, C: Codec, DB: Database>
+                    Decode<'r, DB> for crate::$wrapper<T, C>
+                where
+                    &'r [u8]: Type<DB>,
+                    &'r [u8]: Decode<'r, DB>,
+                    Json<Box<RawValue>>: Decode<'r, DB>,
+                {
+                    fn decode(value: <DB as Database>::ValueRef<'r>) -> Result<Self, BoxDynError> {
+                        match C::FORMAT {
+                            Format::Binary => {
+                                let bytes = <&[u8] as Decode<'r, DB>>::decode(value)?;
+                                Ok(Self::from_slice(bytes)?)
+                            }
+                            Format::Json => {
+                                let Json(raw) = <Json<Box<RawValue>> as Decode<'r, DB>>::decode(value)?;
+                                Ok(Self::from_slice(raw.get().as_bytes())?)
+                            }
+                        }
+                    }
+                }
+            )*
+        };
+    }
+
+    impl_sqlx!(DBson, DBsonDoc);
+}
+
+#[cfg(feature = "diesel")]
+#[cfg_attr(docsrs, doc(cfg(feature = "diesel")))]
+mod impl_diesel {
+    use crate::Codec;
+    use diesel::{
+        backend::Backend,
+        deserialize::{self, FromSql},
+        sql_types::Binary,
+    };
+    use serde::de::DeserializeOwned;
+    #[cfg(any(feature = "diesel-postgres", feature = "diesel-mysql"))]
+    use std::io::Write;
+    #[cfg(any(
+        feature = "diesel-sqlite",
+        feature = "diesel-postgres",
+        feature = "diesel-mysql"
+    ))]
+    use {
+        diesel::serialize::{self, IsNull, Output, ToSql},
+        serde::Serialize,
+        std::fmt::Debug,
+    };
+
+    macro_rules! impl_diesel {
+        ($($wrapper: ident),*) => {
+            $(
+                impl<T: DeserializeOwned, C: Codec, DB: Backend> FromSql<Binary, DB>
+                    for crate::$wrapper<T, C>
+                where
+                    Vec<u8>: FromSql<Binary, DB>,
+                {
+                    fn from_sql(bytes: DB::RawValue<'_>) -> deserialize::Result<Self> {
+                        let bytes = <Vec<u8> as FromSql<Binary, DB>>::from_sql(bytes)?;
+                        Ok(Self::from_slice(&bytes)?)
+                    }
+                }
+
+                #[cfg(feature = "diesel-postgres")]
+                impl<T: Serialize + Debug, C: Codec + Debug> ToSql<Binary, diesel::pg::Pg>
+                    for crate::$wrapper<T, C>
+                {
+                    fn to_sql<'b>(
+                        &'b self,
+                        out: &mut Output<'b, '_, diesel::pg::Pg>,
+                    ) -> serialize::Result {
+                        out.write_all(&self.to_vec()?)?;
+                        Ok(IsNull::No)
+                    }
+                }
+
+                #[cfg(feature = "diesel-mysql")]
+                impl<T: Serialize + Debug, C: Codec + Debug> ToSql<Binary, diesel::mysql::Mysql>
+                    for crate::$wrapper<T, C>
+                {
+                    fn to_sql<'b>(
+                        &'b self,
+                        out: &mut Output<'b, '_, diesel::mysql::Mysql>,
+                    ) -> serialize::Result {
+                        out.write_all(&self.to_vec()?)?;
+                        Ok(IsNull::No)
+                    }
+                }
+
+                #[cfg(feature = "diesel-sqlite")]
+                impl<T: Serialize + Debug, C: Codec + Debug> ToSql<Binary, diesel::sqlite::Sqlite>
+                    for crate::$wrapper<T, C>
+                {
+                    fn to_sql<'b>(
+                        &'b self,
+                        out: &mut Output<'b, '_, diesel::sqlite::Sqlite>,
+                    ) -> serialize::Result {
+                        out.set_value(self.to_vec()?);
+                        Ok(IsNull::No)
+                    }
+                }
+            )*
+        };
     }
 
-    impl<'r, T: Serialize + serde::de::DeserializeOwned, DB: sqlx::database::Database>
-        Decode<'r, DB> for DBson<T>
-    where
-        &'r [u8]: Type<DB>,
-        &'r [u8]: Decode<'r, DB>,
-    {
-        fn decode(
-            value: <DB as HasValueRef<'r>>::ValueRef,
-        ) -> Result<Self, Box<dyn std::error::Error + Send + Sync + 'static>> {
-            let bytes = <&[u8] as Decode<'r, DB>>::decode(value)?;
-            let inner = bson::from_slice(&bytes)?;
-            Ok(inner)
+    impl_diesel!(DBson, DBsonDoc);
+}
+
+#[cfg(feature = "postgres-types")]
+#[cfg_attr(docsrs, doc(cfg(feature = "postgres-types")))]
+mod impl_postgres_types {
+    //! `bytea` columns hold the encoded bytes as they are.
+    //! Codecs producing [`Format::Json`] can also be used with `json` and `jsonb` columns.
+
+    use crate::codec::{Codec, Format};
+    use bytes::{BufMut, BytesMut};
+    use postgres_types::{FromSql, IsNull, ToSql, Type};
+    use serde::{de::DeserializeOwned, Serialize};
+    use std::{error::Error, fmt::Debug};
+
+    /// Version of the binary `jsonb` representation.
+    const JSONB_VERSION: u8 = 1;
+
+    fn accepts<C: Codec>(ty: &Type) -> bool {
+        match C::FORMAT {
+            Format::Binary => *ty == Type::BYTEA,
+            Format::Json => matches!(*ty, Type::BYTEA | Type::JSON | Type::JSONB),
         }
     }
+
+    macro_rules! impl_postgres_types {
+        ($($wrapper: ident),*) => {
+            $(
+                impl<T: Serialize + Debug, C: Codec + Debug> ToSql for crate::$wrapper<T, C> {
+                    fn to_sql(
+                        &self,
+                        ty: &Type,
+                        out: &mut BytesMut,
+                    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+                        let bytes = self.to_vec()?;
+                        if *ty == Type::JSONB {
+                            out.put_u8(JSONB_VERSION);
+                        }
+                        out.put_slice(&bytes);
+                        Ok(IsNull::No)
+                    }
+
+                    fn accepts(ty: &Type) -> bool {
+                        accepts::<C>(ty)
+                    }
+
+                    postgres_types::to_sql_checked!();
+                }
+
+                impl<'a, T: DeserializeOwned, C: Codec> FromSql<'a> for crate::$wrapper<T, C> {
+                    fn from_sql(
+                        ty: &Type,
+                        mut raw: &'a [u8],
+                    ) -> Result<Self, Box<dyn Error + Sync + Send>> {
+                        if *ty == Type::JSONB {
+                            let [version, rest @ ..] = raw else {
+                                return Err("empty jsonb value".into());
+                            };
+                            if *version != JSONB_VERSION {
+                                return Err(format!("unsupported jsonb version {version}").into());
+                            }
+                            raw = rest;
+                        }
+                        Ok(Self::from_slice(raw)?)
+                    }
+
+                    fn accepts(ty: &Type) -> bool {
+                        accepts::<C>(ty)
+                    }
+                }
+            )*
+        };
+    }
+
+    impl_postgres_types!(DBson, DBsonDoc);
 }
@@ -0,0 +1,134 @@
+//! The order MongoDB sorts and compares BSON values in.
+//!
+//! Values of different types compare by [`type_rank`] (`MinKey < null < numbers < strings <
+//! objects < arrays < binary < ObjectId < bool < date < timestamp < regex < MaxKey`), numbers
+//! compare by value whatever their type and documents and arrays compare element by element.
+
+use bson::Bson;
+use std::cmp::Ordering;
+
+/// Position of the type of `value` in the comparison order.
+pub(crate) fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::MinKey => 0,
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Double(_) | Bson::Int32(_) | Bson::Int64(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        Bson::RegularExpression(_) => 11,
+        Bson::DbPointer(_) => 12,
+        Bson::JavaScriptCode(_) => 13,
+        Bson::JavaScriptCodeWithScope(_) => 14,
+        Bson::MaxKey => 15,
+    }
+}
+
+/// A number of any of the BSON numeric types.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Number {
+    Int(i64),
+    Double(f64),
+}
+
+impl Number {
+    pub(crate) fn new(value: &Bson) -> Option<Self> {
+        match value {
+            Bson::Int32(i) => Some(Self::Int((*i).into())),
+            Bson::Int64(i) => Some(Self::Int(*i)),
+            Bson::Double(f) => Some(Self::Double(*f)),
+            Bson::Decimal128(d) => d.to_string().parse().ok().map(Self::Double),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_f64(self) -> f64 {
+        match self {
+            Self::Int(i) => i as f64,
+            Self::Double(f) => f,
+        }
+    }
+
+    /// Compare the numbers, with `NaN` below every other number.
+    pub(crate) fn compare(self, other: Self) -> Ordering {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => a.cmp(&b),
+            (a, b) => {
+                let (a, b) = (a.as_f64(), b.as_f64());
+                a.partial_cmp(&b)
+                    .unwrap_or_else(|| a.is_nan().cmp(&b.is_nan()).reverse())
+            }
+        }
+    }
+}
+
+/// Compare two values in MongoDB's order.
+pub(crate) fn compare(a: &Bson, b: &Bson) -> Ordering {
+    let by_type = type_rank(a).cmp(&type_rank(b));
+    if by_type != Ordering::Equal {
+        return by_type;
+    }
+    match (a, b) {
+        (Bson::String(a) | Bson::Symbol(a), Bson::String(b) | Bson::Symbol(b)) => a.cmp(b),
+        (Bson::Document(a), Bson::Document(b)) => {
+            let mut b = b.iter();
+            for (key, a) in a {
+                let Some((other_key, b)) = b.next() else {
+                    return Ordering::Greater;
+                };
+                let ordering = type_rank(a)
+                    .cmp(&type_rank(b))
+                    .then_with(|| key.cmp(other_key))
+                    .then_with(|| compare(a, b));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            match b.next() {
+                Some(_) => Ordering::Less,
+                None => Ordering::Equal,
+            }
+        }
+        (Bson::Array(a), Bson::Array(b)) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| compare(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Bson::Binary(a), Bson::Binary(b)) => a
+            .bytes
+            .len()
+            .cmp(&b.bytes.len())
+            .then_with(|| u8::from(a.subtype).cmp(&u8::from(b.subtype)))
+            .then_with(|| a.bytes.cmp(&b.bytes)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => a.bytes().cmp(&b.bytes()),
+        (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+        (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+        (Bson::Timestamp(a), Bson::Timestamp(b)) => {
+            (a.time, a.increment).cmp(&(b.time, b.increment))
+        }
+        (Bson::RegularExpression(a), Bson::RegularExpression(b)) => {
+            (&a.pattern, &a.options).cmp(&(&b.pattern, &b.options))
+        }
+        (Bson::DbPointer(_), Bson::DbPointer(_)) => Ordering::Equal,
+        (Bson::JavaScriptCode(a), Bson::JavaScriptCode(b)) => a.cmp(b),
+        (Bson::JavaScriptCodeWithScope(a), Bson::JavaScriptCodeWithScope(b)) => {
+            a.code.cmp(&b.code).then_with(|| {
+                compare(
+                    &Bson::Document(a.scope.clone()),
+                    &Bson::Document(b.scope.clone()),
+                )
+            })
+        }
+        (a, b) => match (Number::new(a), Number::new(b)) {
+            (Some(a), Some(b)) => a.compare(b),
+            // MinKey, MaxKey and null / undefined are all equal to their own kind
+            _ => Ordering::Equal,
+        },
+    }
+}
@@ -0,0 +1,199 @@
+//! Paths into stored documents, in the same syntax as sqlite's json functions.
+//!
+//! A path starts at `$` (the wrapped value) and is followed by any number of
+//! `.key`, `."quoted key"`, `[index]` or `[#-offset]` (counting from the end) segments,
+//! e.g. `$.users[0].name` or `$.tags[#-1]`.
+//! Indexing a document with a number looks up the key of that number, which is how sequences
+//! stored at the top level of a bare document are laid out.
+
+use bson::{Bson, Document};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Segment {
+    Key(String),
+    Index(usize),
+    /// `[#-n]`, `n` elements before the end. `[#]` is `FromEnd(0)`, one past the last element.
+    FromEnd(usize),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Path(pub(crate) Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PathError(pub(crate) String);
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed path `{}`", self.0)
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl FromStr for Path {
+    type Err = PathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || PathError(s.to_string());
+        let mut rest = s.strip_prefix('$').ok_or_else(error)?;
+        let mut segments = Vec::new();
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '.' if rest[1..].starts_with('"') => {
+                    let end = rest[2..].find('"').ok_or_else(error)? + 2;
+                    segments.push(Segment::Key(rest[2..end].to_string()));
+                    rest = &rest[end + 1..];
+                }
+                '.' => {
+                    let end = rest[1..].find(['.', '[']).map_or(rest.len(), |end| end + 1);
+                    if end == 1 {
+                        return Err(error());
+                    }
+                    segments.push(Segment::Key(rest[1..end].to_string()));
+                    rest = &rest[end..];
+                }
+                '[' => {
+                    let end = rest.find(']').ok_or_else(error)?;
+                    let index = &rest[1..end];
+                    segments.push(if index == "#" {
+                        Segment::FromEnd(0)
+                    } else if let Some(offset) = index.strip_prefix("#-") {
+                        Segment::FromEnd(offset.parse().map_err(|_| error())?)
+                    } else {
+                        Segment::Index(index.parse().map_err(|_| error())?)
+                    });
+                    rest = &rest[end + 1..];
+                }
+                _ => return Err(error()),
+            }
+        }
+        Ok(Self(segments))
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("$")?;
+        for segment in &self.0 {
+            match segment {
+                Segment::Key(key) if key.contains(['.', '[', '"']) || key.is_empty() => {
+                    write!(f, ".\"{key}\"")?
+                }
+                Segment::Key(key) => write!(f, ".{key}")?,
+                Segment::Index(index) => write!(f, "[{index}]")?,
+                Segment::FromEnd(0) => f.write_str("[#]")?,
+                Segment::FromEnd(offset) => write!(f, "[#-{offset}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Segment {
+    fn array_index(&self, len: usize) -> Option<usize> {
+        match self {
+            Self::Index(index) => Some(*index),
+            Self::FromEnd(offset) => len.checked_sub(*offset),
+            Self::Key(key) => key.parse().ok(),
+        }
+    }
+
+    fn document_key(&self, doc: &Document) -> Option<String> {
+        match self {
+            Self::Key(key) => Some(key.clone()),
+            Self::Index(index) => Some(index.to_string()),
+            Self::FromEnd(offset) => doc.len().checked_sub(*offset).map(|i| i.to_string()),
+        }
+    }
+
+    pub(crate) fn get<'a>(&self, value: &'a Bson) -> Option<&'a Bson> {
+        match value {
+            Bson::Document(doc) => doc.get(self.document_key(doc)?),
+            Bson::Array(array) => array.get(self.array_index(array.len())?),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_mut<'a>(&self, value: &'a mut Bson) -> Option<&'a mut Bson> {
+        match value {
+            Bson::Document(doc) => {
+                let key = self.document_key(doc)?;
+                doc.get_mut(key)
+            }
+            Bson::Array(array) => {
+                let index = self.array_index(array.len())?;
+                array.get_mut(index)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Path {
+    pub(crate) fn get<'a>(&self, value: &'a Bson) -> Option<&'a Bson> {
+        self.0
+            .iter()
+            .try_fold(value, |value, segment| segment.get(value))
+    }
+
+    /// The value holding the last segment, along with that segment.
+    fn parent_mut<'a>(&self, value: &'a mut Bson) -> Option<(&'a mut Bson, &Segment)> {
+        let (last, parent) = self.0.split_last()?;
+        let parent = parent
+            .iter()
+            .try_fold(value, |value, segment| segment.get_mut(value))?;
+        Some((parent, last))
+    }
+
+    /// Write `new` at this path, like sqlite's `json_set` (or `json_insert` without `overwrite`).
+    ///
+    /// Only the last segment may be missing: a missing key is added to its document and an index
+    /// one past the end (e.g. `[#]`) appends to its array. Returns whether anything was written.
+    pub(crate) fn set(&self, value: &mut Bson, new: Bson, overwrite: bool) -> bool {
+        if self.0.is_empty() {
+            if overwrite {
+                *value = new;
+            }
+            return overwrite;
+        }
+        let Some((parent, last)) = self.parent_mut(value) else {
+            return false;
+        };
+        match parent {
+            Bson::Document(doc) => match last.document_key(doc) {
+                Some(key) if overwrite || !doc.contains_key(&key) => {
+                    doc.insert(key, new);
+                    true
+                }
+                _ => false,
+            },
+            Bson::Array(array) => match last.array_index(array.len()) {
+                Some(index) if index == array.len() => {
+                    array.push(new);
+                    true
+                }
+                Some(index) if overwrite && index < array.len() => {
+                    array[index] = new;
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Take the value at this path out of its document or array.
+    pub(crate) fn remove(&self, value: &mut Bson) -> Option<Bson> {
+        let (parent, last) = self.parent_mut(value)?;
+        match parent {
+            Bson::Document(doc) => doc.remove(last.document_key(doc)?),
+            Bson::Array(array) => {
+                let index = last.array_index(array.len())?;
+                (index < array.len()).then(|| array.remove(index))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,472 @@
+//! SQL functions to look inside of BSON blobs from sqlite.
+//!
+//! The functions and [`Collection`] need the `rusqlite-functions` feature, which turns on the
+//! `functions`, `vtab` and `collation` features of rusqlite. The `rusqlite` feature alone only
+//! reads and writes the wrapper types.
+//!
+//! [`register_functions`] installs the following functions on a connection:
+//!
+//! | function | returns |
+//! |---|---|
+//! | `bson_extract(blob, path)` | the value at `path` |
+//! | `bson_type(blob[, path])` | the [MongoDB type name](https://www.mongodb.com/docs/manual/reference/operator/query/type/) of the value, e.g. `'int'` or `'object'` |
+//! | `bson_valid(blob)` | `1` if `blob` is a single well formed BSON document, `0` otherwise |
+//! | `bson_keys(blob[, path])` | the keys of the document at `path`, as an array |
+//! | `bson_to_json(blob[, path])` | the value as [relaxed extended json](https://www.mongodb.com/docs/manual/reference/mongodb-extended-json/) text |
+//!
+//! The `bson_object(key, value, ...)` and `bson_array(value, ...)` functions and the
+//! `bson_group_array(value)` and `bson_group_object(key, value)` aggregates build new blobs out
+//! of sqlite values, which can be read back as a [`DBson`](crate::DBson) of the matching type.
+//! Blobs holding a bson document are nested as the value they store, so these can be combined
+//! with each other and with existing `DBson` columns.
+//! ```rust
+//! # use std::collections::HashMap;
+//! let conn = rusqlite::Connection::open_in_memory().unwrap();
+//! dbson::rusqlite::register_functions(&conn).unwrap();
+//! let data: dbson::DBson<HashMap<String, Vec<i64>>> = conn
+//!     .query_row(
+//!         "SELECT bson_group_object(key, bson_array(value, value * 2))
+//!          FROM (SELECT 'a' AS key, 1 AS value UNION ALL SELECT 'b', 2)",
+//!         [],
+//!         |row| row.get(0),
+//!     )
+//!     .unwrap();
+//! assert_eq!(data.into_inner()["b"], vec![2, 4]);
+//! ```
+//!
+//! `bson_set(blob, path, value, ...)`, `bson_insert(blob, path, value, ...)`,
+//! `bson_remove(blob, path, ...)` and `bson_patch(blob, patch)` return a modified copy of `blob`,
+//! like sqlite's `json_set`, `json_insert`, `json_remove` and `json_patch`. `bson_insert` doesn't
+//! overwrite existing values, `bson_set` does, and both can only add the last segment of a path
+//! (a missing key, or `[#]` to append to an array). The blob keeps its layout, so a
+//! [`DBson`](crate::DBson) column stays readable as one.
+//! ```rust
+//! let conn = rusqlite::Connection::open_in_memory().unwrap();
+//! dbson::rusqlite::register_functions(&conn).unwrap();
+//! let data = dbson::DBson::new(vec![1, 2, 3]);
+//! let data: dbson::DBson<Vec<i32>> = conn
+//!     .query_row(
+//!         "SELECT bson_remove(bson_set(?1, '$[0]', 10, '$[#]', 4), '$[1]')",
+//!         [data],
+//!         |row| row.get(0),
+//!     )
+//!     .unwrap();
+//! assert_eq!(data.into_inner(), vec![10, 3, 4]);
+//! ```
+//!
+//! `bson_update(blob, update)` applies a MongoDB style update document, like
+//! [`DBson::apply_update`](crate::DBson::apply_update) does. Since sqlite doesn't know the
+//! wrapped type, the result is only checked when it's read back.
+//! ```rust
+//! let conn = rusqlite::Connection::open_in_memory().unwrap();
+//! dbson::rusqlite::register_functions(&conn).unwrap();
+//! let update = dbson::DBsonDoc::new(bson::doc! { "$inc": { "hits": 1 } });
+//! let hits: i64 = conn
+//!     .query_row(
+//!         "SELECT bson_extract(bson_update(bson_object('hits', 41), ?1), '$.hits')",
+//!         [update],
+//!         |row| row.get(0),
+//!     )
+//!     .unwrap();
+//! assert_eq!(hits, 42);
+//! ```
+//!
+//! `bson_match(blob, filter)` tells whether the value matches a MongoDB style
+//! [`Filter`](crate::filter::Filter), which makes the same filters usable in sql and in Rust.
+//! ```rust
+//! let conn = rusqlite::Connection::open_in_memory().unwrap();
+//! dbson::rusqlite::register_functions(&conn).unwrap();
+//! let filter = dbson::DBsonDoc::new(bson::doc! { "age": { "$gte": 18 } });
+//! let adult: bool = conn
+//!     .query_row("SELECT bson_match(bson_object('age', 21), ?1)", [filter], |row| row.get(0))
+//!     .unwrap();
+//! assert!(adult);
+//! ```
+//!
+//! `bson_compare(a, b)` returns `-1`, `0` or `1` as `a` sorts before, with or after `b` in
+//! [MongoDB's comparison order](https://www.mongodb.com/docs/manual/reference/bson-type-comparison-order/)
+//! (`null < numbers < strings < documents < arrays < binary < ObjectId < bool < date ...`),
+//! where sqlite would order numbers, then text and then blobs. Its arguments are converted like
+//! the values of `bson_array`. The `bson` collation orders the [relaxed extended
+//! json](https://www.mongodb.com/docs/manual/reference/mongodb-extended-json/) text of
+//! `bson_to_json` the same way (text that isn't json compares as a string), which makes
+//! `ORDER BY` and range conditions follow MongoDB:
+//! ```rust
+//! let conn = rusqlite::Connection::open_in_memory().unwrap();
+//! dbson::rusqlite::register_functions(&conn).unwrap();
+//! let data = bson::bson!(["b", 2, [1], null, 1.5]);
+//! let data = dbson::DBson::new(data.as_array().unwrap());
+//! let sorted: Vec<String> = conn
+//!     .prepare(
+//!         "SELECT bson_to_json(?1, fullkey) AS json FROM bson_each(?1)
+//!          WHERE json > '1' COLLATE bson ORDER BY json COLLATE bson",
+//!     )
+//!     .unwrap()
+//!     .query_map([data], |row| row.get(0))
+//!     .unwrap()
+//!     .collect::<Result<_, _>>()
+//!     .unwrap();
+//! assert_eq!(sorted, vec!["1.5", "2", "\"b\"", "[1]"]);
+//! ```
+//!
+//! Finally the `bson_each(blob[, path])` and `bson_tree(blob[, path])` table-valued functions,
+//! which work like sqlite's [`json_each` and `json_tree`](https://www.sqlite.org/json1.html#jeach):
+//! `bson_each` has a row for every element of the document or array at `path` while `bson_tree`
+//! walks everything below it. Both have the columns `key`, `value`, `type`, `atom`, `id`,
+//! `parent`, `fullkey` and `path`.
+//! ```rust
+//! let conn = rusqlite::Connection::open_in_memory().unwrap();
+//! dbson::rusqlite::register_functions(&conn).unwrap();
+//! let data = dbson::DBson::new(vec![("a", 1), ("b", 2)]);
+//! let keys: Vec<String> = conn
+//!     .prepare("SELECT bson_extract(value, '$[0]') FROM bson_each(?1) WHERE bson_extract(value, '$[1]') > 1")
+//!     .unwrap()
+//!     .query_map([data], |row| row.get(0))
+//!     .unwrap()
+//!     .collect::<Result<_, _>>()
+//!     .unwrap();
+//! assert_eq!(keys, vec!["b"]);
+//! ```
+//!
+//! On top of these, [`Collection`] manages a whole table of [`DBson`](crate::DBson) values with
+//! a MongoDB like API (`insert_one`, `find`, `update_many`, ...).
+//!
+//! Paths use the syntax of sqlite's json functions (`$.a.b[0]`, `$.list[#-1]`) and start at the
+//! wrapped value, so the `inner` envelope written by [`DBson`](crate::DBson) is skipped and
+//! `$.name` works the same for [`DBson`](crate::DBson) and [`DBsonDoc`](crate::DBsonDoc) columns.
+//!
+//! Scalars are returned as the closest sqlite type: numbers as `INTEGER` or `REAL`, booleans as
+//! `0` / `1`, strings as `TEXT`, binary as `BLOB`, object ids as hex `TEXT` and dates as RFC 3339
+//! `TEXT`. Documents and arrays are returned as blobs which can be read back as a
+//! [`DBson`](crate::DBson) of the matching type. A path that doesn't exist returns `NULL`.
+//! ```rust
+//! let conn = rusqlite::Connection::open_in_memory().unwrap();
+//! dbson::rusqlite::register_functions(&conn).unwrap();
+//! let data = dbson::DBson::new(vec![vec![1, 2], vec![3, 4]]);
+//! let (last, rest): (i64, dbson::DBson<Vec<i32>>) = conn
+//!     .query_row(
+//!         "SELECT bson_extract(?1, '$[1][#-1]'), bson_extract(?1, '$[0]')",
+//!         [data],
+//!         |row| Ok((row.get(0)?, row.get(1)?)),
+//!     )
+//!     .unwrap();
+//! assert_eq!(last, 4);
+//! assert_eq!(rest.into_inner(), vec![1, 2]);
+//! ```
+
+#[cfg(feature = "rusqlite-functions")]
+use crate::{
+    filter::Filter,
+    order,
+    path::Path,
+    stored::{self, Layout, Stored},
+};
+#[cfg(feature = "rusqlite-functions")]
+use ::rusqlite::{
+    functions::{Context, FunctionFlags},
+    types::{Value, ValueRef},
+    Connection, Error, Result,
+};
+#[cfg(feature = "rusqlite-functions")]
+use bson::Bson;
+
+#[cfg(feature = "rusqlite-functions")]
+mod aggregate;
+#[cfg(feature = "rusqlite-functions")]
+mod collection;
+#[cfg(feature = "zstd")]
+mod dictionary;
+#[cfg(feature = "rusqlite-functions")]
+mod each;
+#[cfg(feature = "encryption")]
+mod reencryption;
+
+#[cfg(feature = "rusqlite-functions")]
+#[cfg_attr(docsrs, doc(cfg(feature = "rusqlite-functions")))]
+pub use collection::{Collection, CollectionId, Cursor};
+#[cfg(feature = "zstd")]
+#[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+pub use dictionary::{load_dictionaries, train_dictionary};
+#[cfg(feature = "encryption")]
+#[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+pub use reencryption::reencrypt;
+
+/// Install the `bson_*` functions, table-valued functions and collation on `conn`.
+#[cfg(feature = "rusqlite-functions")]
+#[cfg_attr(docsrs, doc(cfg(feature = "rusqlite-functions")))]
+pub fn register_functions(conn: &Connection) -> Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_scalar_function("bson_valid", 1, flags, |ctx| {
+        Ok(match ctx.get_raw(0) {
+            ValueRef::Null => None,
+            ValueRef::Blob(bytes) => Some(Stored::document(bytes).is_ok()),
+            _ => Some(false),
+        })
+    })?;
+    conn.create_scalar_function("bson_extract", 2, flags, |ctx| with_value(ctx, to_sql))?;
+    for n_arg in [1, 2] {
+        conn.create_scalar_function("bson_type", n_arg, flags, |ctx| {
+            with_value(ctx, |value| {
+                Ok(Value::Text(stored::type_name(value).into()))
+            })
+        })?;
+        conn.create_scalar_function("bson_keys", n_arg, flags, |ctx| {
+            with_value(ctx, |value| match value {
+                Bson::Document(doc) => {
+                    let keys = doc.keys().cloned().map(Bson::String).collect();
+                    to_blob(Bson::Array(keys))
+                }
+                _ => Ok(Value::Null),
+            })
+        })?;
+        conn.create_scalar_function("bson_to_json", n_arg, flags, |ctx| {
+            with_value(ctx, |value| {
+                Ok(Value::Text(
+                    value.clone().into_relaxed_extjson().to_string(),
+                ))
+            })
+        })?;
+    }
+    conn.create_scalar_function("bson_object", -1, flags, |ctx| {
+        if ctx.len() % 2 != 0 {
+            return Err(user_error("bson_object takes an even number of arguments"));
+        }
+        let mut doc = bson::Document::new();
+        for i in (0..ctx.len()).step_by(2) {
+            doc.insert(key_arg(ctx.get_raw(i))?, from_sql(ctx.get_raw(i + 1))?);
+        }
+        to_blob(Bson::Document(doc))
+    })?;
+    conn.create_scalar_function("bson_array", -1, flags, |ctx| {
+        let array = (0..ctx.len())
+            .map(|i| from_sql(ctx.get_raw(i)))
+            .collect::<Result<_>>()?;
+        to_blob(Bson::Array(array))
+    })?;
+    conn.create_scalar_function("bson_set", -1, flags, |ctx| set_pairs(ctx, true))?;
+    conn.create_scalar_function("bson_insert", -1, flags, |ctx| set_pairs(ctx, false))?;
+    conn.create_scalar_function("bson_remove", -1, flags, |ctx| {
+        let Some(mut stored) = stored_arg(ctx.get_raw(0))? else {
+            return Ok(Value::Null);
+        };
+        for i in 1..ctx.len() {
+            let path = path_arg(ctx.get_raw(i))?;
+            if path.0.is_empty() {
+                return Ok(Value::Null);
+            }
+            path.remove(&mut stored.value);
+        }
+        write(&stored)
+    })?;
+    conn.create_scalar_function("bson_patch", 2, flags, |ctx| {
+        let (Some(mut stored), Some(patch)) =
+            (stored_arg(ctx.get_raw(0))?, stored_arg(ctx.get_raw(1))?)
+        else {
+            return Ok(Value::Null);
+        };
+        merge_patch(&mut stored.value, patch.value);
+        write(&stored)
+    })?;
+    conn.create_scalar_function("bson_update", 2, flags, |ctx| {
+        let (Some(mut stored), Some(update)) =
+            (stored_arg(ctx.get_raw(0))?, stored_arg(ctx.get_raw(1))?)
+        else {
+            return Ok(Value::Null);
+        };
+        let Bson::Document(update) = update.value else {
+            return Err(user_error("the update has to be a document"));
+        };
+        crate::update::apply(&mut stored.value, &update).map_err(|e| match &e.path {
+            Some(path) => user_error(format!("{e} at `{path}`")),
+            None => user_error(e),
+        })?;
+        write(&stored)
+    })?;
+    conn.create_scalar_function("bson_match", 2, flags, |ctx| {
+        // the filter is usually the same for every row, so it's only parsed once per statement
+        let filter = ctx.get_or_create_aux(1, |arg| -> Result<Filter> {
+            match stored_arg(arg)?.map(|filter| filter.value) {
+                Some(Bson::Document(filter)) => Filter::new(&filter).map_err(user_error),
+                _ => Err(user_error("the filter has to be a document")),
+            }
+        })?;
+        Ok(stored_arg(ctx.get_raw(0))?.map(|stored| filter.matches_value(&stored.value)))
+    })?;
+    conn.create_scalar_function("bson_compare", 2, flags, |ctx| {
+        let ordering = order::compare(&from_sql(ctx.get_raw(0))?, &from_sql(ctx.get_raw(1))?);
+        Ok(ordering as i64)
+    })?;
+    conn.create_collation("bson", |a, b| order::compare(&from_json(a), &from_json(b)))?;
+    conn.create_aggregate_function("bson_group_array", 1, flags, aggregate::GroupArray)?;
+    conn.create_aggregate_function("bson_group_object", 2, flags, aggregate::GroupObject)?;
+    each::load_module(conn)
+}
+
+#[cfg(feature = "rusqlite-functions")]
+pub(crate) fn user_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::UserFunctionError(e.into())
+}
+
+/// Read a stored value from an argument, `None` for `NULL`.
+#[cfg(feature = "rusqlite-functions")]
+pub(crate) fn stored_arg(arg: ValueRef<'_>) -> Result<Option<Stored>> {
+    match arg {
+        ValueRef::Null => Ok(None),
+        ValueRef::Blob(bytes) => Stored::from_slice(bytes).map(Some).map_err(user_error),
+        value => Err(user_error(format!(
+            "expected a bson BLOB, found {}",
+            value.data_type()
+        ))),
+    }
+}
+
+#[cfg(feature = "rusqlite-functions")]
+pub(crate) fn key_arg(arg: ValueRef<'_>) -> Result<String> {
+    match arg {
+        ValueRef::Text(_) => Ok(arg.as_str().map_err(user_error)?.to_string()),
+        value => Err(user_error(format!(
+            "expected a TEXT key, found {}",
+            value.data_type()
+        ))),
+    }
+}
+
+#[cfg(feature = "rusqlite-functions")]
+pub(crate) fn path_arg(arg: ValueRef<'_>) -> Result<Path> {
+    arg.as_str()
+        .map_err(|e| user_error(format!("expected a path: {e}")))?
+        .parse()
+        .map_err(user_error)
+}
+
+/// Run `f` on the value at the optional path argument, `NULL` if there is no such value.
+#[cfg(feature = "rusqlite-functions")]
+fn with_value(ctx: &Context<'_>, f: impl FnOnce(&Bson) -> Result<Value>) -> Result<Value> {
+    let Some(stored) = stored_arg(ctx.get_raw(0))? else {
+        return Ok(Value::Null);
+    };
+    let path = match ctx.len() {
+        1 => Path::default(),
+        _ => path_arg(ctx.get_raw(1))?,
+    };
+    match path.get(&stored.value) {
+        Some(value) => f(value),
+        None => Ok(Value::Null),
+    }
+}
+
+/// `bson_set` and `bson_insert`, a blob followed by any number of path and value pairs.
+#[cfg(feature = "rusqlite-functions")]
+fn set_pairs(ctx: &Context<'_>, overwrite: bool) -> Result<Value> {
+    if ctx.len() % 2 != 1 {
+        return Err(user_error(
+            "expected a blob followed by path and value pairs",
+        ));
+    }
+    let Some(mut stored) = stored_arg(ctx.get_raw(0))? else {
+        return Ok(Value::Null);
+    };
+    for i in (1..ctx.len()).step_by(2) {
+        let path = path_arg(ctx.get_raw(i))?;
+        path.set(&mut stored.value, from_sql(ctx.get_raw(i + 1))?, overwrite);
+    }
+    write(&stored)
+}
+
+/// Apply `patch` to `target` the way sqlite's `json_patch` does
+/// ([RFC 7396](https://www.rfc-editor.org/rfc/rfc7396)): documents are merged key by key, a
+/// `null` removes the key and anything else replaces the value.
+#[cfg(feature = "rusqlite-functions")]
+fn merge_patch(target: &mut Bson, patch: Bson) {
+    let Bson::Document(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !matches!(target, Bson::Document(_)) {
+        *target = Bson::Document(bson::Document::new());
+    }
+    let Bson::Document(doc) = target else {
+        unreachable!()
+    };
+    for (key, value) in patch {
+        match value {
+            Bson::Null => {
+                doc.remove(&key);
+            }
+            value => merge_patch(doc.entry(key).or_insert(Bson::Null), value),
+        }
+    }
+}
+
+/// The value of relaxed or canonical extended json text, or the text itself as a string.
+#[cfg(feature = "rusqlite-functions")]
+fn from_json(text: &str) -> Bson {
+    serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .and_then(|json| Bson::try_from(json).ok())
+        .unwrap_or_else(|| Bson::String(text.to_string()))
+}
+
+/// Write `stored` back in the layout it was read in.
+#[cfg(feature = "rusqlite-functions")]
+fn write(stored: &Stored) -> Result<Value> {
+    stored.to_vec().map(Value::Blob).map_err(user_error)
+}
+
+/// A document or array, written the way [`DBson`](crate::DBson) would write it.
+#[cfg(feature = "rusqlite-functions")]
+pub(crate) fn to_blob(value: Bson) -> Result<Value> {
+    Stored::new(value, Layout::Envelope)
+        .to_vec()
+        .map(Value::Blob)
+        .map_err(user_error)
+}
+
+/// The closest sqlite value to `value`.
+#[cfg(feature = "rusqlite-functions")]
+pub(crate) fn to_sql(value: &Bson) -> Result<Value> {
+    Ok(match value {
+        Bson::Null | Bson::Undefined => Value::Null,
+        Bson::Double(f) => Value::Real(*f),
+        Bson::Int32(i) => Value::Integer((*i).into()),
+        Bson::Int64(i) => Value::Integer(*i),
+        Bson::Boolean(b) => Value::Integer((*b).into()),
+        Bson::String(s) => Value::Text(s.clone()),
+        Bson::ObjectId(oid) => Value::Text(oid.to_hex()),
+        Bson::DateTime(dt) => Value::Text(dt.try_to_rfc3339_string().map_err(user_error)?),
+        Bson::Binary(binary) => Value::Blob(binary.bytes.clone()),
+        Bson::Document(_) | Bson::Array(_) => to_blob(value.clone())?,
+        other => Value::Text(other.clone().into_relaxed_extjson().to_string()),
+    })
+}
+
+/// The bson value for a sqlite value.
+///
+/// Blobs holding a bson document (like the ones returned by the other `bson_*` functions or
+/// written by [`DBson`](crate::DBson)) are nested as the value they store, other blobs become
+/// binary data. Integers are stored as `int` when they fit and as `long` otherwise.
+#[cfg(feature = "rusqlite-functions")]
+pub(crate) fn from_sql(value: ValueRef<'_>) -> Result<Bson> {
+    Ok(match value {
+        ValueRef::Null => Bson::Null,
+        ValueRef::Integer(i) => i32::try_from(i).map_or(Bson::Int64(i), Bson::Int32),
+        ValueRef::Real(f) => Bson::Double(f),
+        ValueRef::Text(_) => Bson::String(value.as_str().map_err(user_error)?.to_string()),
+        ValueRef::Blob(bytes) => match Stored::from_slice(bytes) {
+            Ok(stored) => stored.value,
+            Err(_) => Bson::Binary(bson::Binary {
+                subtype: bson::spec::BinarySubtype::Generic,
+                bytes: bytes.to_vec(),
+            }),
+        },
+    })
+}
+
+#[cfg(any(
+    feature = "rusqlite-functions",
+    feature = "zstd",
+    feature = "encryption"
+))]
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
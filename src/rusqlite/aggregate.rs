@@ -0,0 +1,43 @@
+//! The `bson_group_array` and `bson_group_object` aggregates.
+
+use super::{from_sql, key_arg, to_blob};
+use ::rusqlite::functions::{Aggregate, Context};
+use ::rusqlite::types::Value;
+use ::rusqlite::Result;
+use bson::{Bson, Document};
+
+/// `bson_group_array(value)`, every value of the group as an array.
+pub(super) struct GroupArray;
+
+impl Aggregate<Vec<Bson>, Value> for GroupArray {
+    fn init(&self, _ctx: &mut Context<'_>) -> Result<Vec<Bson>> {
+        Ok(Vec::new())
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, array: &mut Vec<Bson>) -> Result<()> {
+        array.push(from_sql(ctx.get_raw(0))?);
+        Ok(())
+    }
+
+    fn finalize(&self, _ctx: &mut Context<'_>, array: Option<Vec<Bson>>) -> Result<Value> {
+        to_blob(Bson::Array(array.unwrap_or_default()))
+    }
+}
+
+/// `bson_group_object(key, value)`, a document of every key and value of the group.
+pub(super) struct GroupObject;
+
+impl Aggregate<Document, Value> for GroupObject {
+    fn init(&self, _ctx: &mut Context<'_>) -> Result<Document> {
+        Ok(Document::new())
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, doc: &mut Document) -> Result<()> {
+        doc.insert(key_arg(ctx.get_raw(0))?, from_sql(ctx.get_raw(1))?);
+        Ok(())
+    }
+
+    fn finalize(&self, _ctx: &mut Context<'_>, doc: Option<Document>) -> Result<Value> {
+        to_blob(Bson::Document(doc.unwrap_or_default()))
+    }
+}
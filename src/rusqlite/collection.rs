@@ -0,0 +1,408 @@
+//! [`Collection`], a table of [`DBson`] values queried with MongoDB style filters.
+
+use super::quote_identifier;
+use crate::filter::{Dialect, Index, SqlFilter, SqlParam};
+use crate::DBson;
+use ::rusqlite::types::{FromSqlError, FromSqlResult, Value, ValueRef};
+use ::rusqlite::{params_from_iter, Connection, Error, OptionalExtension, Result, ToSql};
+use bson::oid::ObjectId;
+use bson::Document;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+
+/// The id of the values of a [`Collection`].
+///
+/// Implemented for [`ObjectId`], generated on insert and stored as hex `TEXT`, and for `i64`,
+/// assigned by sqlite as the `INTEGER PRIMARY KEY` of the row.
+pub trait CollectionId: Sized {
+    /// The column definition of the id.
+    const COLUMN: &'static str;
+
+    /// A new id for an inserted value, `None` to let sqlite pick one.
+    fn generate() -> Option<Self>;
+
+    fn to_sql(&self) -> Value;
+
+    fn from_sql(value: ValueRef<'_>) -> FromSqlResult<Self>;
+}
+
+impl CollectionId for ObjectId {
+    const COLUMN: &'static str = "id TEXT PRIMARY KEY NOT NULL";
+
+    fn generate() -> Option<Self> {
+        Some(ObjectId::new())
+    }
+
+    fn to_sql(&self) -> Value {
+        Value::Text(self.to_hex())
+    }
+
+    fn from_sql(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        ObjectId::parse_str(value.as_str()?).map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+impl CollectionId for i64 {
+    const COLUMN: &'static str = "id INTEGER PRIMARY KEY";
+
+    fn generate() -> Option<Self> {
+        None
+    }
+
+    fn to_sql(&self) -> Value {
+        Value::Integer(*self)
+    }
+
+    fn from_sql(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_i64()
+    }
+}
+
+/// A sqlite table holding values of `T` by id, with a MongoDB like API.
+///
+/// The table has an `id` column of type `I` (see [`CollectionId`]) and a `data` column holding
+/// a [`DBson<T>`], so it can be queried with the [sql functions](super) as well. Filters are
+/// compiled with [`SqlFilter`] and updates are applied with [`DBson::apply_update`], so updated
+/// values are checked against `T` before they're written. A filter or an update which can't be
+/// applied fails with [`Error::UserFunctionError`] holding the
+/// [`FilterError`](crate::filter::FilterError) or the [`dbson::Error`](crate::Error). Fields
+/// queried often are best [indexed](Self::create_index), as otherwise every filter reads the
+/// whole table.
+/// ```rust
+/// use bson::doc;
+/// use dbson::rusqlite::Collection;
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct User {
+///     name: String,
+///     age: u32,
+/// }
+/// let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// let users: Collection<User> = Collection::new(&conn, "users").unwrap();
+/// users.insert_one(&User { name: "ann".into(), age: 25 }).unwrap();
+/// users.insert_one(&User { name: "bob".into(), age: 35 }).unwrap();
+///
+/// users.update_many(&doc! { "age": { "$gt": 30 } }, &doc! { "$inc": { "age": 1 } }).unwrap();
+/// let (_id, bob) = users.find_one(&doc! { "name": "bob" }).unwrap().unwrap();
+/// assert_eq!(bob.age, 36);
+/// for user in users.find(&doc! {}).unwrap() {
+///     let (id, user) = user.unwrap();
+///     println!("{id}: {}", user.name);
+/// }
+/// ```
+pub struct Collection<'c, T, I = ObjectId> {
+    conn: &'c Connection,
+    name: String,
+    table: String,
+    marker: PhantomData<fn(T) -> (T, I)>,
+}
+
+impl<'c, T: Serialize + DeserializeOwned, I: CollectionId> Collection<'c, T, I> {
+    /// Open the collection stored in the table `name`, creating the table if it doesn't exist.
+    ///
+    /// This also installs the [sql functions](super::register_functions) on `conn`.
+    pub fn new(conn: &'c Connection, name: &str) -> Result<Self> {
+        super::register_functions(conn)?;
+        let collection = Self {
+            conn,
+            name: name.to_string(),
+            table: quote_identifier(name),
+            marker: PhantomData,
+        };
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} ({}, data BLOB NOT NULL)",
+            collection.table,
+            I::COLUMN
+        ))?;
+        Ok(collection)
+    }
+
+    /// Insert `value`, returning its id.
+    pub fn insert_one(&self, value: &T) -> Result<I> {
+        let data = DBson::new(value);
+        match I::generate() {
+            Some(id) => {
+                self.conn.execute(
+                    &format!("INSERT INTO {} (id, data) VALUES (?1, ?2)", self.table),
+                    (id.to_sql(), data),
+                )?;
+                Ok(id)
+            }
+            None => {
+                self.conn.execute(
+                    &format!("INSERT INTO {} (data) VALUES (?1)", self.table),
+                    [data],
+                )?;
+                read_id(0, ValueRef::Integer(self.conn.last_insert_rowid()))
+            }
+        }
+    }
+
+    /// Insert every value in a single savepoint, returning their ids in order.
+    pub fn insert_many<'v>(&self, values: impl IntoIterator<Item = &'v T>) -> Result<Vec<I>>
+    where
+        T: 'v,
+    {
+        self.atomically(|| {
+            values
+                .into_iter()
+                .map(|value| self.insert_one(value))
+                .collect()
+        })
+    }
+
+    /// The value with the id `id`.
+    pub fn get(&self, id: &I) -> Result<Option<T>> {
+        self.conn
+            .query_row(
+                &format!("SELECT data FROM {} WHERE id = ?1", self.table),
+                [id.to_sql()],
+                |row| row.get::<_, DBson<T>>(0),
+            )
+            .optional()
+            .map(|data| data.map(DBson::into_inner))
+    }
+
+    /// Every value matching `filter`, in insertion order.
+    pub fn find(&self, filter: &Document) -> Result<Cursor<'c, T, I>> {
+        let filter = compile(filter)?;
+        Ok(Cursor {
+            conn: self.conn,
+            sql: format!(
+                "SELECT rowid, id, data FROM {} WHERE rowid > ?{} AND {} ORDER BY rowid LIMIT ?{}",
+                self.table,
+                filter.params.len() + 1,
+                filter.sql,
+                filter.params.len() + 2,
+            ),
+            params: filter.params,
+            after: i64::MIN,
+            batch_size: DEFAULT_BATCH_SIZE,
+            buffer: VecDeque::new(),
+            done: false,
+            marker: PhantomData,
+        })
+    }
+
+    /// The first value matching `filter`.
+    pub fn find_one(&self, filter: &Document) -> Result<Option<(I, T)>> {
+        self.find(filter)?.batch_size(1).next().transpose()
+    }
+
+    /// The number of values matching `filter`.
+    pub fn count(&self, filter: &Document) -> Result<u64> {
+        let filter = compile(filter)?;
+        self.conn.query_row(
+            &format!("SELECT count(*) FROM {} WHERE {}", self.table, filter.sql),
+            params_from_iter(&filter.params),
+            |row| row.get(0),
+        )
+    }
+
+    /// Apply `update` to the first value matching `filter`, returning the number of updated
+    /// values.
+    pub fn update_one(&self, filter: &Document, update: &Document) -> Result<usize> {
+        self.update(filter, update, "LIMIT 1")
+    }
+
+    /// Apply `update` to every value matching `filter`, returning the number of updated values.
+    ///
+    /// Either every value is updated or, if any update fails, none is.
+    pub fn update_many(&self, filter: &Document, update: &Document) -> Result<usize> {
+        self.update(filter, update, "")
+    }
+
+    /// Replace the first value matching `filter` with `value`, returning the number of replaced
+    /// values.
+    pub fn replace_one(&self, filter: &Document, value: &T) -> Result<usize> {
+        let filter = compile(filter)?;
+        let sql = format!(
+            "UPDATE {table} SET data = ?{} WHERE rowid = \
+             (SELECT rowid FROM {table} WHERE {} ORDER BY rowid LIMIT 1)",
+            filter.params.len() + 1,
+            filter.sql,
+            table = self.table,
+        );
+        let data = DBson::new(value);
+        let params = filter.params.iter().map(|p| p as &dyn ToSql);
+        self.conn
+            .execute(&sql, params_from_iter(params.chain([&data as &dyn ToSql])))
+    }
+
+    /// Delete the first value matching `filter`, returning the number of deleted values.
+    pub fn delete_one(&self, filter: &Document) -> Result<usize> {
+        let filter = compile(filter)?;
+        self.conn.execute(
+            &format!(
+                "DELETE FROM {table} WHERE rowid = \
+                 (SELECT rowid FROM {table} WHERE {} ORDER BY rowid LIMIT 1)",
+                filter.sql,
+                table = self.table,
+            ),
+            params_from_iter(&filter.params),
+        )
+    }
+
+    /// Delete every value matching `filter`, returning the number of deleted values.
+    pub fn delete_many(&self, filter: &Document) -> Result<usize> {
+        let filter = compile(filter)?;
+        self.conn.execute(
+            &format!("DELETE FROM {} WHERE {}", self.table, filter.sql),
+            params_from_iter(&filter.params),
+        )
+    }
+
+    /// Create `index` if the collection doesn't have an index of that name yet, returning
+    /// its name.
+    ///
+    /// The index is stored as the sqlite index `"<collection>_<name>"`, which sqlite updates
+    /// along with the table and uses for the filters on its fields. Creating a unique index
+    /// fails if the values already in the collection have duplicate keys, and so do the writes
+    /// that would add one.
+    pub fn create_index(&self, index: &Index) -> Result<String> {
+        let name = index.name_or_default();
+        let sql = index
+            .create_sql(&self.index_identifier(&name), &self.table, "data")
+            .map_err(|e| Error::UserFunctionError(Box::new(e)))?;
+        self.conn.execute_batch(&sql)?;
+        Ok(name)
+    }
+
+    /// Drop the index `name`, doing nothing if there's no such index.
+    pub fn drop_index(&self, name: &str) -> Result<()> {
+        self.conn.execute_batch(&format!(
+            "DROP INDEX IF EXISTS {}",
+            self.index_identifier(name)
+        ))
+    }
+
+    /// The names of the indexes created with [`create_index`](Self::create_index).
+    pub fn list_index_names(&self) -> Result<Vec<String>> {
+        let prefix = format!("{}_", self.name);
+        self.conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = ?1 ORDER BY name")?
+            .query_map([&self.name], |row| row.get::<_, String>(0))?
+            .filter_map(|name| match name {
+                Ok(name) => name.strip_prefix(&prefix).map(|name| Ok(name.to_string())),
+                Err(e) => Some(Err(e)),
+            })
+            .collect()
+    }
+
+    fn index_identifier(&self, name: &str) -> String {
+        quote_identifier(&format!("{}_{name}", self.name))
+    }
+
+    fn update(&self, filter: &Document, update: &Document, limit: &str) -> Result<usize> {
+        let filter = compile(filter)?;
+        self.atomically(|| {
+            let rows = self
+                .conn
+                .prepare(&format!(
+                    "SELECT rowid, data FROM {} WHERE {} ORDER BY rowid {limit}",
+                    self.table, filter.sql
+                ))?
+                .query_map(params_from_iter(&filter.params), |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, DBson<T>>(1)?))
+                })?
+                .collect::<Result<Vec<_>>>()?;
+            let mut statement = self.conn.prepare(&format!(
+                "UPDATE {} SET data = ?2 WHERE rowid = ?1",
+                self.table
+            ))?;
+            let updated = rows.len();
+            for (rowid, mut data) in rows {
+                data.apply_update(update)
+                    .map_err(|e| Error::UserFunctionError(Box::new(e)))?;
+                statement.execute((rowid, data))?;
+            }
+            Ok(updated)
+        })
+    }
+
+    /// Run `f` in a savepoint, which is rolled back if it fails.
+    fn atomically<R>(&self, f: impl FnOnce() -> Result<R>) -> Result<R> {
+        self.conn.execute_batch("SAVEPOINT dbson_collection")?;
+        match f() {
+            Ok(result) => {
+                self.conn.execute_batch("RELEASE dbson_collection")?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.conn
+                    .execute_batch("ROLLBACK TO dbson_collection; RELEASE dbson_collection")?;
+                Err(e)
+            }
+        }
+    }
+}
+
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// The values found by [`Collection::find`], with their ids.
+///
+/// Rows are read in batches as the cursor is iterated, each batch starting after the last row
+/// of the previous one, so no statement is left open between batches and rows inserted while
+/// iterating are found too.
+pub struct Cursor<'c, T, I = ObjectId> {
+    conn: &'c Connection,
+    sql: String,
+    params: Vec<SqlParam>,
+    /// The rowid of the last row read.
+    after: i64,
+    batch_size: usize,
+    buffer: VecDeque<(I, T)>,
+    done: bool,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned, I: CollectionId> Cursor<'_, T, I> {
+    /// Read `batch_size` rows at a time, 100 by default.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    fn fetch(&mut self) -> Result<()> {
+        let mut statement = self.conn.prepare(&self.sql)?;
+        let params = self.params.iter().map(|p| p as &dyn ToSql);
+        let (after, limit) = (self.after, self.batch_size as i64);
+        let mut rows = statement.query(params_from_iter(
+            params.chain([&after as &dyn ToSql, &limit as &dyn ToSql]),
+        ))?;
+        let mut read = 0;
+        while let Some(row) = rows.next()? {
+            self.after = row.get(0)?;
+            let id = read_id(1, row.get_ref(1)?)?;
+            let data: DBson<T> = row.get(2)?;
+            self.buffer.push_back((id, data.into_inner()));
+            read += 1;
+        }
+        self.done = read < self.batch_size;
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned, I: CollectionId> Iterator for Cursor<'_, T, I> {
+    type Item = Result<(I, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            if let Err(e) = self.fetch() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+fn read_id<I: CollectionId>(column: usize, value: ValueRef<'_>) -> Result<I> {
+    I::from_sql(value)
+        .map_err(|e| Error::FromSqlConversionFailure(column, value.data_type(), Box::new(e)))
+}
+
+fn compile(filter: &Document) -> Result<SqlFilter> {
+    SqlFilter::new(filter, "data", Dialect::Sqlite)
+        .map_err(|e| Error::UserFunctionError(Box::new(e)))
+}
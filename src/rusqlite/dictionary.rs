@@ -0,0 +1,91 @@
+//! [`train_dictionary`] and [`load_dictionaries`], storing zstd dictionaries in sqlite.
+
+use super::quote_identifier;
+use crate::dictionary::{self, TABLE};
+use ::rusqlite::{Connection, Error, Result};
+
+/// The number of blobs a dictionary is trained from.
+const SAMPLES: usize = 1000;
+
+/// Train a zstd dictionary of at most `max_size` bytes from the blobs of `column` in `table`,
+/// store it as `id` in the [dictionary table](crate::dictionary::TABLE) and
+/// [register](crate::dictionary::register) it.
+///
+/// The dictionary is trained from up to a thousand blobs spread evenly over the table in
+/// `rowid` order, so the same rows train the same dictionary. Values compressed
+/// with a dictionary can't be read without it, so ids aren't reused: storing a dictionary with
+/// the id of a stored one fails.
+/// ```rust
+/// use dbson::codec::{Compressed, ZstdDict};
+/// use dbson::DBson;
+/// let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// conn.execute_batch("CREATE TABLE logs (data BLOB)").unwrap();
+/// for i in 0..500 {
+///     let line = bson::doc! { "level": "info", "service": "api", "request": i };
+///     conn.execute("INSERT INTO logs VALUES (?1)", [DBson::new(line)]).unwrap();
+/// }
+/// dbson::rusqlite::train_dictionary(&conn, "logs", "data", 7, 2048).unwrap();
+///
+/// // new values are compressed with the dictionary, the old ones are read as they are
+/// type Log = DBson<bson::Document, Compressed<ZstdDict<7>, dbson::codec::Bson, 0>>;
+/// let line = bson::doc! { "level": "warn", "service": "api", "request": 500 };
+/// conn.execute("INSERT INTO logs VALUES (?1)", [Log::with_codec(line)]).unwrap();
+/// let logs = conn
+///     .prepare("SELECT data FROM logs")
+///     .unwrap()
+///     .query_map([], |row| row.get::<_, Log>(0))
+///     .unwrap()
+///     .count();
+/// assert_eq!(logs, 501);
+/// ```
+pub fn train_dictionary(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    id: u32,
+    max_size: usize,
+) -> Result<()> {
+    create_table(conn)?;
+    let column = quote_identifier(column);
+    let samples = conn
+        .prepare(&format!(
+            "SELECT {column} FROM (SELECT {column}, ROW_NUMBER() OVER (ORDER BY rowid) - 1 AS n, \
+             COUNT(*) OVER () AS total FROM {} WHERE typeof({column}) = 'blob') AS samples \
+             WHERE n % ((total + {SAMPLES} - 1) / {SAMPLES}) = 0 LIMIT {SAMPLES}",
+            quote_identifier(table)
+        ))?
+        .query_map([], |row| row.get::<_, Vec<u8>>(0))?
+        .collect::<Result<Vec<_>>>()?;
+    let trained = dictionary::train(&samples, max_size).map_err(Error::ToSqlConversionFailure)?;
+    conn.execute(
+        &format!("INSERT INTO {TABLE} (id, dictionary) VALUES (?1, ?2)"),
+        (id, &trained),
+    )?;
+    dictionary::register(id, trained);
+    Ok(())
+}
+
+/// [Register](crate::dictionary::register) every dictionary stored in `conn`, returning how
+/// many there are.
+///
+/// This creates the dictionary table if it doesn't exist yet.
+pub fn load_dictionaries(conn: &Connection) -> Result<usize> {
+    create_table(conn)?;
+    let dictionaries = conn
+        .prepare(&format!("SELECT id, dictionary FROM {TABLE}"))?
+        .query_map([], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    let loaded = dictionaries.len();
+    for (id, trained) in dictionaries {
+        dictionary::register(id, trained);
+    }
+    Ok(loaded)
+}
+
+fn create_table(conn: &Connection) -> Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {TABLE} (id INTEGER PRIMARY KEY, dictionary BLOB NOT NULL)"
+    ))
+}
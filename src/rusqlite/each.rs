@@ -0,0 +1,240 @@
+//! The `bson_each` and `bson_tree` table-valued functions, modelled after sqlite's
+//! [`json_each` and `json_tree`](https://www.sqlite.org/json1.html#jeach).
+
+use super::{path_arg, stored_arg, to_sql};
+use crate::path::{Path, Segment};
+use crate::stored;
+use ::rusqlite::ffi;
+use ::rusqlite::types::Value;
+use ::rusqlite::vtab::{
+    eponymous_only_module, Context, IndexConstraintOp, IndexInfo, VTab, VTabConfig, VTabConnection,
+    VTabCursor, Values,
+};
+use ::rusqlite::{Connection, Result};
+use bson::Bson;
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+
+const COLUMN_KEY: c_int = 0;
+const COLUMN_VALUE: c_int = 1;
+const COLUMN_TYPE: c_int = 2;
+const COLUMN_ATOM: c_int = 3;
+const COLUMN_ID: c_int = 4;
+const COLUMN_PARENT: c_int = 5;
+const COLUMN_FULLKEY: c_int = 6;
+const COLUMN_PATH: c_int = 7;
+const COLUMN_BSON: c_int = 8;
+const COLUMN_ROOT: c_int = 9;
+
+/// `idx_num` flags telling [`EachCursor::filter`] which arguments it was given.
+const HAS_BSON: c_int = 1;
+const HAS_ROOT: c_int = 2;
+
+pub(super) fn load_module(conn: &Connection) -> Result<()> {
+    conn.create_module("bson_each", eponymous_only_module::<EachTab>(), Some(false))?;
+    conn.create_module("bson_tree", eponymous_only_module::<EachTab>(), Some(true))
+}
+
+#[repr(C)]
+struct EachTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    /// Walk the whole tree (`bson_tree`) rather than the direct children (`bson_each`).
+    recursive: bool,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for EachTab {
+    type Aux = bool;
+    type Cursor = EachCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&bool>,
+        _args: &[&[u8]],
+    ) -> Result<(String, EachTab)> {
+        db.config(VTabConfig::Innocuous)?;
+        let vtab = EachTab {
+            base: ffi::sqlite3_vtab::default(),
+            recursive: aux.copied().unwrap_or_default(),
+        };
+        Ok((
+            "CREATE TABLE x(key,value,type,atom,id,parent,fullkey,path,bson hidden,root hidden)"
+                .to_owned(),
+            vtab,
+        ))
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> Result<()> {
+        let mut bson = None;
+        let mut root = None;
+        for (i, constraint) in info.constraints().enumerate() {
+            if !constraint.is_usable()
+                || constraint.operator() != IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ
+            {
+                continue;
+            }
+            match constraint.column() {
+                COLUMN_BSON => bson = Some(i),
+                COLUMN_ROOT => root = Some(i),
+                _ => {}
+            }
+        }
+        let Some(bson) = bson else {
+            // without a blob there is nothing to walk, make sure the planner provides one
+            info.set_estimated_cost(f64::from(i32::MAX));
+            info.set_estimated_rows(i64::from(i32::MAX));
+            info.set_idx_num(0);
+            return Ok(());
+        };
+        let mut idx_num = HAS_BSON;
+        let mut usage = info.constraint_usage(bson);
+        usage.set_argv_index(1);
+        usage.set_omit(true);
+        if let Some(root) = root {
+            idx_num |= HAS_ROOT;
+            let mut usage = info.constraint_usage(root);
+            usage.set_argv_index(2);
+            usage.set_omit(true);
+        }
+        info.set_estimated_cost(1.0);
+        info.set_estimated_rows(100);
+        info.set_idx_num(idx_num);
+        Ok(())
+    }
+
+    fn open(&'vtab mut self) -> Result<EachCursor<'vtab>> {
+        Ok(EachCursor {
+            base: ffi::sqlite3_vtab_cursor::default(),
+            recursive: self.recursive,
+            rows: Vec::new(),
+            row: 0,
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// A single element of the walked value.
+struct Row {
+    key: Value,
+    value: Bson,
+    parent: Option<i64>,
+    full_key: Path,
+}
+
+#[repr(C)]
+struct EachCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    recursive: bool,
+    rows: Vec<Row>,
+    row: usize,
+    phantom: PhantomData<&'vtab EachTab>,
+}
+
+impl EachCursor<'_> {
+    /// Add `value` to the rows and, for `bson_tree`, everything below it.
+    fn walk(&mut self, key: Value, value: &Bson, parent: Option<i64>, full_key: Path) {
+        let id = self.rows.len() as i64;
+        self.rows.push(Row {
+            key,
+            value: value.clone(),
+            parent,
+            full_key: full_key.clone(),
+        });
+        if self.recursive {
+            self.children(value, Some(id), &full_key);
+        }
+    }
+
+    fn children(&mut self, value: &Bson, parent: Option<i64>, path: &Path) {
+        let child = |segment| {
+            let mut path = path.clone();
+            path.0.push(segment);
+            path
+        };
+        match value {
+            Bson::Document(doc) => {
+                for (key, value) in doc {
+                    let full_key = child(Segment::Key(key.clone()));
+                    self.walk(Value::Text(key.clone()), value, parent, full_key);
+                }
+            }
+            Bson::Array(array) => {
+                for (index, value) in array.iter().enumerate() {
+                    let full_key = child(Segment::Index(index));
+                    self.walk(Value::Integer(index as i64), value, parent, full_key);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+unsafe impl VTabCursor for EachCursor<'_> {
+    fn filter(&mut self, idx_num: c_int, _idx_str: Option<&str>, args: &Values<'_>) -> Result<()> {
+        self.rows.clear();
+        self.row = 0;
+        let mut args = args.iter();
+        let stored = match idx_num & HAS_BSON {
+            0 => None,
+            _ => args.next().map(stored_arg).transpose()?.flatten(),
+        };
+        let root = match idx_num & HAS_ROOT {
+            0 => Path::default(),
+            _ => args.next().map(path_arg).transpose()?.unwrap_or_default(),
+        };
+        let Some(value) = stored.as_ref().and_then(|stored| root.get(&stored.value)) else {
+            return Ok(());
+        };
+        match value {
+            Bson::Document(_) | Bson::Array(_) if !self.recursive => {
+                self.children(value, None, &root)
+            }
+            _ => {
+                let key = match root.0.last() {
+                    Some(Segment::Key(key)) => Value::Text(key.clone()),
+                    Some(Segment::Index(index)) => Value::Integer(*index as i64),
+                    Some(Segment::FromEnd(_)) | None => Value::Null,
+                };
+                self.walk(key, value, None, root)
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.row += 1;
+        Ok(())
+    }
+
+    fn eof(&self) -> bool {
+        self.row >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> Result<()> {
+        let row = &self.rows[self.row];
+        match i {
+            COLUMN_KEY => ctx.set_result(&row.key),
+            COLUMN_VALUE => ctx.set_result(&to_sql(&row.value)?),
+            COLUMN_TYPE => ctx.set_result(&stored::type_name(&row.value)),
+            COLUMN_ATOM => match row.value {
+                Bson::Document(_) | Bson::Array(_) => ctx.set_result(&Value::Null),
+                _ => ctx.set_result(&to_sql(&row.value)?),
+            },
+            COLUMN_ID => ctx.set_result(&(self.row as i64)),
+            COLUMN_PARENT => ctx.set_result(&row.parent),
+            COLUMN_FULLKEY => ctx.set_result(&row.full_key.to_string()),
+            COLUMN_PATH => {
+                let mut path = row.full_key.clone();
+                path.0.pop();
+                ctx.set_result(&path.to_string())
+            }
+            // the hidden argument columns aren't read back
+            _ => ctx.set_result(&Value::Null),
+        }
+    }
+
+    fn rowid(&self) -> Result<i64> {
+        Ok(self.row as i64)
+    }
+}
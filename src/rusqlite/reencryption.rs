@@ -0,0 +1,131 @@
+//! [`reencrypt`], moving a column of encrypted blobs to the current key.
+
+use super::quote_identifier;
+use crate::encryption::{self, KeyProvider, Reencrypted, Reencryption, CHECKPOINT_TABLE};
+use ::rusqlite::types::Type;
+use ::rusqlite::{Connection, Error, OptionalExtension, Result};
+
+/// Rewrite the blobs of a column still encrypted with an older key with the current key of
+/// `keys`, batch by batch, see [`Reencryption`].
+///
+/// Each batch is its own transaction, so writers aren't blocked for longer than a batch takes.
+/// Blobs that aren't encrypted fail the re-encryption, `NULL`s are skipped.
+/// ```rust
+/// use dbson::encryption::{EncryptedDBson, KeyRing, Reencryption};
+/// use std::sync::Arc;
+/// let ring = Arc::new(KeyRing::new("2023", [1; 32]));
+/// dbson::encryption::set_key_provider(ring.clone());
+/// let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// conn.execute_batch("CREATE TABLE users (id INTEGER PRIMARY KEY, email BLOB)").unwrap();
+/// for i in 0..10 {
+///     let email = EncryptedDBson::new(format!("user{i}@example.com"));
+///     conn.execute("INSERT INTO users (email) VALUES (?1)", [email]).unwrap();
+/// }
+///
+/// ring.rotate("2024", [2; 32]);
+/// let done = dbson::rusqlite::reencrypt(&conn, &Reencryption::new("users", "email"), &*ring).unwrap();
+/// assert_eq!(done.rewritten, 10);
+/// ring.remove("2023").unwrap();
+/// let email: EncryptedDBson<String> = conn
+///     .query_row("SELECT email FROM users WHERE id = 3", [], |row| row.get(0))
+///     .unwrap();
+/// assert_eq!(email.into_inner(), "user2@example.com");
+/// ```
+pub fn reencrypt(
+    conn: &Connection,
+    reencryption: &Reencryption<'_>,
+    keys: &dyn KeyProvider,
+) -> Result<Reencrypted> {
+    let key_id = keys
+        .current_key_id()
+        .map_err(Error::ToSqlConversionFailure)?;
+    create_table(conn)?;
+    let Reencryption {
+        table,
+        column,
+        id_column,
+        batch_size,
+        ..
+    } = reencryption;
+    let mut last_id = conn
+        .query_row(
+            &format!(
+                "SELECT last_id FROM {CHECKPOINT_TABLE} \
+                 WHERE table_name = ?1 AND column_name = ?2 AND key_id = ?3"
+            ),
+            (table, column, key_id.as_bytes()),
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?;
+    let (table, column, id_column) = (
+        quote_identifier(table),
+        quote_identifier(column),
+        quote_identifier(id_column),
+    );
+    let select = format!(
+        "SELECT {id_column}, {column} FROM {table} \
+         WHERE {column} IS NOT NULL AND (?1 IS NULL OR {id_column} > ?1) \
+         ORDER BY {id_column} LIMIT {batch_size}"
+    );
+    let update =
+        format!("UPDATE {table} SET {column} = ?2 WHERE {id_column} = ?1 AND {column} = ?3");
+    let mut done = Reencrypted::default();
+    loop {
+        let tx = conn.unchecked_transaction()?;
+        let rows = tx
+            .prepare_cached(&select)?
+            .query_map([last_id], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        for (id, blob) in &rows {
+            let associated_data = reencryption.associated_data_of(*id);
+            let reencrypted = encryption::reencrypt(keys, blob, &associated_data)
+                .map_err(|e| Error::FromSqlConversionFailure(1, Type::Blob, e))?;
+            let rewritten = match reencrypted {
+                Some(reencrypted) => {
+                    tx.prepare_cached(&update)?
+                        .execute((id, reencrypted, blob))?
+                }
+                None => 0,
+            };
+            match rewritten {
+                0 => done.up_to_date += 1,
+                _ => done.rewritten += 1,
+            }
+        }
+        let Some((id, _)) = rows.last() else {
+            tx.execute(
+                &format!(
+                    "DELETE FROM {CHECKPOINT_TABLE} WHERE table_name = ?1 AND column_name = ?2"
+                ),
+                (&reencryption.table, &reencryption.column),
+            )?;
+            tx.commit()?;
+            return Ok(done);
+        };
+        last_id = Some(*id);
+        tx.execute(
+            &format!(
+                "INSERT INTO {CHECKPOINT_TABLE} (table_name, column_name, key_id, last_id) \
+                 VALUES (?1, ?2, ?3, ?4) ON CONFLICT (table_name, column_name) \
+                 DO UPDATE SET key_id = excluded.key_id, last_id = excluded.last_id"
+            ),
+            (
+                &reencryption.table,
+                &reencryption.column,
+                key_id.as_bytes(),
+                id,
+            ),
+        )?;
+        tx.commit()?;
+    }
+}
+
+fn create_table(conn: &Connection) -> Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {CHECKPOINT_TABLE} (table_name TEXT NOT NULL, \
+         column_name TEXT NOT NULL, key_id BLOB NOT NULL, last_id INTEGER NOT NULL, \
+         PRIMARY KEY (table_name, column_name))"
+    ))
+}
@@ -0,0 +1,940 @@
+//! An async [`Collection`] of [`DBson`] values over a [`sqlx::Pool`].
+//!
+//! The sqlite collection lives behind the `sqlx-sqlite` feature and the PostgreSQL one behind
+//! `sqlx-postgres`.
+
+use crate::filter::{Filter, FilterError, SqlFilter};
+use crate::DBson;
+use bson::oid::ObjectId;
+use bson::{Bson, Document};
+use futures_util::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use sqlx::error::BoxDynError;
+use sqlx::{Database, Error, Pool};
+use std::marker::PhantomData;
+
+/// The id of the values of a [`Collection`].
+///
+/// Implemented for [`ObjectId`], generated on insert and stored as hex text, and for `i64`,
+/// assigned by the database.
+pub trait CollectionId: sealed::Id {}
+
+impl CollectionId for ObjectId {}
+
+impl CollectionId for i64 {}
+
+mod sealed {
+    use super::*;
+
+    pub enum Key {
+        Integer(i64),
+        Text(String),
+    }
+
+    /// The databases a [`Collection`] can be stored in.
+    #[allow(async_fn_in_trait)]
+    pub trait Backend: Database {
+        /// The type of the `data` column.
+        const DATA_COLUMN: &'static str;
+        /// The `id` column when ids are integers picked by the database.
+        const INTEGER_ID_COLUMN: &'static str;
+        /// A column every table has, ordering its rows the same way until they're changed.
+        #[cfg(feature = "zstd")]
+        const ROW_ORDER: &'static str;
+
+        async fn execute(pool: &Pool<Self>, sql: &str) -> Result<(), Error>;
+
+        /// Whether the connections have the bson functions, so filters can be compiled to sql.
+        async fn has_functions(pool: &Pool<Self>) -> bool;
+
+        /// The rows of `sql` selecting an integer and a blob, with `params` bound to `$1`, `$2`...
+        #[cfg(any(feature = "zstd", feature = "encryption"))]
+        async fn fetch_blobs(
+            pool: &Pool<Self>,
+            sql: &str,
+            params: &[Param<'_>],
+        ) -> Result<Vec<(i64, Vec<u8>)>, Error>;
+
+        /// Run `sql` with `params` bound to `$1`, `$2`... on `connection` (or a transaction),
+        /// returning the number of rows affected.
+        #[cfg(any(feature = "zstd", feature = "encryption"))]
+        async fn execute_with(
+            connection: &mut Self::Connection,
+            sql: &str,
+            params: &[Param<'_>],
+        ) -> Result<u64, Error>;
+    }
+
+    /// A value bound to the queries of [`Backend::fetch_blobs`] and [`Backend::execute_with`].
+    #[cfg(any(feature = "zstd", feature = "encryption"))]
+    #[derive(Clone, Copy)]
+    pub enum Param<'a> {
+        Integer(Option<i64>),
+        Text(&'a str),
+        Blob(&'a [u8]),
+    }
+
+    pub trait Id: Sized + Send + Sync + Unpin + 'static {
+        /// Whether the id is an integer picked by the database.
+        const INTEGER: bool;
+
+        fn generate() -> Option<Self>;
+
+        fn to_key(&self) -> Key;
+
+        fn from_key(key: Key) -> Result<Self, BoxDynError>;
+    }
+
+    impl Id for ObjectId {
+        const INTEGER: bool = false;
+
+        fn generate() -> Option<Self> {
+            Some(ObjectId::new())
+        }
+
+        fn to_key(&self) -> Key {
+            Key::Text(self.to_hex())
+        }
+
+        fn from_key(key: Key) -> Result<Self, BoxDynError> {
+            match key {
+                Key::Text(hex) => Ok(ObjectId::parse_str(hex)?),
+                Key::Integer(i) => {
+                    Err(format!("expected an ObjectId, found the integer {i}").into())
+                }
+            }
+        }
+    }
+
+    impl Id for i64 {
+        const INTEGER: bool = true;
+
+        fn generate() -> Option<Self> {
+            None
+        }
+
+        fn to_key(&self) -> Key {
+            Key::Integer(*self)
+        }
+
+        fn from_key(key: Key) -> Result<Self, BoxDynError> {
+            match key {
+                Key::Integer(i) => Ok(i),
+                Key::Text(text) => Err(format!("expected an integer, found `{text}`").into()),
+            }
+        }
+    }
+}
+
+use sealed::Key;
+#[cfg(any(feature = "zstd", feature = "encryption"))]
+use sealed::Param;
+
+/// A table holding values of `T` by id, with an async MongoDB like API.
+///
+/// This is the async counterpart of the rusqlite
+#[cfg_attr(
+    feature = "rusqlite-functions",
+    doc = "[`Collection`](crate::rusqlite::Collection):"
+)]
+#[cfg_attr(not(feature = "rusqlite-functions"), doc = "`Collection`:")]
+/// the table has an `id` column of type `I` (see [`CollectionId`]) and a `data` column holding a
+/// [`DBson<T>`] (a `BLOB` in sqlite, `bytea` in PostgreSQL), and updates are applied with
+/// [`DBson::apply_update`] in a transaction. [`find`](Self::find) returns a
+/// [`Stream`](futures_util::Stream) reading the matching values in batches, in id order.
+///
+/// Filters are compiled to sql with [`SqlFilter`] on sqlite pools whose connections have the
+/// [bson functions](register_functions), which lets sqlite use its indexes. Otherwise, and
+/// always for PostgreSQL which can't look inside of a `bytea`, the rows are filtered as they're
+/// read.
+/// ```rust
+/// # #[cfg(feature = "sqlx-sqlite")]
+/// # tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+/// use bson::doc;
+/// use dbson::sqlx::Collection;
+/// use futures_util::TryStreamExt;
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct User {
+///     name: String,
+///     age: u32,
+/// }
+/// let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+/// let users: Collection<sqlx::Sqlite, User> = Collection::new(pool, "users").await.unwrap();
+/// users.insert_one(&User { name: "ann".into(), age: 25 }).await.unwrap();
+/// users.insert_one(&User { name: "bob".into(), age: 35 }).await.unwrap();
+///
+/// let adults: Vec<_> = users.find(&doc! { "age": { "$gte": 30 } }).unwrap().try_collect().await.unwrap();
+/// assert_eq!(adults.len(), 1);
+/// assert_eq!(adults[0].1.name, "bob");
+/// # });
+/// ```
+pub struct Collection<DB: Database, T, I = ObjectId> {
+    pool: Pool<DB>,
+    name: String,
+    table: String,
+    /// Whether filters can be compiled to sql.
+    sql_filters: bool,
+    marker: PhantomData<fn(T) -> (T, I)>,
+}
+
+impl<DB: Database, T, I> Clone for Collection<DB, T, I> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            name: self.name.clone(),
+            table: self.table.clone(),
+            sql_filters: self.sql_filters,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<DB: sealed::Backend, T, I: CollectionId> Collection<DB, T, I> {
+    /// Open the collection stored in the table `name`, creating the table if it doesn't exist.
+    pub async fn new(pool: Pool<DB>, name: &str) -> Result<Self, Error> {
+        let id_column = match I::INTEGER {
+            true => DB::INTEGER_ID_COLUMN,
+            false => "id TEXT PRIMARY KEY NOT NULL",
+        };
+        let table = format!("\"{}\"", name.replace('"', "\"\""));
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {table} ({id_column}, data {} NOT NULL)",
+            DB::DATA_COLUMN
+        );
+        DB::execute(&pool, &sql).await?;
+        let sql_filters = DB::has_functions(&pool).await;
+        Ok(Self {
+            pool,
+            name: name.to_string(),
+            table,
+            sql_filters,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<DB: Database, T, I> Collection<DB, T, I> {
+    /// The pool the collection reads from.
+    pub fn pool(&self) -> &Pool<DB> {
+        &self.pool
+    }
+
+    /// `filter` as a `WHERE` clause, with the part that can't be checked in sql left over.
+    fn compile(&self, filter: &Document) -> Result<SqlFilter, Error> {
+        let compiled = if self.sql_filters {
+            SqlFilter::new(filter, "data", crate::filter::Dialect::Sqlite)
+        } else {
+            Filter::new(filter).map(|residual| SqlFilter {
+                sql: "TRUE".into(),
+                params: Vec::new(),
+                residual: (!filter.is_empty()).then_some(residual),
+            })
+        };
+        compiled.map_err(filter_error)
+    }
+}
+
+fn filter_error(e: FilterError) -> Error {
+    Error::Encode(Box::new(e))
+}
+
+const BATCH_SIZE: i64 = 100;
+
+/// Whether the value read from a row matches the part of the filter left out of the sql.
+fn residual_matches(residual: Option<&Filter>, data: &DBson<Bson>) -> bool {
+    residual.is_none_or(|filter| filter.matches_value(&data.inner))
+}
+
+/// Install the [sql functions](crate::rusqlite) of the `rusqlite-functions` feature on a sqlx
+/// connection.
+///
+/// Every connection of a pool needs them for [`Collection`] to compile filters to sql, so this
+/// is best called from [`PoolOptions::after_connect`](sqlx::pool::PoolOptions::after_connect):
+/// ```rust
+/// # tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+/// let pool = sqlx::sqlite::SqlitePoolOptions::new()
+///     .after_connect(|conn, _| Box::pin(dbson::sqlx::register_functions(conn)))
+///     .connect("sqlite::memory:")
+///     .await
+///     .unwrap();
+/// let name: String = sqlx::query_scalar("SELECT bson_extract(bson_object('name', 'ann'), '$.name')")
+///     .fetch_one(&pool)
+///     .await
+///     .unwrap();
+/// assert_eq!(name, "ann");
+/// # });
+/// ```
+///
+/// This needs sqlx and rusqlite to link the same version of `libsqlite3-sys`.
+#[cfg(all(feature = "sqlx-sqlite", feature = "rusqlite-functions"))]
+#[cfg_attr(
+    docsrs,
+    doc(cfg(all(feature = "sqlx-sqlite", feature = "rusqlite-functions")))
+)]
+pub async fn register_functions(conn: &mut sqlx::SqliteConnection) -> Result<(), Error> {
+    let mut handle = conn.lock_handle().await?;
+    // SAFETY: the handle stays locked, and so unused by sqlx, while the borrowed connection
+    // exists, and a connection made with `from_handle` doesn't close the handle when dropped
+    let borrowed = unsafe { ::rusqlite::Connection::from_handle(handle.as_raw_handle().as_ptr()) }
+        .map_err(|e| Error::Configuration(Box::new(e)))?;
+    crate::rusqlite::register_functions(&borrowed).map_err(|e| Error::Configuration(Box::new(e)))
+}
+
+/// Train a zstd dictionary of at most `max_size` bytes from the blobs of `column` in `table`,
+/// store it as `id` in the [dictionary table](crate::dictionary::TABLE) and
+/// [register](crate::dictionary::register) it, like the rusqlite
+#[cfg_attr(
+    feature = "rusqlite",
+    doc = "[`train_dictionary`](crate::rusqlite::train_dictionary)."
+)]
+#[cfg_attr(not(feature = "rusqlite"), doc = "`train_dictionary`.")]
+#[cfg(feature = "zstd")]
+#[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+pub async fn train_dictionary<DB: sealed::Backend>(
+    pool: &Pool<DB>,
+    table: &str,
+    column: &str,
+    id: u32,
+    max_size: usize,
+) -> Result<(), Error> {
+    create_dictionary_table(pool).await?;
+    let quote = |name: &str| format!("\"{}\"", name.replace('"', "\"\""));
+    let sql = format!(
+        "SELECT CAST(0 AS BIGINT), {column} FROM (SELECT {column}, \
+         ROW_NUMBER() OVER (ORDER BY {}) - 1 AS n, COUNT(*) OVER () AS total \
+         FROM {} WHERE {column} IS NOT NULL) AS samples \
+         WHERE n % ((total + 999) / 1000) = 0 LIMIT 1000",
+        DB::ROW_ORDER,
+        quote(table),
+        column = quote(column),
+    );
+    let samples: Vec<_> = DB::fetch_blobs(pool, &sql, &[])
+        .await?
+        .into_iter()
+        .map(|(_, blob)| blob)
+        .collect();
+    let trained = crate::dictionary::train(&samples, max_size).map_err(Error::Encode)?;
+    let sql = format!(
+        "INSERT INTO {} (id, dictionary) VALUES ($1, $2)",
+        crate::dictionary::TABLE
+    );
+    let params = [Param::Integer(Some(id.into())), Param::Blob(&trained)];
+    DB::execute_with(&mut *pool.acquire().await?, &sql, &params).await?;
+    crate::dictionary::register(id, trained);
+    Ok(())
+}
+
+/// [Register](crate::dictionary::register) every dictionary stored in the database, returning
+/// how many there are.
+///
+/// This creates the dictionary table if it doesn't exist yet.
+#[cfg(feature = "zstd")]
+#[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+pub async fn load_dictionaries<DB: sealed::Backend>(pool: &Pool<DB>) -> Result<usize, Error> {
+    create_dictionary_table(pool).await?;
+    let sql = format!("SELECT id, dictionary FROM {}", crate::dictionary::TABLE);
+    let dictionaries = DB::fetch_blobs(pool, &sql, &[]).await?;
+    let loaded = dictionaries.len();
+    for (id, trained) in dictionaries {
+        let id = u32::try_from(id).map_err(|e| Error::Decode(Box::new(e)))?;
+        crate::dictionary::register(id, trained);
+    }
+    Ok(loaded)
+}
+
+#[cfg(feature = "zstd")]
+async fn create_dictionary_table<DB: sealed::Backend>(pool: &Pool<DB>) -> Result<(), Error> {
+    let sql = format!(
+        "CREATE TABLE IF NOT EXISTS {} (id BIGINT PRIMARY KEY, dictionary {} NOT NULL)",
+        crate::dictionary::TABLE,
+        DB::DATA_COLUMN
+    );
+    DB::execute(pool, &sql).await
+}
+
+/// Rewrite the blobs of a column still encrypted with an older key with the current key of
+/// `keys`, batch by batch, like the rusqlite
+#[cfg_attr(
+    feature = "rusqlite",
+    doc = "[`reencrypt`](crate::rusqlite::reencrypt)."
+)]
+#[cfg_attr(not(feature = "rusqlite"), doc = "`reencrypt`.")]
+///
+/// Each batch is rewritten in a transaction which also saves the
+/// [checkpoint](crate::encryption::CHECKPOINT_TABLE): dropping the future interrupts the
+/// re-encryption, rolling back the batch it was in, and it resumes after the last committed
+/// batch when it's run again.
+#[cfg(feature = "encryption")]
+#[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+pub async fn reencrypt<DB: sealed::Backend>(
+    pool: &Pool<DB>,
+    reencryption: &crate::encryption::Reencryption<'_>,
+    keys: &dyn crate::encryption::KeyProvider,
+) -> Result<crate::encryption::Reencrypted, Error> {
+    use crate::encryption::{self, Reencryption, CHECKPOINT_TABLE};
+    let key_id = keys.current_key_id().map_err(Error::Encode)?;
+    let sql = format!(
+        "CREATE TABLE IF NOT EXISTS {CHECKPOINT_TABLE} (table_name TEXT NOT NULL, \
+         column_name TEXT NOT NULL, key_id {} NOT NULL, last_id BIGINT NOT NULL, \
+         PRIMARY KEY (table_name, column_name))",
+        DB::DATA_COLUMN
+    );
+    DB::execute(pool, &sql).await?;
+    let Reencryption {
+        table,
+        column,
+        id_column,
+        batch_size,
+        ..
+    } = reencryption;
+    let names = [Param::Text(table), Param::Text(column)];
+    let sql = format!(
+        "SELECT last_id, key_id FROM {CHECKPOINT_TABLE} WHERE table_name = $1 AND column_name = $2"
+    );
+    let mut last_id = DB::fetch_blobs(pool, &sql, &names)
+        .await?
+        .into_iter()
+        .find(|(_, checkpoint)| *checkpoint == key_id.as_bytes())
+        .map(|(id, _)| id);
+    let quote = |name: &str| format!("\"{}\"", name.replace('"', "\"\""));
+    let (table, column, id_column) = (quote(table), quote(column), quote(id_column));
+    let select = format!(
+        "SELECT {id_column}, {column} FROM {table} \
+         WHERE {column} IS NOT NULL AND ($1 IS NULL OR {id_column} > $1) \
+         ORDER BY {id_column} LIMIT {batch_size}"
+    );
+    let update =
+        format!("UPDATE {table} SET {column} = $2 WHERE {id_column} = $1 AND {column} = $3");
+    let mut done = encryption::Reencrypted::default();
+    loop {
+        let rows = DB::fetch_blobs(pool, &select, &[Param::Integer(last_id)]).await?;
+        let mut transaction = pool.begin().await?;
+        for (id, blob) in &rows {
+            let associated_data = reencryption.associated_data_of(*id);
+            let reencrypted = encryption::reencrypt(keys, blob, &associated_data)
+                .map_err(|e| Error::Decode(format!("row {id}: {e}").into()))?;
+            let rewritten = match reencrypted {
+                Some(reencrypted) => {
+                    let params = [
+                        Param::Integer(Some(*id)),
+                        Param::Blob(&reencrypted),
+                        Param::Blob(blob),
+                    ];
+                    DB::execute_with(&mut transaction, &update, &params).await?
+                }
+                None => 0,
+            };
+            match rewritten {
+                0 => done.up_to_date += 1,
+                _ => done.rewritten += 1,
+            }
+        }
+        let Some((id, _)) = rows.last() else {
+            let sql = format!(
+                "DELETE FROM {CHECKPOINT_TABLE} WHERE table_name = $1 AND column_name = $2"
+            );
+            DB::execute_with(&mut transaction, &sql, &names).await?;
+            transaction.commit().await?;
+            return Ok(done);
+        };
+        last_id = Some(*id);
+        let sql = format!(
+            "INSERT INTO {CHECKPOINT_TABLE} (table_name, column_name, key_id, last_id) \
+             VALUES ($1, $2, $3, $4) ON CONFLICT (table_name, column_name) \
+             DO UPDATE SET key_id = excluded.key_id, last_id = excluded.last_id"
+        );
+        let params = [
+            names[0],
+            names[1],
+            Param::Blob(key_id.as_bytes()),
+            Param::Integer(last_id),
+        ];
+        DB::execute_with(&mut transaction, &sql, &params).await?;
+        transaction.commit().await?;
+    }
+}
+
+macro_rules! impl_collection {
+    ($db: ty, $feature: literal, $placeholder: literal) => {
+        #[cfg(feature = $feature)]
+        #[cfg_attr(docsrs, doc(cfg(feature = $feature)))]
+        impl<T, I> Collection<$db, T, I>
+        where
+            T: serde::Serialize + serde::de::DeserializeOwned + Send + Unpin + 'static,
+            I: CollectionId,
+        {
+            fn placeholder(n: usize) -> String {
+                format!(concat!($placeholder, "{}"), n)
+            }
+
+            fn bind_key<'q>(
+                query: sqlx::query::Query<'q, $db, <$db as Database>::Arguments<'q>>,
+                key: Option<Key>,
+            ) -> sqlx::query::Query<'q, $db, <$db as Database>::Arguments<'q>> {
+                match key {
+                    Some(Key::Integer(i)) => query.bind(i),
+                    Some(Key::Text(text)) => query.bind(text),
+                    None if I::INTEGER => query.bind(None::<i64>),
+                    None => query.bind(None::<String>),
+                }
+            }
+
+            fn query<'q>(
+                sql: &'q str,
+                filter: &SqlFilter,
+            ) -> sqlx::query::Query<'q, $db, <$db as Database>::Arguments<'q>> {
+                use crate::filter::SqlParam;
+                filter
+                    .params
+                    .iter()
+                    .fold(sqlx::query(sql), |query, param| match param {
+                        SqlParam::Integer(i) => query.bind(*i),
+                        SqlParam::Real(f) => query.bind(*f),
+                        SqlParam::Text(text) => query.bind(text.clone()),
+                        SqlParam::Blob(blob) => query.bind(blob.clone()),
+                    })
+            }
+
+            fn read_id(row: &<$db as Database>::Row) -> Result<I, Error> {
+                use sqlx::Row;
+                let key = match I::INTEGER {
+                    true => Key::Integer(row.try_get("id")?),
+                    false => Key::Text(row.try_get("id")?),
+                };
+                I::from_key(key).map_err(Error::Decode)
+            }
+
+            /// Insert `value`, returning its id.
+            pub async fn insert_one(&self, value: &T) -> Result<I, Error> {
+                self.insert(&self.pool, value).await
+            }
+
+            /// Insert every value in a single transaction, returning their ids in order.
+            pub async fn insert_many<'v>(
+                &self,
+                values: impl IntoIterator<Item = &'v T>,
+            ) -> Result<Vec<I>, Error>
+            where
+                T: 'v,
+            {
+                let mut tx = self.pool.begin().await?;
+                let mut ids = Vec::new();
+                for value in values {
+                    ids.push(self.insert(&mut *tx, value).await?);
+                }
+                tx.commit().await?;
+                Ok(ids)
+            }
+
+            async fn insert<'e>(
+                &self,
+                executor: impl sqlx::Executor<'e, Database = $db>,
+                value: &T,
+            ) -> Result<I, Error> {
+                use sqlx::Row;
+                let data = DBson::new(value);
+                match I::generate() {
+                    Some(id) => {
+                        let sql = format!(
+                            "INSERT INTO {} (id, data) VALUES ({}, {})",
+                            self.table,
+                            Self::placeholder(1),
+                            Self::placeholder(2),
+                        );
+                        Self::bind_key(sqlx::query(&sql), Some(id.to_key()))
+                            .bind(data)
+                            .execute(executor)
+                            .await?;
+                        Ok(id)
+                    }
+                    None => {
+                        let sql = format!(
+                            "INSERT INTO {} (data) VALUES ({}) RETURNING id",
+                            self.table,
+                            Self::placeholder(1),
+                        );
+                        let row = sqlx::query(&sql).bind(data).fetch_one(executor).await?;
+                        I::from_key(Key::Integer(row.try_get("id")?)).map_err(Error::Decode)
+                    }
+                }
+            }
+
+            /// The value with the id `id`.
+            pub async fn get(&self, id: &I) -> Result<Option<T>, Error> {
+                let sql = format!(
+                    "SELECT data FROM {} WHERE id = {}",
+                    self.table,
+                    Self::placeholder(1)
+                );
+                let data: Option<DBson<T>> = Self::bind_key(sqlx::query(&sql), Some(id.to_key()))
+                    .fetch_optional(&self.pool)
+                    .await?
+                    .map(|row| sqlx::Row::try_get(&row, "data"))
+                    .transpose()?;
+                Ok(data.map(DBson::into_inner))
+            }
+
+            /// Every value matching `filter` with its id, in id order.
+            pub fn find(
+                &self,
+                filter: &Document,
+            ) -> Result<BoxStream<'static, Result<(I, T), Error>>, Error> {
+                let filter = self.compile(filter)?;
+                let start = (self.clone(), filter, None, false);
+                let batches = stream::try_unfold(start, |(this, filter, after, done)| async move {
+                    if done {
+                        return Ok::<_, Error>(None);
+                    }
+                    let (batch, after, done) = this.batch(&filter, after).await?;
+                    Ok(Some((batch, (this, filter, after, done))))
+                });
+                Ok(batches
+                    .map_ok(|batch| stream::iter(batch.into_iter().map(Ok)))
+                    .try_flatten()
+                    .boxed())
+            }
+
+            /// The next matching values after the id `after`, the id of the last row read and
+            /// whether every row has been read.
+            async fn batch(
+                &self,
+                filter: &SqlFilter,
+                mut after: Option<Key>,
+            ) -> Result<(Vec<(I, T)>, Option<Key>, bool), Error> {
+                use sqlx::Row;
+                let n = filter.params.len();
+                let sql = format!(
+                    "SELECT id, data FROM {} WHERE {} AND ({after} IS NULL OR id > {after}) \
+                     ORDER BY id LIMIT {BATCH_SIZE}",
+                    self.table,
+                    filter.sql,
+                    after = Self::placeholder(n + 1),
+                );
+                // rows filtered out in Rust don't count, so keep reading until something matches
+                loop {
+                    let rows = Self::bind_key(Self::query(&sql, filter), after.take())
+                        .fetch_all(&self.pool)
+                        .await?;
+                    let done = (rows.len() as i64) < BATCH_SIZE;
+                    let mut batch = Vec::new();
+                    for row in &rows {
+                        let id = Self::read_id(row)?;
+                        after = Some(id.to_key());
+                        if filter.residual.is_some()
+                            && !residual_matches(filter.residual.as_ref(), &row.try_get("data")?)
+                        {
+                            continue;
+                        }
+                        let data: DBson<T> = row.try_get("data")?;
+                        batch.push((id, data.into_inner()));
+                    }
+                    if done || !batch.is_empty() {
+                        return Ok((batch, after, done));
+                    }
+                }
+            }
+
+            /// The first value matching `filter`.
+            pub async fn find_one(&self, filter: &Document) -> Result<Option<(I, T)>, Error> {
+                self.find(filter)?.try_next().await
+            }
+
+            /// The number of values matching `filter`.
+            pub async fn count(&self, filter: &Document) -> Result<u64, Error> {
+                let compiled = self.compile(filter)?;
+                if compiled.residual.is_some() {
+                    return self
+                        .find(filter)?
+                        .try_fold(0, |count, _| async move { Ok(count + 1) })
+                        .await;
+                }
+                let sql = format!("SELECT count(*) FROM {} WHERE {}", self.table, compiled.sql);
+                let count: i64 = sqlx::Row::try_get(
+                    &Self::query(&sql, &compiled).fetch_one(&self.pool).await?,
+                    0,
+                )?;
+                Ok(count as u64)
+            }
+
+            /// Apply `update` to the first value matching `filter`, returning the number of
+            /// updated values.
+            pub async fn update_one(
+                &self,
+                filter: &Document,
+                update: &Document,
+            ) -> Result<u64, Error> {
+                self.modify(filter, Some(1), |data| {
+                    data.apply_update(update)
+                        .map_err(|e| Error::Encode(Box::new(e)))
+                })
+                .await
+            }
+
+            /// Apply `update` to every value matching `filter`, returning the number of updated
+            /// values.
+            ///
+            /// Either every value is updated or, if any update fails, none is.
+            pub async fn update_many(
+                &self,
+                filter: &Document,
+                update: &Document,
+            ) -> Result<u64, Error> {
+                self.modify(filter, None, |data| {
+                    data.apply_update(update)
+                        .map_err(|e| Error::Encode(Box::new(e)))
+                })
+                .await
+            }
+
+            /// Replace the first value matching `filter` with `value`, returning the number of
+            /// replaced values.
+            pub async fn replace_one(&self, filter: &Document, value: &T) -> Result<u64, Error> {
+                let replacement = DBson::new(value)
+                    .to_vec()
+                    .map_err(|e| Error::Encode(Box::new(e)))?;
+                self.modify(filter, Some(1), |data| {
+                    *data =
+                        DBson::from_slice(&replacement).map_err(|e| Error::Encode(Box::new(e)))?;
+                    Ok(())
+                })
+                .await
+            }
+
+            /// Delete the first value matching `filter`, returning the number of deleted values.
+            pub async fn delete_one(&self, filter: &Document) -> Result<u64, Error> {
+                self.delete(filter, Some(1)).await
+            }
+
+            /// Delete every value matching `filter`, returning the number of deleted values.
+            pub async fn delete_many(&self, filter: &Document) -> Result<u64, Error> {
+                self.delete(filter, None).await
+            }
+
+            /// The ids and values matching `filter`, up to `limit` of them, read in `tx`.
+            async fn matching(
+                &self,
+                tx: &mut sqlx::Transaction<'static, $db>,
+                filter: &Document,
+                limit: Option<usize>,
+            ) -> Result<Vec<(Key, DBson<T>)>, Error> {
+                use sqlx::Row;
+                let filter = self.compile(filter)?;
+                let sql = format!(
+                    "SELECT id, data FROM {} WHERE {} ORDER BY id{}",
+                    self.table,
+                    filter.sql,
+                    match (limit, &filter.residual) {
+                        (Some(limit), None) => format!(" LIMIT {limit}"),
+                        _ => String::new(),
+                    }
+                );
+                let mut rows = Self::query(&sql, &filter).fetch(&mut **tx);
+                let mut found = Vec::new();
+                while let Some(row) = rows.try_next().await? {
+                    if limit.is_some_and(|limit| found.len() >= limit) {
+                        break;
+                    }
+                    if !residual_matches(filter.residual.as_ref(), &row.try_get("data")?) {
+                        continue;
+                    }
+                    found.push((Self::read_id(&row)?.to_key(), row.try_get("data")?));
+                }
+                Ok(found)
+            }
+
+            async fn modify(
+                &self,
+                filter: &Document,
+                limit: Option<usize>,
+                change: impl Fn(&mut DBson<T>) -> Result<(), Error>,
+            ) -> Result<u64, Error> {
+                let mut tx = self.pool.begin().await?;
+                let found = self.matching(&mut tx, filter, limit).await?;
+                let sql = format!(
+                    "UPDATE {} SET data = {} WHERE id = {}",
+                    self.table,
+                    Self::placeholder(1),
+                    Self::placeholder(2),
+                );
+                let modified = found.len() as u64;
+                for (key, mut data) in found {
+                    change(&mut data)?;
+                    Self::bind_key(sqlx::query(&sql).bind(data), Some(key))
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                tx.commit().await?;
+                Ok(modified)
+            }
+
+            async fn delete(&self, filter: &Document, limit: Option<usize>) -> Result<u64, Error> {
+                let mut tx = self.pool.begin().await?;
+                let found = self.matching(&mut tx, filter, limit).await?;
+                let sql = format!(
+                    "DELETE FROM {} WHERE id = {}",
+                    self.table,
+                    Self::placeholder(1)
+                );
+                let deleted = found.len() as u64;
+                for (key, _) in found {
+                    Self::bind_key(sqlx::query(&sql), Some(key))
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                tx.commit().await?;
+                Ok(deleted)
+            }
+        }
+    };
+}
+
+impl_collection!(sqlx::Sqlite, "sqlx-sqlite", "?");
+
+impl_collection!(sqlx::Postgres, "sqlx-postgres", "$");
+
+#[cfg(feature = "sqlx-sqlite")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sqlx-sqlite")))]
+impl<T, I> Collection<sqlx::Sqlite, T, I> {
+    /// Create `index` if the collection doesn't have an index of that name yet, returning its
+    /// name, like the rusqlite
+    #[cfg_attr(
+        feature = "rusqlite-functions",
+        doc = "[`Collection::create_index`](crate::rusqlite::Collection::create_index)."
+    )]
+    #[cfg_attr(
+        not(feature = "rusqlite-functions"),
+        doc = "`Collection::create_index`."
+    )]
+    ///
+    /// The indexes are built from the bson functions, so every connection of the pool needs
+    /// them, see [`register_functions`].
+    pub async fn create_index(&self, index: &crate::filter::Index) -> Result<String, Error> {
+        if !self.sql_filters {
+            let message = "indexes need the bson functions on every connection of the pool";
+            return Err(Error::Configuration(message.into()));
+        }
+        let name = index.name_or_default();
+        let sql = index
+            .create_sql(&self.index_identifier(&name), &self.table, "data")
+            .map_err(filter_error)?;
+        sqlx::query(&sql).execute(&self.pool).await?;
+        Ok(name)
+    }
+
+    /// Drop the index `name`, doing nothing if there's no such index.
+    pub async fn drop_index(&self, name: &str) -> Result<(), Error> {
+        let sql = format!("DROP INDEX IF EXISTS {}", self.index_identifier(name));
+        sqlx::query(&sql).execute(&self.pool).await.map(drop)
+    }
+
+    /// The names of the indexes created with [`create_index`](Self::create_index).
+    pub async fn list_index_names(&self) -> Result<Vec<String>, Error> {
+        let prefix = format!("{}_", self.name);
+        let names: Vec<String> = sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = ?1 ORDER BY name",
+        )
+        .bind(&self.name)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(names
+            .into_iter()
+            .filter_map(|name| name.strip_prefix(&prefix).map(str::to_string))
+            .collect())
+    }
+
+    fn index_identifier(&self, name: &str) -> String {
+        let name = format!("{}_{name}", self.name);
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+/// Bind every [`Param`](sealed::Param) to `query`.
+#[cfg(any(feature = "zstd", feature = "encryption"))]
+macro_rules! bind_params {
+    ($query: expr, $params: expr) => {
+        $params.iter().fold($query, |query, param| match *param {
+            sealed::Param::Integer(i) => query.bind(i),
+            sealed::Param::Text(text) => query.bind(text),
+            sealed::Param::Blob(blob) => query.bind(blob),
+        })
+    };
+}
+
+#[cfg(feature = "sqlx-sqlite")]
+impl sealed::Backend for sqlx::Sqlite {
+    const DATA_COLUMN: &'static str = "BLOB";
+    const INTEGER_ID_COLUMN: &'static str = "id INTEGER PRIMARY KEY";
+    #[cfg(feature = "zstd")]
+    const ROW_ORDER: &'static str = "rowid";
+
+    async fn execute(pool: &Pool<Self>, sql: &str) -> Result<(), Error> {
+        sqlx::query(sql).execute(pool).await.map(drop)
+    }
+
+    #[cfg(any(feature = "zstd", feature = "encryption"))]
+    async fn fetch_blobs(
+        pool: &Pool<Self>,
+        sql: &str,
+        params: &[Param<'_>],
+    ) -> Result<Vec<(i64, Vec<u8>)>, Error> {
+        bind_params!(sqlx::query_as(sql), params)
+            .fetch_all(pool)
+            .await
+    }
+
+    #[cfg(any(feature = "zstd", feature = "encryption"))]
+    async fn execute_with(
+        connection: &mut Self::Connection,
+        sql: &str,
+        params: &[Param<'_>],
+    ) -> Result<u64, Error> {
+        let done = bind_params!(sqlx::query(sql), params)
+            .execute(connection)
+            .await?;
+        Ok(done.rows_affected())
+    }
+
+    async fn has_functions(pool: &Pool<Self>) -> bool {
+        Self::execute(pool, "SELECT bson_valid(NULL)").await.is_ok()
+    }
+}
+
+#[cfg(feature = "sqlx-postgres")]
+impl sealed::Backend for sqlx::Postgres {
+    const DATA_COLUMN: &'static str = "BYTEA";
+    const INTEGER_ID_COLUMN: &'static str =
+        "id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY";
+    #[cfg(feature = "zstd")]
+    const ROW_ORDER: &'static str = "ctid";
+
+    async fn execute(pool: &Pool<Self>, sql: &str) -> Result<(), Error> {
+        sqlx::query(sql).execute(pool).await.map(drop)
+    }
+
+    #[cfg(any(feature = "zstd", feature = "encryption"))]
+    async fn fetch_blobs(
+        pool: &Pool<Self>,
+        sql: &str,
+        params: &[Param<'_>],
+    ) -> Result<Vec<(i64, Vec<u8>)>, Error> {
+        bind_params!(sqlx::query_as(sql), params)
+            .fetch_all(pool)
+            .await
+    }
+
+    #[cfg(any(feature = "zstd", feature = "encryption"))]
+    async fn execute_with(
+        connection: &mut Self::Connection,
+        sql: &str,
+        params: &[Param<'_>],
+    ) -> Result<u64, Error> {
+        let done = bind_params!(sqlx::query(sql), params)
+            .execute(connection)
+            .await?;
+        Ok(done.rows_affected())
+    }
+
+    /// PostgreSQL can't look inside of the `bytea` column, filters are always applied in Rust.
+    async fn has_functions(_pool: &Pool<Self>) -> bool {
+        false
+    }
+}
@@ -0,0 +1,152 @@
+//! BSON blobs seen as plain documents, without knowing the wrapped type.
+
+use bson::{Bson, Document};
+use std::fmt;
+
+/// Key of the envelope written by [`DBson`](crate::DBson).
+pub(crate) const ENVELOPE_KEY: &str = "inner";
+
+/// How a value is laid out in its blob, see [`DBson`](crate::DBson) and
+/// [`DBsonDoc`](crate::DBsonDoc).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Layout {
+    Envelope,
+    Bare,
+}
+
+/// The value stored in a blob, with the envelope taken off.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Stored {
+    pub(crate) value: Bson,
+    pub(crate) layout: Layout,
+}
+
+#[derive(Debug)]
+pub(crate) enum StoredError {
+    Deserialize(bson::de::Error),
+    Serialize(bson::ser::Error),
+    TrailingBytes(usize),
+    NotADocument(&'static str),
+    NotAnEnvelope,
+    Decompress(crate::codec::DecompressError),
+}
+
+impl fmt::Display for StoredError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Deserialize(e) => write!(f, "invalid bson document: {e}"),
+            Self::Serialize(e) => write!(f, "unable to write bson document: {e}"),
+            Self::TrailingBytes(len) => write!(f, "{len} trailing bytes after the bson document"),
+            Self::NotADocument(kind) => write!(
+                f,
+                "a bare bson blob has to be a document or an array, not {kind}"
+            ),
+            Self::NotAnEnvelope => write!(
+                f,
+                "an enveloped bson blob has to be a document with `{ENVELOPE_KEY}` as its only key"
+            ),
+            Self::Decompress(e) => write!(f, "unable to decompress the blob: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoredError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Deserialize(e) => Some(e),
+            Self::Serialize(e) => Some(e),
+            Self::Decompress(e) => Some(e),
+            Self::TrailingBytes(_) | Self::NotADocument(_) | Self::NotAnEnvelope => None,
+        }
+    }
+}
+
+impl Stored {
+    pub(crate) fn new(value: Bson, layout: Layout) -> Self {
+        Self { value, layout }
+    }
+
+    /// Read a complete BSON document, rejecting anything after it.
+    ///
+    /// Blobs written by [`Compressed`](crate::codec::Compressed) are decompressed first.
+    pub(crate) fn document(bytes: &[u8]) -> Result<Document, StoredError> {
+        let bytes =
+            crate::codec::compression::decompress(bytes).map_err(StoredError::Decompress)?;
+        let mut reader = &*bytes;
+        let doc = Document::from_reader(&mut reader).map_err(StoredError::Deserialize)?;
+        if !reader.is_empty() {
+            return Err(StoredError::TrailingBytes(reader.len()));
+        }
+        Ok(doc)
+    }
+
+    /// Read a blob known to be written in `layout`, which comes from the wrapper type.
+    pub(crate) fn read(bytes: &[u8], layout: Layout) -> Result<Self, StoredError> {
+        let mut doc = Self::document(bytes)?;
+        let value = match layout {
+            Layout::Envelope if doc.len() == 1 => {
+                doc.remove(ENVELOPE_KEY).ok_or(StoredError::NotAnEnvelope)?
+            }
+            Layout::Envelope => return Err(StoredError::NotAnEnvelope),
+            Layout::Bare => Bson::Document(doc),
+        };
+        Ok(Self::new(value, layout))
+    }
+
+    /// Read a blob of either layout, for the sql functions which don't know the wrapper type.
+    ///
+    /// A document with `inner` as its only key is taken to be an envelope.
+    #[cfg(feature = "rusqlite-functions")]
+    pub(crate) fn from_slice(bytes: &[u8]) -> Result<Self, StoredError> {
+        let mut doc = Self::document(bytes)?;
+        if doc.len() == 1 {
+            if let Some(value) = doc.remove(ENVELOPE_KEY) {
+                return Ok(Self::new(value, Layout::Envelope));
+            }
+        }
+        Ok(Self::new(Bson::Document(doc), Layout::Bare))
+    }
+
+    pub(crate) fn to_vec(&self) -> Result<Vec<u8>, StoredError> {
+        let doc = match (&self.value, self.layout) {
+            (value, Layout::Envelope) => bson::doc! { ENVELOPE_KEY: value.clone() },
+            (Bson::Document(doc), Layout::Bare) => doc.clone(),
+            (Bson::Array(array), Layout::Bare) => array
+                .iter()
+                .enumerate()
+                .map(|(index, value)| (index.to_string(), value.clone()))
+                .collect(),
+            (value, Layout::Bare) => return Err(StoredError::NotADocument(type_name(value))),
+        };
+        let mut bytes = Vec::new();
+        doc.to_writer(&mut bytes).map_err(StoredError::Serialize)?;
+        Ok(bytes)
+    }
+}
+
+/// The name MongoDB's `$type` operator uses for the type of `value`.
+pub(crate) fn type_name(value: &Bson) -> &'static str {
+    match value {
+        Bson::Double(_) => "double",
+        Bson::String(_) => "string",
+        Bson::Document(_) => "object",
+        Bson::Array(_) => "array",
+        Bson::Binary(_) => "binData",
+        Bson::Undefined => "undefined",
+        Bson::ObjectId(_) => "objectId",
+        Bson::Boolean(_) => "bool",
+        Bson::DateTime(_) => "date",
+        Bson::Null => "null",
+        Bson::RegularExpression(_) => "regex",
+        Bson::DbPointer(_) => "dbPointer",
+        Bson::JavaScriptCode(_) => "javascript",
+        Bson::Symbol(_) => "symbol",
+        Bson::JavaScriptCodeWithScope(_) => "javascriptWithScope",
+        Bson::Int32(_) => "int",
+        Bson::Timestamp(_) => "timestamp",
+        Bson::Int64(_) => "long",
+        Bson::Decimal128(_) => "decimal",
+        Bson::MinKey => "minKey",
+        Bson::MaxKey => "maxKey",
+    }
+}
@@ -0,0 +1,399 @@
+//! MongoDB [update operators](https://www.mongodb.com/docs/manual/reference/operator/update/)
+//! applied to stored values.
+//!
+//! Supports `$set`, `$unset`, `$inc`, `$mul`, `$min`, `$max`, `$rename`, `$push` (with `$each`,
+//! `$position` and `$slice`), `$addToSet` (with `$each`) and `$pull` (with a value or a
+//! [filter](crate::filter) condition). Fields are addressed with
+//! dotted paths (`"address.city"`, `"tags.0"`) and missing documents along the path are created,
+//! positional operators (`$`, `$[]`) aren't supported. Setting an index past the end of an array
+//! pads it with nulls, at most 10 000 of them.
+
+use crate::codec::BoxError;
+use crate::filter::{self, ElemMatch};
+use crate::order::{self, Number};
+use crate::stored::{self, Layout, Stored};
+use crate::{codec, Error};
+use bson::{Bson, Document};
+use serde::{de::DeserializeOwned, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// The most nulls an update pads an array with to reach an index past its end, an update going
+/// further fails rather than allocating the gap.
+pub(crate) const MAX_PADDING: usize = 10_000;
+
+/// A malformed update document or an operator that can't be applied to the stored value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UpdateError {
+    /// The field the operator was applied to.
+    pub(crate) path: Option<String>,
+    pub(crate) message: String,
+}
+
+impl UpdateError {
+    fn new(path: &str, message: impl Into<String>) -> Self {
+        Self {
+            path: Some(path.to_string()),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+/// Apply `update` to a wrapper of `T` stored in `layout`, returning the updated wrapper.
+///
+/// The value goes through the bson codec both ways, so the result is checked against `T`.
+pub(crate) fn apply_to<T: ?Sized, W: Serialize + DeserializeOwned>(
+    wrapper: &W,
+    layout: Layout,
+    update: &Document,
+) -> Result<W, Error> {
+    let bytes = codec::encode::<T, codec::Bson>(wrapper)?;
+    let failed = |source: BoxError, path| Error::Update {
+        type_name: std::any::type_name::<T>(),
+        path,
+        source,
+    };
+    let mut stored = Stored::read(&bytes, layout).map_err(|e| failed(e.into(), None))?;
+    apply(&mut stored.value, update).map_err(|e| failed(e.message.clone().into(), e.path))?;
+    let bytes = stored.to_vec().map_err(|e| failed(e.into(), None))?;
+    codec::decode::<T, codec::Bson, W>(&bytes)
+}
+
+/// Apply every operator of `update` to `value`, in order.
+pub(crate) fn apply(value: &mut Bson, update: &Document) -> Result<(), UpdateError> {
+    if update.is_empty() {
+        return Err(UpdateError {
+            path: None,
+            message: "the update document is empty".into(),
+        });
+    }
+    for (operator, fields) in update {
+        let Bson::Document(fields) = fields else {
+            return Err(UpdateError {
+                path: None,
+                message: format!("the fields of `{operator}` have to be a document"),
+            });
+        };
+        for (path, argument) in fields {
+            apply_operator(value, operator, path, argument)?;
+        }
+    }
+    Ok(())
+}
+
+fn apply_operator(
+    value: &mut Bson,
+    operator: &str,
+    path: &str,
+    argument: &Bson,
+) -> Result<(), UpdateError> {
+    let error = |message: String| UpdateError::new(path, message);
+    let keys = split(path)?;
+    match operator {
+        "$set" => *field(value, &keys)? = argument.clone(),
+        "$unset" => {
+            unset(value, &keys);
+        }
+        "$inc" | "$mul" => {
+            let Some(operand) = Number::new(argument) else {
+                return Err(error(format!(
+                    "`{operator}` needs a number, found {}",
+                    type_of(argument)
+                )));
+            };
+            // a missing field is set to the increment, or to a zero of the multiplier's type,
+            // while an existing one has to be a number, even if it's null
+            let missing = existing(value, &keys).is_none();
+            let field = field(value, &keys)?;
+            let current = match missing {
+                true if operator == "$inc" => zero_like(argument),
+                true => {
+                    *field = zero_like(argument);
+                    return Ok(());
+                }
+                false => field.clone(),
+            };
+            *field = arithmetic(operator, &current, argument, operand).map_err(error)?;
+        }
+        "$min" | "$max" => {
+            let wanted = if operator == "$min" {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            };
+            // an existing null is compared like any other value, it sorts before everything
+            let missing = existing(value, &keys).is_none();
+            let field = field(value, &keys)?;
+            if missing || order::compare(argument, field) == wanted {
+                *field = argument.clone();
+            }
+        }
+        "$rename" => {
+            let Bson::String(to) = argument else {
+                return Err(error(format!(
+                    "`$rename` needs a string, found {}",
+                    type_of(argument)
+                )));
+            };
+            if let Some(moved) = unset(value, &keys) {
+                *field(value, &split(to)?)? = moved;
+            }
+        }
+        "$push" | "$addToSet" => {
+            let Each {
+                items,
+                position,
+                slice,
+            } = each(operator, argument).map_err(error)?;
+            let array = array_field(value, &keys, operator).map_err(error)?;
+            let items = if operator == "$addToSet" {
+                let mut unique = Vec::new();
+                for item in items {
+                    let known = |other: &Bson| filter::equals(other, &item);
+                    if !array.iter().any(known) && !unique.iter().any(known) {
+                        unique.push(item);
+                    }
+                }
+                unique
+            } else {
+                items
+            };
+            let position = position.map_or(array.len(), |p| clamp_index(p, array.len()));
+            array.splice(position..position, items);
+            if let Some(slice) = slice {
+                if slice >= 0 {
+                    array.truncate(slice as usize);
+                } else {
+                    let keep = slice.unsigned_abs() as usize;
+                    array.drain(..array.len().saturating_sub(keep));
+                }
+            }
+        }
+        "$pull" => {
+            let condition = match argument {
+                Bson::Document(condition) => {
+                    Some(ElemMatch::new(condition).map_err(|e| error(e.to_string()))?)
+                }
+                _ => None,
+            };
+            if let Some(Bson::Array(array)) = existing(value, &keys) {
+                array.retain(|item| match &condition {
+                    Some(condition) => !condition.matches(item),
+                    None => !filter::equals(item, argument),
+                });
+            }
+        }
+        operator if operator.starts_with('$') => {
+            return Err(UpdateError {
+                path: None,
+                message: format!("unsupported update operator `{operator}`"),
+            })
+        }
+        key => {
+            return Err(UpdateError {
+                path: None,
+                message: format!(
+                    "update documents can only hold operators, found the field `{key}`"
+                ),
+            })
+        }
+    }
+    Ok(())
+}
+
+fn type_of(value: &Bson) -> &'static str {
+    stored::type_name(value)
+}
+
+fn split(path: &str) -> Result<Vec<&str>, UpdateError> {
+    let keys: Vec<_> = path.split('.').collect();
+    if keys
+        .iter()
+        .any(|key| key.is_empty() || key.starts_with('$'))
+    {
+        return Err(UpdateError::new(
+            path,
+            format!("`{path}` isn't a valid field path"),
+        ));
+    }
+    Ok(keys)
+}
+
+/// The field at `keys`, creating it (as `null`) and any missing documents on the way.
+fn field<'a>(mut value: &'a mut Bson, keys: &[&str]) -> Result<&'a mut Bson, UpdateError> {
+    for (depth, key) in keys.iter().enumerate() {
+        let last = depth + 1 == keys.len();
+        let missing = || match last {
+            true => Bson::Null,
+            false => Bson::Document(Document::new()),
+        };
+        value = match value {
+            Bson::Document(doc) => doc.entry(key.to_string()).or_insert_with(missing),
+            Bson::Array(array) => {
+                let index: usize = key.parse().map_err(|_| {
+                    UpdateError::new(&keys.join("."), format!("`{key}` isn't an array index"))
+                })?;
+                if index - array.len().min(index) > MAX_PADDING {
+                    return Err(UpdateError::new(
+                        &keys.join("."),
+                        format!(
+                            "`{key}` is more than {MAX_PADDING} past the end of an array of {}",
+                            array.len()
+                        ),
+                    ));
+                }
+                if index >= array.len() {
+                    array.resize(index, Bson::Null);
+                    array.push(missing());
+                }
+                &mut array[index]
+            }
+            other => {
+                return Err(UpdateError::new(
+                    &keys.join("."),
+                    format!("can't create the field `{key}` in a {}", type_of(other)),
+                ))
+            }
+        };
+    }
+    Ok(value)
+}
+
+/// The field at `keys`, if it exists.
+fn existing<'a>(mut value: &'a mut Bson, keys: &[&str]) -> Option<&'a mut Bson> {
+    for key in keys {
+        value = match value {
+            Bson::Document(doc) => doc.get_mut(*key)?,
+            Bson::Array(array) => array.get_mut(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Remove the field at `keys`. Array elements are set to `null` to keep the other indexes.
+fn unset(value: &mut Bson, keys: &[&str]) -> Option<Bson> {
+    let (last, parent) = keys.split_last()?;
+    match existing(value, parent)? {
+        Bson::Document(doc) => doc.remove(*last),
+        Bson::Array(array) => {
+            let item = array.get_mut(last.parse::<usize>().ok()?)?;
+            Some(std::mem::replace(item, Bson::Null))
+        }
+        _ => None,
+    }
+}
+
+fn array_field<'a>(
+    value: &'a mut Bson,
+    keys: &[&str],
+    operator: &str,
+) -> Result<&'a mut Vec<Bson>, String> {
+    let field = field(value, keys).map_err(|e| e.message)?;
+    if field == &Bson::Null {
+        *field = Bson::Array(Vec::new());
+    }
+    match field {
+        Bson::Array(array) => Ok(array),
+        other => Err(format!(
+            "`{operator}` needs an array, found {}",
+            type_of(other)
+        )),
+    }
+}
+
+/// The argument of `$push` or `$addToSet`, either a single item or `$each` with its modifiers.
+struct Each {
+    items: Vec<Bson>,
+    position: Option<i64>,
+    slice: Option<i64>,
+}
+
+fn each(operator: &str, argument: &Bson) -> Result<Each, String> {
+    let Some(Bson::Array(items)) = argument.as_document().and_then(|doc| doc.get("$each")) else {
+        return Ok(Each {
+            items: vec![argument.clone()],
+            position: None,
+            slice: None,
+        });
+    };
+    let mut position = None;
+    let mut slice = None;
+    for (modifier, value) in argument.as_document().into_iter().flatten() {
+        let number = || match Number::new(value) {
+            Some(Number::Int(i)) => Ok(i),
+            _ => Err(format!("`{modifier}` needs an integer")),
+        };
+        match modifier.as_str() {
+            "$each" => {}
+            "$position" if operator == "$push" => position = Some(number()?),
+            "$slice" if operator == "$push" => slice = Some(number()?),
+            modifier => return Err(format!("unsupported `{operator}` modifier `{modifier}`")),
+        }
+    }
+    Ok(Each {
+        items: items.clone(),
+        position,
+        slice,
+    })
+}
+
+/// Turn a `$position`, which counts from the end when negative, into an index.
+fn clamp_index(position: i64, len: usize) -> usize {
+    if position >= 0 {
+        (position as usize).min(len)
+    } else {
+        len.saturating_sub(position.unsigned_abs() as usize)
+    }
+}
+
+fn zero_like(value: &Bson) -> Bson {
+    match value {
+        Bson::Int32(_) => Bson::Int32(0),
+        Bson::Int64(_) => Bson::Int64(0),
+        _ => Bson::Double(0.0),
+    }
+}
+
+/// `current + operand` or `current * operand`, widening `int` to `long` on overflow.
+fn arithmetic(
+    operator: &str,
+    current: &Bson,
+    argument: &Bson,
+    operand: Number,
+) -> Result<Bson, String> {
+    let Some(number) = Number::new(current).filter(|_| !matches!(current, Bson::Decimal128(_)))
+    else {
+        return Err(format!(
+            "`{operator}` can't be applied to a {}",
+            type_of(current)
+        ));
+    };
+    let add = operator == "$inc";
+    Ok(match (number, operand) {
+        (Number::Int(a), Number::Int(b)) => {
+            let result = if add {
+                a.checked_add(b)
+            } else {
+                a.checked_mul(b)
+            };
+            let result = result.ok_or_else(|| format!("`{operator}` overflowed"))?;
+            match (current, argument) {
+                (Bson::Int32(_), Bson::Int32(_)) => {
+                    i32::try_from(result).map_or(Bson::Int64(result), Bson::Int32)
+                }
+                _ => Bson::Int64(result),
+            }
+        }
+        (a, b) if add => Bson::Double(a.as_f64() + b.as_f64()),
+        (a, b) => Bson::Double(a.as_f64() * b.as_f64()),
+    })
+}
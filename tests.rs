@@ -1,5 +1,8 @@
 macro_rules! rusqlite_test {
     ($val: expr, $type: ty) => {
+        rusqlite_test!($val, $type, dbson::codec::Bson);
+    };
+    ($val: expr, $type: ty, $codec: ty) => {
         let data = $val;
         let conn =
             rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
@@ -10,10 +13,10 @@ macro_rules! rusqlite_test {
         .expect("unable to execute");
         conn.execute(
             "insert into test (data) values (?)",
-            [dbson::DBson::new(&data)],
+            [dbson::DBson::<_, $codec>::with_codec(&data)],
         )
         .expect("Unable to insert data");
-        let query_data: dbson::DBson<$type> = conn
+        let query_data: dbson::DBson<$type, $codec> = conn
             .query_row("select data from test", [], |row| row.get(0))
             .expect("Unable to query data");
         let qdata = query_data.into_inner();
@@ -23,7 +26,7 @@ macro_rules! rusqlite_test {
 
 #[test]
 pub fn rusqlite_top_level_test() {
-    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::collections::{HashMap, HashSet};
     rusqlite_test!(vec![1, 2, 3, 4], Vec<u32>);
     rusqlite_test!(
         vec![1, 2, 3, 4].into_iter().collect::<HashSet<u32>>(),
@@ -39,9 +42,8 @@ pub fn rusqlite_top_level_test() {
 }
 
 #[test]
-#[should_panic]
 pub fn rusqlite_top_level_test_documents() {
-    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::collections::{BTreeMap, HashMap};
     rusqlite_test!(
         vec![(1, "Hello"), (2, "World"), (3, "Never"), (4, "Gonna")]
             .into_iter()
@@ -49,10 +51,71 @@ pub fn rusqlite_top_level_test_documents() {
             .collect::<BTreeMap<u32, String>>(),
         BTreeMap<u32, String>
     );
+    rusqlite_test!(
+        vec![(-1, vec![(true, 'a')]), (2, vec![(false, 'b')])]
+            .into_iter()
+            .map(|(n, v)| (n, v.into_iter().collect::<HashMap<bool, char>>()))
+            .collect::<BTreeMap<i64, HashMap<bool, char>>>(),
+        BTreeMap<i64, HashMap<bool, char>>
+    );
+}
+
+#[test]
+pub fn rusqlite_top_level_test_scalars() {
+    rusqlite_test!(42u32, u32);
+    rusqlite_test!(String::from("Hello"), String);
+    rusqlite_test!((), ());
+    rusqlite_test!(Some(1.5f64), Option<f64>);
+    rusqlite_test!(
+        (1u8, String::from("two"), vec![3i64]),
+        (u8, String, Vec<i64>)
+    );
+    rusqlite_test!(
+        (
+            bson::oid::ObjectId::new(),
+            bson::DateTime::from_millis(1_700_000_000_000)
+        ),
+        (bson::oid::ObjectId, bson::DateTime)
+    );
+}
+
+#[test]
+pub fn rusqlite_top_level_test_bare_layout() {
+    use std::collections::BTreeMap;
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    conn.execute("create table test (id integer primary key, data blob)", [])
+        .expect("unable to execute");
+    let list = vec![1u32, 2, 3, 4];
+    let tuple = (1u32, String::from("Hello"));
+    let map = BTreeMap::from([(1u32, vec![1u32]), (2, vec![2, 2])]);
+    conn.execute(
+        "insert into test (id, data) values (1, ?), (2, ?), (3, ?)",
+        rusqlite::params![
+            dbson::DBsonDoc::new(&list),
+            dbson::DBsonDoc::new(&tuple),
+            dbson::DBsonDoc::new(&map)
+        ],
+    )
+    .expect("Unable to insert data");
+    let qlist: dbson::DBsonDoc<Vec<u32>> = conn
+        .query_row("select data from test where id = 1", [], |row| row.get(0))
+        .expect("Unable to query data");
+    let qtuple: dbson::DBsonDoc<(u32, String)> = conn
+        .query_row("select data from test where id = 2", [], |row| row.get(0))
+        .expect("Unable to query data");
+    let qmap: dbson::DBsonDoc<BTreeMap<u32, Vec<u32>>> = conn
+        .query_row("select data from test where id = 3", [], |row| row.get(0))
+        .expect("Unable to query data");
+    assert_eq!(qlist.into_inner(), list);
+    assert_eq!(qtuple.into_inner(), tuple);
+    assert_eq!(qmap.into_inner(), map);
 }
 
 macro_rules! sqlx_test {
     ($val: expr, $type: ty) => {
+        sqlx_test!($val, $type, dbson::codec::Bson);
+    };
+    ($val: expr, $type: ty, $codec: ty) => {
         let data = $val;
         let mut conn = sqlx::sqlite::SqliteConnection::connect("sqlite::memory:")
             .await
@@ -62,11 +125,11 @@ macro_rules! sqlx_test {
             .await
             .expect("unable to execute");
         sqlx::query("insert into test (data) values (?)")
-            .bind(dbson::DBson::new(&data))
+            .bind(dbson::DBson::<_, $codec>::with_codec(&data))
             .execute(&mut conn)
             .await
             .expect("Unable to insert data");
-        let query_data: dbson::DBson<$type> = sqlx::query_scalar("select data from test")
+        let query_data: dbson::DBson<$type, $codec> = sqlx::query_scalar("select data from test")
             .fetch_one(&mut conn)
             .await
             .expect("Unable to query data");
@@ -78,7 +141,7 @@ macro_rules! sqlx_test {
 #[tokio::test]
 pub async fn sqlx_top_level_test() {
     use sqlx::Connection;
-    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::collections::{HashMap, HashSet};
     sqlx_test!(vec![1, 2, 3, 4], Vec<u32>);
     sqlx_test!(
         vec![1, 2, 3, 4].into_iter().collect::<HashSet<u32>>(),
@@ -92,3 +155,2763 @@ pub async fn sqlx_top_level_test() {
         HashMap<String, String>
     );
 }
+
+macro_rules! codec_tests {
+    ($test: ident, $($codec: ty),*) => {
+        use std::collections::{BTreeMap, HashMap};
+        $(
+            $test!(vec![1, 2, 3, 4], Vec<u32>, $codec);
+            $test!(
+                vec![("1", "Hello"), ("2", "World")]
+                    .into_iter()
+                    .map(|(n, w)| (n.to_string(), w.to_string()))
+                    .collect::<HashMap<String, String>>(),
+                HashMap<String, String>,
+                $codec
+            );
+            $test!(
+                vec![(1, "Hello"), (2, "World")]
+                    .into_iter()
+                    .map(|(n, w)| (n, w.to_string()))
+                    .collect::<BTreeMap<u32, String>>(),
+                BTreeMap<u32, String>,
+                $codec
+            );
+        )*
+    };
+}
+
+#[test]
+pub fn rusqlite_codec_test() {
+    use dbson::codec::{
+        Cbor, Compressed, Json, Lz4, Memcomparable, MsgPack, Postcard, Snappy, Zstd,
+    };
+    codec_tests!(
+        rusqlite_test,
+        MsgPack,
+        Cbor,
+        Json,
+        Postcard,
+        Memcomparable,
+        Compressed<Zstd, dbson::codec::Bson, 0>,
+        Compressed<Lz4, MsgPack, 0>,
+        Compressed<Snappy>
+    );
+}
+
+#[tokio::test]
+pub async fn sqlx_codec_test() {
+    use dbson::codec::{
+        Cbor, Compressed, Json, Lz4, Memcomparable, MsgPack, Postcard, Snappy, Zstd,
+    };
+    use sqlx::Connection;
+    codec_tests!(
+        sqlx_test,
+        MsgPack,
+        Cbor,
+        Json,
+        Postcard,
+        Memcomparable,
+        Compressed<Zstd, dbson::codec::Bson, 0>,
+        Compressed<Lz4, MsgPack, 0>,
+        Compressed<Snappy>
+    );
+}
+
+#[test]
+pub fn rusqlite_layout_side_by_side_test() {
+    use std::collections::HashMap;
+    let data = vec![("1", "Hello"), ("2", "World")]
+        .into_iter()
+        .map(|(n, w)| (n.to_string(), w.to_string()))
+        .collect::<HashMap<String, String>>();
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    conn.execute(
+        "create table if not exists test (id integer primary key, data blob)",
+        [],
+    )
+    .expect("unable to execute");
+    conn.execute(
+        "insert into test (id, data) values (1, ?)",
+        [dbson::DBson::new(&data)],
+    )
+    .expect("Unable to insert data");
+    conn.execute(
+        "insert into test (id, data) values (2, ?)",
+        [dbson::DBsonDoc::new(&data)],
+    )
+    .expect("Unable to insert data");
+
+    let enveloped: dbson::DBson<HashMap<String, String>> = conn
+        .query_row("select data from test where id = 1", [], |row| row.get(0))
+        .expect("Unable to query data");
+    let bare: dbson::DBsonDoc<HashMap<String, String>> = conn
+        .query_row("select data from test where id = 2", [], |row| row.get(0))
+        .expect("Unable to query data");
+    assert!(enveloped.into_inner() == data);
+    assert!(bare.into_inner() == data);
+
+    let raw: Vec<u8> = conn
+        .query_row("select data from test where id = 2", [], |row| row.get(0))
+        .expect("Unable to query data");
+    let document: bson::Document = bson::from_slice(&raw).expect("Unable to decode document");
+    assert_eq!(document.get_str("1"), Ok("Hello"));
+    assert!(!document.contains_key("inner"));
+}
+
+#[tokio::test]
+pub async fn sqlx_encode_error_test() {
+    use sqlx::Connection;
+    let mut conn = sqlx::sqlite::SqliteConnection::connect("sqlite::memory:")
+        .await
+        .expect("Unable to open sqlite connection");
+    sqlx::query("create table if not exists test (id integer primary key, data blob)")
+        .execute(&mut conn)
+        .await
+        .expect("unable to execute");
+    // BSON has no unsigned 64 bit integers, so this can't be encoded
+    let result = sqlx::query("insert into test (data) values (?)")
+        .bind(dbson::DBson::new(vec![u64::MAX]))
+        .execute(&mut conn)
+        .await;
+    assert!(result.is_err());
+    let rows: i64 = sqlx::query_scalar("select count(*) from test")
+        .fetch_one(&mut conn)
+        .await
+        .expect("Unable to query data");
+    assert_eq!(rows, 0);
+
+    // A failing bind must not be replaced with NULL in a multi value insert either
+    let result = sqlx::query("insert into test (data) values (?), (?)")
+        .bind(dbson::DBson::new(vec![1u64]))
+        .bind(dbson::DBson::new(vec![u64::MAX]))
+        .execute(&mut conn)
+        .await;
+    assert!(result.is_err());
+    let nulls: i64 = sqlx::query_scalar("select count(*) from test where data is null")
+        .fetch_one(&mut conn)
+        .await
+        .expect("Unable to query data");
+    assert_eq!(nulls, 0);
+}
+
+#[test]
+pub fn rusqlite_encode_error_test() {
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    conn.execute(
+        "create table if not exists test (id integer primary key, data blob)",
+        [],
+    )
+    .expect("unable to execute");
+    let result = conn.execute(
+        "insert into test (data) values (?)",
+        [dbson::DBson::new(vec![u64::MAX])],
+    );
+    assert!(matches!(
+        result,
+        Err(rusqlite::Error::ToSqlConversionFailure(_))
+    ));
+    let rows: i64 = conn
+        .query_row("select count(*) from test", [], |row| row.get(0))
+        .expect("Unable to query data");
+    assert_eq!(rows, 0);
+}
+
+#[test]
+pub fn rusqlite_error_context_test() {
+    use std::collections::HashMap;
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    conn.execute(
+        "create table if not exists test (id integer primary key, data blob)",
+        [],
+    )
+    .expect("unable to execute");
+    let data = HashMap::from([("a".to_string(), vec![1i64, -2])]);
+    conn.execute(
+        "insert into test (data) values (?)",
+        [dbson::DBson::new(&data)],
+    )
+    .expect("Unable to insert data");
+
+    let error = conn
+        .query_row("select data from test", [], |row| {
+            row.get::<_, dbson::DBson<HashMap<String, Vec<u32>>>>(0)
+        })
+        .expect_err("Negative numbers can't be decoded as u32");
+    let rusqlite::Error::FromSqlConversionFailure(_, _, error) = error else {
+        panic!("Unexpected error {error:?}");
+    };
+    let error = error
+        .downcast_ref::<dbson::Error>()
+        .expect("Not a dbson error");
+    let dbson::Error::Deserialize {
+        type_name,
+        codec,
+        len,
+        path,
+        ..
+    } = error
+    else {
+        panic!("Unexpected error {error:?}");
+    };
+    assert_eq!(
+        *type_name,
+        std::any::type_name::<HashMap<String, Vec<u32>>>()
+    );
+    assert_eq!(*codec, "bson");
+    assert!(*len > 0);
+    assert_eq!(path.as_deref(), Some("inner.a[1]"));
+
+    let error = conn
+        .execute(
+            "insert into test (data) values (?)",
+            [dbson::DBson::new(HashMap::from([("b", vec![0, u64::MAX])]))],
+        )
+        .expect_err("u64::MAX can't be encoded");
+    let rusqlite::Error::ToSqlConversionFailure(error) = error else {
+        panic!("Unexpected error {error:?}");
+    };
+    let error = error
+        .downcast_ref::<dbson::Error>()
+        .expect("Not a dbson error");
+    assert!(matches!(error, dbson::Error::Serialize { .. }));
+    assert_eq!(error.path(), Some("inner.b[1]"));
+}
+
+#[tokio::test]
+pub async fn sqlx_error_context_test() {
+    use sqlx::Connection;
+    let mut conn = sqlx::sqlite::SqliteConnection::connect("sqlite::memory:")
+        .await
+        .expect("Unable to open sqlite connection");
+    sqlx::query("create table if not exists test (id integer primary key, data blob)")
+        .execute(&mut conn)
+        .await
+        .expect("unable to execute");
+    sqlx::query("insert into test (data) values (?)")
+        .bind(dbson::DBson::new(vec!["Hello"]))
+        .execute(&mut conn)
+        .await
+        .expect("Unable to insert data");
+    let error = sqlx::query_scalar::<_, dbson::DBson<Vec<u32>>>("select data from test")
+        .fetch_one(&mut conn)
+        .await
+        .expect_err("Strings can't be decoded as u32");
+    let sqlx::Error::ColumnDecode { source, .. } = error else {
+        panic!("Unexpected error {error:?}");
+    };
+    let error = source
+        .downcast_ref::<dbson::Error>()
+        .expect("Not a dbson error");
+    assert_eq!(error.type_name(), std::any::type_name::<Vec<u32>>());
+    assert_eq!(error.path(), Some("inner[0]"));
+}
+
+mod diesel_schema {
+    diesel::table! {
+        test (id) {
+            id -> Integer,
+            data -> Binary,
+        }
+    }
+}
+
+macro_rules! diesel_test {
+    ($val: expr, $type: ty) => {
+        let data = $val;
+        let mut conn =
+            <diesel::sqlite::SqliteConnection as diesel::Connection>::establish(":memory:")
+                .expect("Unable to open sqlite connection");
+        diesel::sql_query("create table if not exists test (id integer primary key, data blob)")
+            .execute(&mut conn)
+            .expect("unable to execute");
+        diesel::insert_into(diesel_schema::test::table)
+            .values(diesel_schema::test::data.eq(dbson::DBson::new(&data)))
+            .execute(&mut conn)
+            .expect("Unable to insert data");
+        let query_data: dbson::DBson<$type> = diesel_schema::test::table
+            .select(diesel_schema::test::data)
+            .first(&mut conn)
+            .expect("Unable to query data");
+        let qdata = query_data.into_inner();
+        assert!(data == qdata);
+    };
+}
+
+#[test]
+pub fn diesel_top_level_test() {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    diesel_test!(vec![1, 2, 3, 4], Vec<u32>);
+    diesel_test!(
+        vec![1, 2, 3, 4].into_iter().collect::<HashSet<u32>>(),
+        HashSet<u32>
+    );
+    diesel_test!(
+        vec![("1", "Hello"), ("2", "World"), ("3", "Never"), ("4", "Gonna")]
+            .into_iter()
+            .map(|(n, w)| (n.to_string(), w.to_string()))
+            .collect::<HashMap<String, String>>(),
+        HashMap<String, String>
+    );
+    diesel_test!(
+        vec![(1, "Hello"), (2, "World")]
+            .into_iter()
+            .map(|(n, w)| (n, w.to_string()))
+            .collect::<BTreeMap<u32, String>>(),
+        BTreeMap<u32, String>
+    );
+}
+
+#[test]
+pub fn diesel_decode_error_test() {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    let mut conn = <diesel::sqlite::SqliteConnection as diesel::Connection>::establish(":memory:")
+        .expect("Unable to open sqlite connection");
+    diesel::sql_query("create table if not exists test (id integer primary key, data blob)")
+        .execute(&mut conn)
+        .expect("unable to execute");
+    diesel::insert_into(diesel_schema::test::table)
+        .values(diesel_schema::test::data.eq(dbson::DBson::new(vec!["Hello"])))
+        .execute(&mut conn)
+        .expect("Unable to insert data");
+    let error = diesel_schema::test::table
+        .select(diesel_schema::test::data)
+        .first::<dbson::DBson<Vec<u32>>>(&mut conn)
+        .expect_err("Strings can't be decoded as u32");
+    let diesel::result::Error::DeserializationError(error) = error else {
+        panic!("Unexpected error {error:?}");
+    };
+    // diesel wraps the error with the name of the field that failed
+    let error = std::error::Error::source(&*error).expect("Missing field error source");
+    assert!(error.downcast_ref::<dbson::Error>().is_some());
+
+    let error = diesel::insert_into(diesel_schema::test::table)
+        .values(diesel_schema::test::data.eq(dbson::DBson::new(vec![u64::MAX])))
+        .execute(&mut conn)
+        .expect_err("u64::MAX can't be encoded");
+    assert!(matches!(
+        error,
+        diesel::result::Error::SerializationError(_)
+    ));
+}
+
+#[test]
+pub fn postgres_types_bytea_test() {
+    use postgres_types::{FromSql, ToSql, Type};
+    use std::collections::BTreeMap;
+    let data = BTreeMap::from([(1u32, vec!["Hello".to_string()]), (2, vec![])]);
+    let mut buf = bytes::BytesMut::new();
+    let value = dbson::DBson::new(&data);
+    assert!(<dbson::DBson<&BTreeMap<u32, Vec<String>>> as ToSql>::accepts(&Type::BYTEA));
+    assert!(!<dbson::DBson<&BTreeMap<u32, Vec<String>>> as ToSql>::accepts(&Type::JSONB));
+    value
+        .to_sql_checked(&Type::BYTEA, &mut buf)
+        .expect("Unable to encode data");
+    assert_eq!(
+        &buf[..],
+        &value.to_vec().expect("Unable to encode data")[..]
+    );
+    let qdata = dbson::DBson::<BTreeMap<u32, Vec<String>>>::from_sql(&Type::BYTEA, &buf)
+        .expect("Unable to decode data");
+    assert_eq!(qdata.into_inner(), data);
+
+    assert!(value.to_sql_checked(&Type::TEXT, &mut buf).is_err());
+    assert!(dbson::DBson::<BTreeMap<u32, Vec<String>>>::from_sql(&Type::BYTEA, b"junk").is_err());
+}
+
+#[test]
+pub fn postgres_types_jsonb_test() {
+    use dbson::codec::Json;
+    use postgres_types::{FromSql, ToSql, Type};
+    use std::collections::HashMap;
+    let data = HashMap::from([("key".to_string(), vec![1u64, 2, 3])]);
+    let value = dbson::DBsonDoc::<_, Json>::with_codec(&data);
+    let mut buf = bytes::BytesMut::new();
+    value
+        .to_sql_checked(&Type::JSONB, &mut buf)
+        .expect("Unable to encode data");
+    assert_eq!(&buf[..], b"\x01{\"key\":[1,2,3]}");
+    let qdata = dbson::DBsonDoc::<HashMap<String, Vec<u64>>, Json>::from_sql(&Type::JSONB, &buf)
+        .expect("Unable to decode data");
+    assert_eq!(qdata.into_inner(), data);
+
+    buf.clear();
+    value
+        .to_sql_checked(&Type::JSON, &mut buf)
+        .expect("Unable to encode data");
+    assert_eq!(&buf[..], b"{\"key\":[1,2,3]}");
+    assert!(
+        dbson::DBsonDoc::<HashMap<String, Vec<u64>>, Json>::from_sql(&Type::JSONB, b"\x02{}")
+            .is_err()
+    );
+}
+
+#[test]
+pub fn sqlx_postgres_jsonb_test() {
+    use dbson::codec::ExtJson;
+    use sqlx::{postgres::Postgres, Encode, Type, TypeInfo};
+    type Value = dbson::DBson<(bson::oid::ObjectId, i64), ExtJson>;
+    assert_eq!(<Value as Type<Postgres>>::type_info().name(), "JSONB");
+    assert_eq!(
+        <dbson::DBson<Vec<u32>> as Type<Postgres>>::type_info().name(),
+        "BYTEA"
+    );
+
+    let id = bson::oid::ObjectId::new();
+    let mut buf = sqlx::postgres::PgArgumentBuffer::default();
+    let is_null =
+        <Value as Encode<Postgres>>::encode_by_ref(&Value::with_codec((id, 42)), &mut buf)
+            .expect("Unable to encode data");
+    assert!(matches!(is_null, sqlx::encode::IsNull::No));
+    // jsonb values are prefixed with their format version
+    assert_eq!(buf[0], 1);
+    let json: serde_json::Value = serde_json::from_slice(&buf[1..]).expect("Not json");
+    assert_eq!(
+        json,
+        serde_json::json!({ "inner": [{ "$oid": id.to_hex() }, 42] })
+    );
+}
+
+#[tokio::test]
+pub async fn sqlx_sqlite_json_test() {
+    use dbson::codec::ExtJson;
+    use sqlx::Connection;
+    use std::collections::BTreeMap;
+    let data = BTreeMap::from([
+        (1u32, bson::DateTime::from_millis(1_700_000_000_000)),
+        (2, bson::DateTime::from_millis(0)),
+    ]);
+    let mut conn = sqlx::sqlite::SqliteConnection::connect("sqlite::memory:")
+        .await
+        .expect("Unable to open sqlite connection");
+    sqlx::query("create table if not exists test (id integer primary key, data)")
+        .execute(&mut conn)
+        .await
+        .expect("unable to execute");
+    sqlx::query("insert into test (data) values (?)")
+        .bind(dbson::DBsonDoc::<_, ExtJson>::with_codec(&data))
+        .execute(&mut conn)
+        .await
+        .expect("Unable to insert data");
+    let (kind, date): (String, String) =
+        sqlx::query_as("select typeof(data), json_extract(data, '$.2.$date') from test")
+            .fetch_one(&mut conn)
+            .await
+            .expect("Unable to query data");
+    assert_eq!(kind, "text");
+    assert_eq!(date, "1970-01-01T00:00:00Z");
+    let query_data: dbson::DBsonDoc<BTreeMap<u32, bson::DateTime>, ExtJson> =
+        sqlx::query_scalar("select data from test")
+            .fetch_one(&mut conn)
+            .await
+            .expect("Unable to query data");
+    assert_eq!(query_data.into_inner(), data);
+}
+
+#[test]
+pub fn rusqlite_json_test() {
+    use dbson::codec::ExtJson;
+    let id = bson::oid::ObjectId::new();
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    conn.execute("create table test (id integer primary key, data)", [])
+        .expect("unable to execute");
+    conn.execute(
+        "insert into test (data) values (?)",
+        [dbson::DBson::<_, ExtJson>::with_codec(vec![id])],
+    )
+    .expect("Unable to insert data");
+    let oid: String = conn
+        .query_row(
+            "select json_extract(data, '$.inner[0].$oid') from test",
+            [],
+            |row| row.get(0),
+        )
+        .expect("Unable to query data");
+    assert_eq!(oid, id.to_hex());
+    let query_data: dbson::DBson<Vec<bson::oid::ObjectId>, ExtJson> = conn
+        .query_row("select data from test", [], |row| row.get(0))
+        .expect("Unable to query data");
+    assert_eq!(query_data.into_inner(), vec![id]);
+}
+
+#[test]
+pub fn rusqlite_functions_test() {
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    struct User {
+        name: String,
+        tags: Vec<String>,
+        address: Address,
+    }
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    struct Address {
+        city: String,
+        zip: u32,
+    }
+    let user = User {
+        name: "alice".into(),
+        tags: vec!["admin".into(), "ops".into()],
+        address: Address {
+            city: "Paris".into(),
+            zip: 75001,
+        },
+    };
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute("create table test (id integer primary key, data blob)", [])
+        .expect("unable to execute");
+    conn.execute(
+        "insert into test (data) values (?1), (?2)",
+        rusqlite::params![dbson::DBson::new(&user), dbson::DBsonDoc::new(&user)],
+    )
+    .expect("Unable to insert data");
+
+    // the envelope is skipped, so both layouts answer the same
+    let names: Vec<(String, String, i64)> = conn
+        .prepare("select bson_extract(data, '$.name'), bson_extract(data, '$.tags[#-1]'), bson_extract(data, '$.address.zip') from test")
+        .expect("unable to prepare")
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read rows");
+    assert_eq!(names, vec![("alice".into(), "ops".into(), 75001); 2]);
+
+    let (address, tags): (dbson::DBson<Address>, dbson::DBson<Vec<String>>) = conn
+        .query_row(
+            "select bson_extract(data, '$.address'), bson_extract(data, '$.tags') from test where id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .expect("Unable to query data");
+    assert_eq!(address.into_inner(), user.address);
+    assert_eq!(tags.into_inner(), user.tags);
+
+    let (types, missing, valid, invalid): (String, Option<i64>, bool, bool) = conn
+        .query_row(
+            "select bson_type(data) || ',' || bson_type(data, '$.tags') || ',' || bson_type(data, '$.address.zip'),
+                    bson_extract(data, '$.address.street'),
+                    bson_valid(data),
+                    bson_valid(substr(data, 2))
+             from test where id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .expect("Unable to query data");
+    assert_eq!(types, "object,array,long");
+    assert_eq!(missing, None);
+    assert!(valid);
+    assert!(!invalid);
+
+    let (keys, json): (dbson::DBson<Vec<String>>, String) = conn
+        .query_row(
+            "select bson_keys(data, '$.address'), bson_to_json(data, '$.address') from test where id = 2",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .expect("Unable to query data");
+    assert_eq!(keys.into_inner(), vec!["city", "zip"]);
+    assert_eq!(json, r#"{"city":"Paris","zip":75001}"#);
+
+    let error = conn
+        .query_row("select bson_extract(data, 'name') from test", [], |row| {
+            row.get::<_, Option<String>>(0)
+        })
+        .expect_err("malformed paths are rejected");
+    assert!(error.to_string().contains("malformed path"), "{error}");
+}
+
+#[test]
+pub fn rusqlite_each_test() {
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute("create table test (id integer primary key, data blob)", [])
+        .expect("unable to execute");
+    let mut first = std::collections::BTreeMap::new();
+    first.insert("tags".to_string(), vec!["a", "b"]);
+    let mut second = std::collections::BTreeMap::new();
+    second.insert("tags".to_string(), vec!["c"]);
+    conn.execute(
+        "insert into test (data) values (?1), (?2)",
+        rusqlite::params![dbson::DBson::new(first), dbson::DBsonDoc::new(second)],
+    )
+    .expect("Unable to insert data");
+
+    // unnest the arrays of every row
+    let tags: Vec<(i64, i64, String, String)> = conn
+        .prepare(
+            "select test.id, tag.key, tag.value, tag.fullkey
+             from test, bson_each(test.data, '$.tags') as tag
+             order by test.id, tag.key",
+        )
+        .expect("unable to prepare")
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read rows");
+    assert_eq!(
+        tags,
+        vec![
+            (1, 0, "a".into(), "$.tags[0]".into()),
+            (1, 1, "b".into(), "$.tags[1]".into()),
+            (2, 0, "c".into(), "$.tags[0]".into()),
+        ]
+    );
+
+    let tree: Vec<(String, Option<i64>, String, String)> = conn
+        .prepare("select type, parent, fullkey, path from bson_tree((select data from test where id = 1))")
+        .expect("unable to prepare")
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read rows");
+    assert_eq!(
+        tree,
+        vec![
+            ("object".into(), None, "$".into(), "$".into()),
+            ("array".into(), Some(0), "$.tags".into(), "$".into()),
+            (
+                "string".into(),
+                Some(1),
+                "$.tags[0]".into(),
+                "$.tags".into()
+            ),
+            (
+                "string".into(),
+                Some(1),
+                "$.tags[1]".into(),
+                "$.tags".into()
+            ),
+        ]
+    );
+
+    let scalar: Vec<(Option<String>, i64)> = conn
+        .prepare("select key, atom from bson_each(?1, '$.n')")
+        .expect("unable to prepare")
+        .query_map(
+            [dbson::DBsonDoc::new(std::collections::HashMap::from([(
+                "n", 7,
+            )]))],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read rows");
+    assert_eq!(scalar, vec![(Some("n".into()), 7)]);
+}
+
+#[test]
+pub fn rusqlite_constructors_test() {
+    #[derive(serde::Deserialize, PartialEq, Debug)]
+    struct Author {
+        name: String,
+        books: Vec<Book>,
+    }
+    #[derive(serde::Deserialize, PartialEq, Debug)]
+    struct Book {
+        title: String,
+        year: i32,
+        rating: f64,
+    }
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute_batch(
+        "create table author (id integer primary key, name text);
+         create table book (author integer, title text, year integer, rating real);
+         insert into author values (1, 'Ursula'), (2, 'Terry');
+         insert into book values (1, 'The Dispossessed', 1974, 4.5), (1, 'Lathe of Heaven', 1971, 4.0);",
+    )
+    .expect("unable to execute");
+
+    // denormalize the join into one document per author
+    let authors: Vec<dbson::DBson<Author>> = conn
+        .prepare(
+            "select bson_object('name', author.name, 'books', bson_group_array(
+                 bson_object('title', book.title, 'year', book.year, 'rating', book.rating)
+             ))
+             from author join (select * from book order by year) as book on book.author = author.id
+             group by author.id order by author.id",
+        )
+        .expect("unable to prepare")
+        .query_map([], |row| row.get(0))
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read rows");
+    assert_eq!(
+        authors
+            .into_iter()
+            .map(dbson::DBson::into_inner)
+            .collect::<Vec<_>>(),
+        vec![Author {
+            name: "Ursula".into(),
+            books: vec![
+                Book {
+                    title: "Lathe of Heaven".into(),
+                    year: 1971,
+                    rating: 4.0,
+                },
+                Book {
+                    title: "The Dispossessed".into(),
+                    year: 1974,
+                    rating: 4.5,
+                },
+            ],
+        }]
+    );
+
+    // existing blobs nest as their value, empty groups are empty containers
+    let (nested, empty_array): (dbson::DBson<Vec<Vec<u32>>>, dbson::DBson<Vec<u32>>) = conn
+        .query_row(
+            "select bson_array(?1, bson_array()),
+                    (select bson_group_array(id) from author where id > 2)",
+            [dbson::DBson::new(vec![1u32, 2])],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .expect("Unable to query data");
+    assert_eq!(nested.into_inner(), vec![vec![1, 2], vec![]]);
+    assert!(empty_array.into_inner().is_empty());
+    let empty_object: dbson::DBson<std::collections::HashMap<String, u32>> = conn
+        .query_row(
+            "select bson_group_object(name, id) from author where id > 2",
+            [],
+            |row| row.get(0),
+        )
+        .expect("Unable to query data");
+    assert!(empty_object.into_inner().is_empty());
+
+    conn.query_row("select bson_object('a')", [], |row| {
+        row.get::<_, Vec<u8>>(0)
+    })
+    .expect_err("keys need a value");
+}
+
+#[test]
+pub fn rusqlite_modify_test() {
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Default)]
+    struct Counter {
+        name: String,
+        count: i64,
+        tags: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+    }
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute("create table test (id integer primary key, data blob)", [])
+        .expect("unable to execute");
+    let counter = Counter {
+        name: "visits".into(),
+        count: 1,
+        tags: vec!["web".into()],
+        note: None,
+    };
+    conn.execute(
+        "insert into test (data) values (?1), (?2)",
+        rusqlite::params![dbson::DBson::new(&counter), dbson::DBsonDoc::new(&counter)],
+    )
+    .expect("Unable to insert data");
+
+    conn.execute(
+        "update test set data = bson_set(data, '$.count', bson_extract(data, '$.count') + 1, '$.tags[#]', 'api')",
+        [],
+    )
+    .expect("unable to update");
+    // insert doesn't overwrite, remove of a missing path is a no-op
+    conn.execute(
+        "update test set data = bson_remove(bson_insert(data, '$.name', 'other', '$.note', 'hi'), '$.tags[0]', '$.missing')",
+        [],
+    )
+    .expect("unable to update");
+    let expected = Counter {
+        name: "visits".into(),
+        count: 2,
+        tags: vec!["api".into()],
+        note: Some("hi".into()),
+    };
+    let envelope: dbson::DBson<Counter> = conn
+        .query_row("select data from test where id = 1", [], |row| row.get(0))
+        .expect("Unable to query data");
+    assert_eq!(envelope.into_inner(), expected);
+    let bare: dbson::DBsonDoc<Counter> = conn
+        .query_row("select data from test where id = 2", [], |row| row.get(0))
+        .expect("Unable to query data");
+    assert_eq!(bare.into_inner(), expected);
+    // like json_set, a missing intermediate key leaves the value alone
+    let unchanged: dbson::DBson<Counter> = conn
+        .query_row(
+            "select bson_set(data, '$.missing.key', 1, '$.tags[5][0]', 'x') from test where id = 1",
+            [],
+            |row| row.get(0),
+        )
+        .expect("Unable to query data");
+    assert_eq!(unchanged.into_inner(), expected);
+
+    let patched: dbson::DBson<Counter> = conn
+        .query_row(
+            "select bson_patch(data, bson_object('count', 0, 'note', null)) from test where id = 1",
+            [],
+            |row| row.get(0),
+        )
+        .expect("Unable to query data");
+    assert_eq!(
+        patched.into_inner(),
+        Counter {
+            count: 0,
+            note: None,
+            ..expected
+        }
+    );
+
+    let removed: Option<Vec<u8>> = conn
+        .query_row(
+            "select bson_remove(data, '$') from test where id = 1",
+            [],
+            |row| row.get(0),
+        )
+        .expect("Unable to query data");
+    assert_eq!(removed, None);
+    conn.query_row(
+        "select bson_set(data, '$', 1) from test where id = 2",
+        [],
+        |row| row.get::<_, Vec<u8>>(0),
+    )
+    .expect_err("a bare document can't hold a scalar");
+}
+
+#[test]
+pub fn apply_update_test() {
+    use bson::doc;
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Clone)]
+    struct Profile {
+        name: String,
+        visits: i32,
+        score: f64,
+        tags: Vec<String>,
+        address: Address,
+    }
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Clone)]
+    struct Address {
+        #[serde(alias = "town")]
+        city: String,
+        zip: Option<u32>,
+    }
+    let profile = Profile {
+        name: "alice".into(),
+        visits: 1,
+        score: 2.0,
+        tags: vec!["a".into(), "b".into(), "a".into()],
+        address: Address {
+            city: "Paris".into(),
+            zip: Some(75001),
+        },
+    };
+    let mut data = dbson::DBson::new(profile.clone());
+    data.apply_update(&doc! {
+        "$inc": { "visits": 2 },
+        "$mul": { "score": 1.5 },
+        "$pull": { "tags": "a" },
+        "$push": { "tags": { "$each": ["c", "d", "e"], "$position": 0, "$slice": 3 } },
+        "$addToSet": { "tags": { "$each": ["c", "z"] } },
+        "$unset": { "address.zip": "" },
+        "$max": { "visits": 2 },
+        "$min": { "score": 10 },
+    })
+    .expect("Unable to apply the update");
+    assert_eq!(
+        data.clone().into_inner(),
+        Profile {
+            visits: 3,
+            score: 3.0,
+            tags: vec!["c".into(), "d".into(), "e".into(), "z".into()],
+            address: Address {
+                city: "Paris".into(),
+                zip: None,
+            },
+            ..profile.clone()
+        }
+    );
+
+    // the update has to produce a valid `Profile`, otherwise nothing changes
+    let before = data.clone();
+    let error = data
+        .apply_update(&doc! { "$set": { "name": "bob", "address.zip": "unknown" } })
+        .expect_err("zip is a number");
+    assert!(matches!(error, dbson::Error::Deserialize { .. }), "{error}");
+    assert_eq!(error.path(), Some("inner.address.zip"));
+    assert_eq!(data, before);
+    let error = data
+        .apply_update(&doc! { "$inc": { "name": 1 } })
+        .expect_err("name isn't a number");
+    assert!(matches!(error, dbson::Error::Update { .. }), "{error}");
+    assert_eq!(error.path(), Some("name"));
+    data.apply_update(&doc! { "name": "bob" })
+        .expect_err("replacement documents aren't updates");
+    // an existing null isn't a number, and it sorts before every number
+    let error = data
+        .apply_update(&doc! { "$inc": { "address.zip": 1 } })
+        .expect_err("zip is null");
+    assert!(matches!(error, dbson::Error::Update { .. }), "{error}");
+    assert_eq!(error.path(), Some("address.zip"));
+    data.apply_update(&doc! { "$min": { "address.zip": 75002 } })
+        .expect("Unable to apply the update");
+    assert_eq!(data.clone().into_inner().address.zip, None);
+    data.apply_update(&doc! { "$max": { "address.zip": 75002 } })
+        .expect("Unable to apply the update");
+    assert_eq!(data.clone().into_inner().address.zip, Some(75002));
+    // an index far past the end fails instead of allocating the gap
+    let error = data
+        .apply_update(&doc! { "$set": { "tags.20000000000": "x" } })
+        .expect_err("too far past the end");
+    assert!(matches!(error, dbson::Error::Update { .. }), "{error}");
+    assert_eq!(error.path(), Some("tags.20000000000"));
+    assert_eq!(data.clone().into_inner().tags, ["c", "d", "e", "z"]);
+
+    // $addToSet compares numbers by value, whatever their width
+    let mut ids = dbson::DBson::new(std::collections::BTreeMap::from([(
+        "ids".to_string(),
+        vec![5_u64, 7],
+    )]));
+    ids.apply_update(&doc! { "$addToSet": { "ids": { "$each": [5_i32, 7.0, 9_i64, 9_i32] } } })
+        .expect("Unable to apply the update");
+    assert_eq!(ids.into_inner()["ids"], vec![5, 7, 9]);
+
+    // the bare layout gives the same results
+    let mut doc = dbson::DBsonDoc::new(profile);
+    doc.apply_update(
+        &doc! { "$rename": { "address.city": "address.town" }, "$set": { "name": "bob" } },
+    )
+    .expect("Unable to apply the update");
+    let doc = doc.into_inner();
+    assert_eq!(
+        (doc.name.as_str(), doc.address.city.as_str()),
+        ("bob", "Paris")
+    );
+}
+
+#[test]
+pub fn rusqlite_update_test() {
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute("create table test (id integer primary key, data blob)", [])
+        .expect("unable to execute");
+    conn.execute(
+        "insert into test (data) values (?1)",
+        [dbson::DBson::new(std::collections::BTreeMap::from([(
+            "hits".to_string(),
+            vec![1],
+        )]))],
+    )
+    .expect("Unable to insert data");
+    let update = dbson::DBsonDoc::new(bson::doc! { "$push": { "hits": 2 } });
+    conn.execute("update test set data = bson_update(data, ?1)", [&update])
+        .expect("unable to update");
+    let data: dbson::DBson<std::collections::BTreeMap<String, Vec<i32>>> = conn
+        .query_row("select data from test", [], |row| row.get(0))
+        .expect("Unable to query data");
+    assert_eq!(data.into_inner()["hits"], vec![1, 2]);
+
+    let update = dbson::DBsonDoc::new(bson::doc! { "$inc": { "hits": 1 } });
+    let error = conn
+        .execute("update test set data = bson_update(data, ?1)", [&update])
+        .expect_err("hits is an array");
+    assert!(error.to_string().contains("at `hits`"), "{error}");
+}
+
+#[test]
+pub fn filter_test() {
+    use bson::doc;
+    use dbson::filter::Filter;
+    let user = doc! {
+        "name": "Alice",
+        "age": 31,
+        "score": 4.5,
+        "tags": ["admin", "ops"],
+        "address": { "city": "Paris", "zip": 75001 },
+        "orders": [{ "item": "book", "price": 12 }, { "item": "pen", "price": 2 }],
+        "manager": null,
+    };
+    let matches = |filter: bson::Document| {
+        Filter::new(&filter)
+            .unwrap_or_else(|e| panic!("{e}"))
+            .matches(&user)
+    };
+    // comparison
+    assert!(matches(
+        doc! { "age": 31, "score": { "$gt": 4, "$lte": 4.5 } }
+    ));
+    assert!(matches(doc! { "age": { "$gte": 31.0 } }));
+    assert!(!matches(doc! { "age": { "$gt": "30" } }));
+    assert!(matches(
+        doc! { "name": { "$in": ["Bob", "Alice"] }, "age": { "$nin": [1, 2] } }
+    ));
+    assert!(matches(
+        doc! { "address": { "city": "Paris", "zip": 75001 } }
+    ));
+    assert!(!matches(
+        doc! { "address": { "zip": 75001, "city": "Paris" } }
+    ));
+    assert!(matches(doc! { "address.city": { "$ne": "Lyon" } }));
+    // logical
+    assert!(matches(doc! { "$or": [{ "age": 1 }, { "name": "Alice" }] }));
+    assert!(!matches(
+        doc! { "$nor": [{ "age": 1 }, { "name": "Alice" }] }
+    ));
+    assert!(matches(
+        doc! { "$and": [{ "age": 31 }], "age": { "$not": { "$lt": 18 } } }
+    ));
+    // element
+    assert!(matches(
+        doc! { "manager": null, "boss": null, "boss.name": { "$exists": false } }
+    ));
+    assert!(!matches(doc! { "manager": { "$exists": false } }));
+    assert!(matches(
+        doc! { "age": { "$type": "number" }, "tags": { "$type": ["array", 2] } }
+    ));
+    // array
+    assert!(matches(doc! { "tags": "ops", "tags.0": "admin" }));
+    assert!(matches(
+        doc! { "tags": { "$all": ["ops", "admin"], "$size": 2 } }
+    ));
+    assert!(matches(doc! { "orders.price": { "$lt": 5 } }));
+    assert!(matches(
+        doc! { "orders": { "$elemMatch": { "item": "pen", "price": { "$lt": 5 } } } }
+    ));
+    assert!(!matches(
+        doc! { "orders": { "$elemMatch": { "item": "book", "price": { "$lt": 5 } } } }
+    ));
+    assert!(matches(
+        doc! { "tags": { "$elemMatch": { "$regex": "^o" } } }
+    ));
+    // evaluation
+    assert!(matches(
+        doc! { "name": { "$regex": "^ali", "$options": "i" } }
+    ));
+    assert!(matches(
+        doc! { "name": bson::Regex { pattern: "ce$".into(), options: String::new() } }
+    ));
+    assert!(matches(doc! { "age": { "$mod": [10, 1] } }));
+    let filter = dbson::filter::Filter::new(&doc! { "n": { "$mod": [-1_i64, 0] } })
+        .expect("Unable to parse the filter");
+    assert!(filter.matches(&doc! { "n": i64::MIN }));
+    assert!(filter.matches(&doc! { "n": i64::MIN as f64 }));
+
+    for invalid in [
+        doc! { "age": { "$near": 1 } },
+        doc! { "$where": "true" },
+        doc! { "$or": [] },
+        doc! { "name": { "$regex": "(" } },
+        doc! { "age": { "$type": "integer" } },
+    ] {
+        Filter::new(&invalid).expect_err("invalid filters are rejected");
+    }
+
+    // $pull takes the same conditions
+    let mut data = dbson::DBson::new(vec![doc! { "scores": [1, 5, 8, 3] }]);
+    data.apply_update(&doc! { "$pull": { "0.scores": { "$gte": 5 } } })
+        .expect("Unable to apply the update");
+    assert_eq!(data.into_inner(), vec![doc! { "scores": [1, 3] }]);
+}
+
+#[test]
+pub fn rusqlite_match_test() {
+    use bson::doc;
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    struct Person {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute("create table test (id integer primary key, data blob)", [])
+        .expect("unable to execute");
+    let people = [
+        ("ann", 25, &["a"][..]),
+        ("bob", 35, &["a", "b"]),
+        ("cid", 45, &["c"]),
+    ];
+    for (name, age, tags) in people {
+        let person = Person {
+            name: name.into(),
+            age,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        };
+        conn.execute(
+            "insert into test (data) values (?1)",
+            [dbson::DBson::new(person)],
+        )
+        .expect("Unable to insert data");
+    }
+    conn.execute("insert into test (data) values (null)", [])
+        .expect("Unable to insert data");
+
+    let filter = doc! { "age": { "$gt": 30 }, "tags": { "$in": ["a", "c"] } };
+    let in_sql: Vec<String> = conn
+        .prepare(
+            "select bson_extract(data, '$.name') from test where bson_match(data, ?1) order by id",
+        )
+        .expect("unable to prepare")
+        .query_map([dbson::DBsonDoc::new(&filter)], |row| row.get(0))
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read rows");
+    assert_eq!(in_sql, vec!["bob", "cid"]);
+
+    // the same filter in memory
+    let filter = dbson::filter::Filter::new(&filter).expect("valid filter");
+    let in_memory: Vec<String> = conn
+        .prepare("select data from test where data is not null order by id")
+        .expect("unable to prepare")
+        .query_map([], |row| row.get::<_, dbson::DBsonDoc<bson::Document>>(0))
+        .expect("unable to query")
+        .map(|row| row.expect("unable to read row").into_inner())
+        .filter_map(|doc| match doc.get("inner") {
+            Some(bson::Bson::Document(person)) if filter.matches(person) => {
+                person.get_str("name").ok().map(str::to_string)
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(in_memory, in_sql);
+
+    let error = conn
+        .query_row(
+            "select count(*) from test where bson_match(data, ?1)",
+            [dbson::DBsonDoc::new(doc! { "age": { "$bad": 1 } })],
+            |row| row.get::<_, i64>(0),
+        )
+        .expect_err("invalid filter");
+    assert!(
+        error.to_string().contains("unsupported operator `$bad`"),
+        "{error}"
+    );
+}
+
+#[cfg(feature = "rusqlite-functions")]
+#[test]
+pub fn sql_filter_test() {
+    use bson::doc;
+    use dbson::filter::{Dialect, Filter, SqlFilter, SqlParam};
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute_batch(
+        "create table test (id integer primary key, data blob);
+         create index test_age on test (bson_extract(data, '$.age'));",
+    )
+    .expect("unable to execute");
+    let docs = [
+        doc! { "name": "ann", "age": 25, "admin": true, "tags": ["a"] },
+        doc! { "name": "bob", "age": 35_i64, "admin": false, "tags": ["a", "b"] },
+        doc! { "name": "cid", "age": 45.5, "nick": null, "address": { "city": "Oslo" } },
+        doc! { "name": "dan", "age": "old", "inner": 1 },
+        doc! { "name": "eve" },
+        doc! { "name": "fay", "tags": ["b", null], "orders": [{ "price": 3 }, { "price": 8 }] },
+    ];
+    for doc in &docs {
+        conn.execute(
+            "insert into test (data) values (?1)",
+            [dbson::DBson::new(doc)],
+        )
+        .expect("Unable to insert data");
+    }
+
+    let filters = [
+        doc! {},
+        doc! { "age": { "$gte": 30 } },
+        doc! { "age": { "$gt": 20, "$lt": 40 } },
+        doc! { "age": { "$lt": "z" } },
+        doc! { "age": 35 },
+        doc! { "age": { "$ne": 35 } },
+        doc! { "age": { "$in": [25, 45.5, "old"] } },
+        doc! { "age": { "$nin": [25, 45.5] } },
+        doc! { "admin": true },
+        doc! { "admin": { "$exists": false } },
+        doc! { "nick": null },
+        doc! { "nick": { "$exists": true } },
+        doc! { "address.city": "Oslo" },
+        doc! { "inner": 1 },
+        doc! { "$or": [{ "name": "ann" }, { "age": { "$gt": 40 } }] },
+        doc! { "$nor": [{ "name": "ann" }, { "admin": false }] },
+        doc! { "age": { "$not": { "$gt": 30 } } },
+        doc! { "name": { "$regex": "^[ab]", "$options": "i" }, "age": { "$lt": 30 } },
+        // arrays are looked into like in Rust
+        doc! { "tags": "b" },
+        doc! { "tags": { "$ne": "a" } },
+        doc! { "tags": { "$in": ["x", "b"] }, "name": { "$nin": ["bob"] } },
+        doc! { "tags": { "$gte": "b" } },
+        doc! { "tags": null },
+        doc! { "orders.price": { "$lt": 5 } },
+        doc! { "orders.price": { "$not": { "$gt": 5 } } },
+        doc! { "orders.price": null },
+        doc! { "orders.price": { "$exists": true } },
+        doc! { "$or": [{ "tags": "a" }, { "orders.price": 8 }] },
+        doc! { "tags": { "$all": ["b"] } },
+        doc! { "tags": { "$size": 2 } },
+        doc! { "$or": [{ "tags": { "$all": ["a"] } }, { "name": "eve" }] },
+        doc! { "address": { "city": "Oslo" } },
+    ];
+    for filter in &filters {
+        let compiled = SqlFilter::new(filter, "data", Dialect::Sqlite).expect("valid filter");
+        assert!(compiled.residual.is_none());
+        let query = format!("select id from test where {} order by id", compiled.sql);
+        let in_sql: Vec<usize> = conn
+            .prepare(&query)
+            .expect("unable to prepare")
+            .query_map(rusqlite::params_from_iter(&compiled.params), |row| {
+                row.get(0)
+            })
+            .expect("unable to query")
+            .collect::<Result<_, _>>()
+            .expect("unable to read rows");
+        let matching = Filter::new(filter).expect("valid filter");
+        let in_memory: Vec<usize> = (1..=docs.len())
+            .filter(|id| matching.matches(&docs[id - 1]))
+            .collect();
+        assert_eq!(in_sql, in_memory, "{filter}");
+    }
+
+    // comparisons on a path use an index on its bson_extract
+    let compiled = SqlFilter::new(&doc! { "age": { "$gt": 30 } }, "data", Dialect::Sqlite)
+        .expect("valid filter");
+    let plan = conn
+        .prepare(&format!(
+            "explain query plan select id from test where {}",
+            compiled.sql
+        ))
+        .expect("unable to prepare")
+        .query_map(rusqlite::params_from_iter(&compiled.params), |row| {
+            row.get::<_, String>(3)
+        })
+        .expect("unable to query")
+        .collect::<Result<Vec<_>, _>>()
+        .expect("unable to read plan")
+        .join("\n");
+    assert!(plan.contains("USING INDEX test_age"), "{plan}");
+    assert!(!plan.contains("SCAN"), "{plan}");
+
+    let compiled = SqlFilter::new(
+        &doc! { "age": { "$gte": 18 }, "address.city": "Oslo", "tags": { "$size": 2 } },
+        "data",
+        Dialect::Postgres { envelope: true },
+    )
+    .expect("valid filter");
+    // the sql also keeps the rows where a field may be in an array, the residual checks them
+    assert_eq!(
+        compiled.sql,
+        "((((data #> '{inner,age}') >= $1::text::jsonb \
+         AND jsonb_typeof(data #> '{inner,age}') = 'number') \
+         OR jsonb_typeof(data #> '{inner,age}') = 'array') \
+         AND ((data #> '{inner,address,city}') = $2::text::jsonb \
+         OR (jsonb_typeof(data #> '{inner,address,city}') = 'array' \
+         OR (data #> '{inner,address,city}') IS NULL)))"
+    );
+    assert_eq!(
+        compiled.params,
+        vec![
+            SqlParam::Text("18".into()),
+            SqlParam::Text("\"Oslo\"".into())
+        ]
+    );
+    let residual = compiled.residual.expect("$size isn't compiled");
+    let person = |age: bson::Bson, tags: Vec<&str>| {
+        doc! { "age": age, "address": [{ "city": "Oslo" }], "tags": tags }
+    };
+    assert!(residual.matches(&person(bson::bson!([10, 20]), vec!["a", "b"])));
+    assert!(!residual.matches(&person(bson::bson!([10, 12]), vec!["a", "b"])));
+    assert!(!residual.matches(&person(bson::bson!(20), vec!["a"])));
+
+    let compiled = SqlFilter::new(
+        &doc! { "$or": [{ "a": null }, { "b": { "$regex": "x" } }] },
+        "doc",
+        Dialect::Postgres { envelope: false },
+    )
+    .expect("valid filter");
+    assert_eq!(compiled.sql, "TRUE");
+    assert!(compiled.params.is_empty());
+    assert!(compiled.residual.is_some());
+
+    assert!(SqlFilter::new(&doc! { "$where": "1" }, "data", Dialect::Sqlite).is_err());
+}
+
+#[cfg(feature = "rusqlite-functions")]
+#[test]
+pub fn rusqlite_compare_test() {
+    use bson::{doc, oid::ObjectId};
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute("create table test (id integer primary key, data blob)", [])
+        .expect("unable to execute");
+    let values = [
+        bson::Bson::Boolean(false),
+        bson::Bson::String("10".into()),
+        bson::Bson::Int64(10),
+        bson::Bson::ObjectId(ObjectId::parse_str("650000000000000000000000").expect("oid")),
+        bson::Bson::Array(vec![1.into()]),
+        bson::Bson::Double(2.5),
+        bson::Bson::Document(doc! { "a": 1 }),
+        bson::Bson::Null,
+        bson::Bson::String("9".into()),
+    ];
+    for value in &values {
+        conn.execute(
+            "insert into test (data) values (?1)",
+            [dbson::DBson::new(doc! { "v": value })],
+        )
+        .expect("Unable to insert data");
+    }
+    // null, numbers, strings, documents, arrays, ObjectId, bool
+    let expected = vec![8, 6, 3, 2, 9, 7, 5, 4, 1];
+    let sorted: Vec<i64> = conn
+        .prepare("select id from test order by bson_to_json(data, '$.v') collate bson, id")
+        .expect("unable to prepare")
+        .query_map([], |row| row.get(0))
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read rows");
+    assert_eq!(sorted, expected);
+
+    // the missing field sorts with null
+    let sorted: Vec<i64> = conn
+        .prepare(
+            "select id from test where bson_to_json(data, '$.v') >= '3' collate bson order by id",
+        )
+        .expect("unable to prepare")
+        .query_map([], |row| row.get(0))
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read rows");
+    assert_eq!(sorted, vec![1, 2, 3, 4, 5, 7, 9]);
+
+    let compare = |a: &str, b: &str| -> i64 {
+        conn.query_row(&format!("select bson_compare({a}, {b})"), [], |row| {
+            row.get(0)
+        })
+        .expect("unable to query")
+    };
+    assert_eq!(compare("1", "2.5"), -1);
+    assert_eq!(compare("2", "2.0"), 0);
+    assert_eq!(compare("'a'", "99"), 1);
+    assert_eq!(compare("NULL", "1"), -1);
+    assert_eq!(compare("bson_array(1, 2)", "bson_array(1)"), 1);
+    assert_eq!(compare("bson_object('a', 1)", "bson_array(1)"), -1);
+    assert_eq!(compare("x'00'", "bson_array()"), 1);
+}
+
+#[test]
+pub fn memcomparable_order_test() {
+    use dbson::codec::{Codec, Memcomparable};
+    use std::fmt::Debug;
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug)]
+    enum Shape {
+        Dot,
+        Circle(u8),
+        Rect { w: i16, h: i16 },
+    }
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug)]
+    struct Key {
+        tenant: Option<u32>,
+        name: String,
+        shape: Shape,
+        tags: Vec<String>,
+        bytes: serde_bytes::ByteBuf,
+    }
+    fn check<T: serde::Serialize + serde::de::DeserializeOwned + Ord + Debug>(values: Vec<T>) {
+        for a in &values {
+            let bytes = Memcomparable::to_vec(a).expect("Unable to encode");
+            let back: T = Memcomparable::from_slice(&bytes).expect("Unable to decode");
+            assert_eq!(&back, a);
+            for b in &values {
+                let other = Memcomparable::to_vec(b).expect("Unable to encode");
+                assert_eq!(bytes.cmp(&other), a.cmp(b), "{a:?} / {b:?}");
+            }
+        }
+    }
+    check(vec![i64::MIN, -300, -1, 0, 1, 255, 256, i64::MAX]);
+    check(vec![0u16, 1, 255, 256, u16::MAX]);
+    check(vec!['\0', 'a', 'z', 'é', '😀']);
+    check(
+        ["", "\0", "\0\0", "a", "a\0", "a\0b", "ab", "b"]
+            .map(String::from)
+            .to_vec(),
+    );
+    check(vec![
+        (1u32, "b".to_string()),
+        (1, "ba".into()),
+        (2, "".into()),
+        (10, "a".into()),
+    ]);
+    check(vec![None, Some(false), Some(true)]);
+    check(vec![vec![], vec![0u8], vec![0, 0], vec![0, 1], vec![1]]);
+    check(vec![
+        std::collections::BTreeMap::from([(1, 2)]),
+        std::collections::BTreeMap::from([(1, 2), (2, 0)]),
+        std::collections::BTreeMap::from([(1, 3)]),
+    ]);
+    let key = |tenant, name: &str, shape, tags: &[&str], bytes: &[u8]| Key {
+        tenant,
+        name: name.into(),
+        shape,
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+        bytes: serde_bytes::ByteBuf::from(bytes),
+    };
+    check(vec![
+        key(None, "z", Shape::Dot, &[], b""),
+        key(Some(1), "a", Shape::Dot, &["b"], b"\0"),
+        key(Some(1), "a", Shape::Circle(3), &[], b""),
+        key(Some(1), "a", Shape::Rect { w: -1, h: 5 }, &[], b""),
+        key(Some(1), "a", Shape::Rect { w: 2, h: -5 }, &[], b""),
+        key(Some(1), "a", Shape::Rect { w: 2, h: -5 }, &["a"], b""),
+        key(Some(1), "a", Shape::Rect { w: 2, h: -5 }, &["a"], b"\0\xff"),
+        key(Some(1), "a", Shape::Rect { w: 2, h: -5 }, &["a"], b"\x01"),
+    ]);
+
+    let floats = [
+        f64::NEG_INFINITY,
+        -1.5,
+        -0.0,
+        0.0,
+        1e-300,
+        2.0,
+        f64::INFINITY,
+    ];
+    let encoded: Vec<_> = floats
+        .iter()
+        .map(|f| Memcomparable::to_vec(f).expect("Unable to encode"))
+        .collect();
+    assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+    let back: f64 = Memcomparable::from_slice(&encoded[1]).expect("Unable to decode");
+    assert_eq!(back, -1.5);
+
+    assert!(Memcomparable::from_slice::<u32>(&[0, 0, 0, 1, 0]).is_err());
+    assert!(Memcomparable::from_slice::<String>(b"abc").is_err());
+}
+
+#[test]
+pub fn compressed_codec_test() {
+    use bson::doc;
+    use dbson::codec::{Compressed, DecompressError, Lz4, Snappy, Zstd};
+    use dbson::DBson;
+    let pages: Vec<String> = (0..200)
+        .map(|i| format!("page {} of the book", i % 7))
+        .collect();
+    let plain = DBson::new(pages.clone())
+        .to_vec()
+        .expect("Unable to encode");
+
+    fn check<A>(pages: &Vec<String>, plain: &[u8])
+    where
+        Compressed<A>: dbson::codec::Codec,
+    {
+        type Zipped<A> = DBson<Vec<String>, Compressed<A>>;
+        let zipped = Zipped::<A>::with_codec(pages.clone())
+            .to_vec()
+            .expect("Unable to encode");
+        assert!(zipped.starts_with(b"\xFFDBZ"));
+        assert!(zipped.len() < plain.len() / 4, "{} bytes", zipped.len());
+        // every algorithm reads blobs of the others, and uncompressed ones
+        for blob in [&zipped[..], plain] {
+            let back = Zipped::<Zstd>::from_slice(blob).expect("Unable to decode");
+            assert_eq!(&back.into_inner(), pages);
+            let back = Zipped::<Snappy>::from_slice(blob).expect("Unable to decode");
+            assert_eq!(&back.into_inner(), pages);
+        }
+    }
+    check::<Zstd>(&pages, &plain);
+    check::<Zstd<19>>(&pages, &plain);
+    check::<Lz4>(&pages, &plain);
+    check::<Snappy>(&pages, &plain);
+
+    // values below the threshold are stored as they are behind the header
+    let small = DBson::<_, Compressed<Zstd>>::with_codec(vec![1, 2, 3])
+        .to_vec()
+        .expect("Unable to encode");
+    let bytes = DBson::new(vec![1, 2, 3])
+        .to_vec()
+        .expect("Unable to encode");
+    assert_eq!(small, [&b"\xFFDBZ\x00"[..], &bytes].concat());
+    // so bytes which look like a header can't be mistaken for one
+    type Tagged = DBson<(u8, u8, u8, u8, u8), Compressed<Lz4, dbson::codec::Postcard>>;
+    let tag = (0xFF, b'D', b'B', b'Z', 1);
+    let blob = Tagged::with_codec(tag).to_vec().expect("Unable to encode");
+    assert_eq!(&blob[5..], b"\xFFDBZ\x01");
+    assert_eq!(
+        Tagged::from_slice(&blob)
+            .expect("Unable to decode")
+            .into_inner(),
+        tag
+    );
+
+    // blobs decompressing to more than the maximum fail before filling the memory
+    type Bomb = DBson<String, Compressed<Zstd, dbson::codec::Postcard>>;
+    let bomb = Bomb::with_codec("0".repeat(dbson::codec::DEFAULT_MAX_DECOMPRESSED_SIZE))
+        .to_vec()
+        .expect("Unable to encode");
+    assert!(bomb.len() < 100_000, "{} bytes", bomb.len());
+    let error = Bomb::from_slice(&bomb).unwrap_err();
+    assert!(
+        matches!(
+            error,
+            dbson::Error::Decompress {
+                codec: "zstd+postcard",
+                source: DecompressError::TooLarge { .. },
+                ..
+            }
+        ),
+        "{error}"
+    );
+    let lying = b"\xFFDBZ\x02\xFF\xFF\xFF\x7F\x10abc";
+    let error = DBson::<Vec<String>, Compressed<Lz4>>::from_slice(lying).unwrap_err();
+    assert!(error.to_string().contains("maximum"), "{error}");
+    assert!(
+        matches!(
+            error,
+            dbson::Error::Decompress {
+                source: DecompressError::TooLarge {
+                    max: dbson::codec::DEFAULT_MAX_DECOMPRESSED_SIZE
+                },
+                ..
+            }
+        ),
+        "{error}"
+    );
+    let mut corrupt = DBson::<_, Compressed<Lz4>>::with_codec(pages.clone())
+        .to_vec()
+        .expect("Unable to encode");
+    corrupt.truncate(corrupt.len() / 2);
+    let error = DBson::<Vec<String>, Compressed<Lz4>>::from_slice(&corrupt).unwrap_err();
+    assert!(
+        matches!(
+            error,
+            dbson::Error::Decompress {
+                codec: "lz4+bson",
+                source: DecompressError::Corrupt {
+                    algorithm: "lz4",
+                    offset: 9,
+                    ..
+                },
+                ..
+            }
+        ),
+        "{error}"
+    );
+    let error = DBson::<Vec<String>, Compressed<Lz4>>::from_slice(b"\xFFDBZ\x09abc").unwrap_err();
+    assert!(
+        matches!(
+            error,
+            dbson::Error::Decompress {
+                source: DecompressError::UnknownAlgorithm {
+                    id: 9,
+                    feature: None
+                },
+                ..
+            }
+        ),
+        "{error}"
+    );
+    let error = DBson::<Vec<String>, Compressed<Lz4>>::from_slice(b"\xFFDBZ").unwrap_err();
+    assert!(
+        matches!(
+            error,
+            dbson::Error::Decompress {
+                source: DecompressError::Truncated { offset: 4 },
+                ..
+            }
+        ),
+        "{error}"
+    );
+
+    // the sql functions see through the compression
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute("create table test (data blob)", [])
+        .expect("unable to execute");
+    let book = doc! { "title": "book", "pages": pages.clone() };
+    conn.execute(
+        "insert into test values (?1), (?2)",
+        (
+            DBson::<_, Compressed<Zstd>>::with_codec(book.clone()),
+            DBson::new(doc! { "title": "leaflet" }),
+        ),
+    )
+    .expect("unable to insert");
+    let titles: Vec<String> = conn
+        .prepare(
+            "select bson_extract(data, '$.title') from test where bson_valid(data) order by rowid",
+        )
+        .expect("unable to prepare")
+        .query_map([], |row| row.get(0))
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read");
+    assert_eq!(titles, vec!["book", "leaflet"]);
+    let data: DBson<bson::Document, Compressed<Zstd>> = conn
+        .query_row(
+            "select data from test where bson_match(data, bson_object('title', 'book'))",
+            [],
+            |row| row.get(0),
+        )
+        .expect("unable to query");
+    assert_eq!(data.into_inner(), book);
+}
+
+#[test]
+pub fn rusqlite_dictionary_test() {
+    use bson::doc;
+    use dbson::codec::{Bson, Compressed, ZstdDict};
+    use dbson::DBson;
+    type Log = DBson<bson::Document, Compressed<ZstdDict<11>, Bson, 0>>;
+    let line = |i: i32| {
+        let level = ["info", "warn"][i as usize % 2];
+        doc! { "level": level, "service": "api", "message": "request handled", "request": i }
+    };
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    dbson::rusqlite::register_functions(&conn).expect("Unable to register functions");
+    conn.execute("create table logs (data blob)", [])
+        .expect("unable to execute");
+    for i in 0..500 {
+        conn.execute("insert into logs values (?1)", [DBson::new(line(i))])
+            .expect("unable to insert");
+    }
+    assert!(Log::with_codec(line(0)).to_vec().is_err());
+    dbson::rusqlite::train_dictionary(&conn, "logs", "data", 11, 2048)
+        .expect("Unable to train dictionary");
+    assert!(dbson::rusqlite::train_dictionary(&conn, "logs", "data", 11, 2048).is_err());
+
+    let compressed = Log::with_codec(line(500))
+        .to_vec()
+        .expect("Unable to encode");
+    let plain = DBson::new(line(500)).to_vec().expect("Unable to encode");
+    assert_eq!(&compressed[..9], b"\xFFDBZ\x04\x0b\0\0\0");
+    let error = Log::from_slice(b"\xFFDBZ\x04\x63\0\0\0abc").unwrap_err();
+    assert!(
+        matches!(
+            error,
+            dbson::Error::Decompress {
+                source: dbson::codec::DecompressError::MissingDictionary { id: 99 },
+                ..
+            }
+        ),
+        "{error}"
+    );
+    assert!(
+        compressed.len() < plain.len() / 2,
+        "{} bytes",
+        compressed.len()
+    );
+    conn.execute("insert into logs values (?1)", [Log::with_codec(line(500))])
+        .expect("unable to insert");
+    let (request, log): (i64, Log) = conn
+        .query_row(
+            "select bson_extract(data, '$.request'), data from logs where bson_extract(data, '$.request') = 500",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .expect("unable to query");
+    assert_eq!((request, log.into_inner()), (500, line(500)));
+    // the old values are still read as they are
+    let logs: Vec<Log> = conn
+        .prepare("select data from logs")
+        .expect("unable to prepare")
+        .query_map([], |row| row.get(0))
+        .expect("unable to query")
+        .collect::<Result<_, _>>()
+        .expect("unable to read");
+    assert_eq!(logs.len(), 501);
+
+    // dictionaries stored by another program are loaded from their table
+    let other = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    assert_eq!(
+        dbson::rusqlite::load_dictionaries(&other).expect("Unable to load"),
+        0
+    );
+    let samples: Vec<_> = (0..500)
+        .map(|i| DBson::new(line(i)).to_vec().expect("Unable to encode"))
+        .collect();
+    let trained = dbson::dictionary::train(&samples, 1024).expect("Unable to train");
+    other
+        .execute("insert into dbson_dictionaries values (12, ?1)", [&trained])
+        .expect("unable to insert");
+    assert!(dbson::dictionary::get(12).is_none());
+    assert_eq!(
+        dbson::rusqlite::load_dictionaries(&other).expect("Unable to load"),
+        1
+    );
+    assert_eq!(dbson::dictionary::get(12).as_deref(), Some(&trained[..]));
+    assert!(dbson::dictionary::train(&samples[..2], 1024).is_err());
+}
+
+#[tokio::test]
+pub async fn sqlx_dictionary_test() {
+    use dbson::codec::{Bson, Compressed, ZstdDict};
+    use dbson::DBson;
+    type Event = DBson<(String, u32), Compressed<ZstdDict<21>, Bson, 0>>;
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:")
+        .await
+        .expect("Unable to open sqlite pool");
+    sqlx::query("create table events (data blob)")
+        .execute(&pool)
+        .await
+        .expect("unable to execute");
+    for i in 0..500 {
+        sqlx::query("insert into events values (?1)")
+            .bind(DBson::new((format!("event {}", i % 20), i)))
+            .execute(&pool)
+            .await
+            .expect("unable to insert");
+    }
+    dbson::sqlx::train_dictionary(&pool, "events", "data", 21, 1024)
+        .await
+        .expect("Unable to train dictionary");
+    assert_eq!(
+        dbson::sqlx::load_dictionaries(&pool)
+            .await
+            .expect("Unable to load dictionaries"),
+        1
+    );
+    sqlx::query("insert into events values (?1)")
+        .bind(Event::with_codec(("event 500".into(), 500)))
+        .execute(&pool)
+        .await
+        .expect("unable to insert");
+    let events: Vec<Event> = sqlx::query_scalar("select data from events order by rowid desc")
+        .fetch_all(&pool)
+        .await
+        .expect("unable to query");
+    assert_eq!(events.len(), 501);
+    let last = events.into_iter().next().map(DBson::into_inner);
+    assert_eq!(last, Some(("event 500".to_string(), 500)));
+}
+
+#[test]
+pub fn rusqlite_memcomparable_key_test() {
+    use dbson::codec::Memcomparable;
+    type Key = dbson::DBson<(u32, String), Memcomparable>;
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    conn.execute("create table test (key blob primary key, value text)", [])
+        .expect("unable to execute");
+    let keys = [(2, "a"), (1, "b"), (10, ""), (1, "ab"), (2, "")];
+    for (id, name) in keys {
+        let key = Key::with_codec((id, name.to_string()));
+        conn.execute("insert into test (key, value) values (?1, ?2)", (key, name))
+            .expect("Unable to insert data");
+    }
+    let from = Key::with_codec((1, "b".to_string()));
+    let to = Key::with_codec((10, String::new()));
+    let sorted: Vec<(u32, String)> = conn
+        .prepare("select key from test where key >= ?1 and key < ?2 order by key")
+        .expect("unable to prepare")
+        .query_map([from, to], |row| row.get::<_, Key>(0))
+        .expect("unable to query")
+        .map(|key| key.expect("unable to read row").into_inner())
+        .collect();
+    assert_eq!(
+        sorted,
+        vec![
+            (1, "b".to_string()),
+            (2, String::new()),
+            (2, "a".to_string())
+        ]
+    );
+    let duplicate = Key::with_codec((2, "a".to_string()));
+    assert!(conn
+        .execute("insert into test (key) values (?1)", [duplicate])
+        .is_err());
+}
+
+#[cfg(feature = "rusqlite-functions")]
+#[test]
+pub fn rusqlite_collection_test() {
+    use bson::doc;
+    use dbson::rusqlite::Collection;
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Clone)]
+    struct Person {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+    let person = |name: &str, age, tags: &[&str]| Person {
+        name: name.into(),
+        age,
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+    };
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    let people: Collection<Person> =
+        Collection::new(&conn, "my \"people\"").expect("Unable to create collection");
+    let ann = people
+        .insert_one(&person("ann", 25, &["a"]))
+        .expect("Unable to insert");
+    let ids = people
+        .insert_many(&[
+            person("bob", 35, &["a", "b"]),
+            person("cid", 45, &["c"]),
+            person("dan", 55, &[]),
+        ])
+        .expect("Unable to insert");
+    assert_eq!(ids.len(), 3);
+    assert_eq!(
+        people.get(&ann).expect("Unable to get"),
+        Some(person("ann", 25, &["a"]))
+    );
+    assert_eq!(people.count(&doc! {}).expect("Unable to count"), 4);
+    assert_eq!(
+        people
+            .count(&doc! { "age": { "$gte": 35 }, "tags": { "$all": ["a"] } })
+            .expect("Unable to count"),
+        1
+    );
+
+    // cursors read in batches and keep insertion order
+    let names: Vec<String> = people
+        .find(&doc! { "age": { "$gt": 20 } })
+        .expect("Unable to find")
+        .batch_size(2)
+        .map(|found| found.expect("Unable to read").1.name)
+        .collect();
+    assert_eq!(names, vec!["ann", "bob", "cid", "dan"]);
+    // array fields match on any of their elements, like in Rust
+    let names: Vec<String> = people
+        .find(&doc! { "tags": "a", "age": { "$ne": 25 } })
+        .expect("Unable to find")
+        .map(|found| found.expect("Unable to read").1.name)
+        .collect();
+    assert_eq!(names, vec!["bob"]);
+    assert_eq!(
+        people
+            .count(&doc! { "tags": { "$nin": ["a", "c"] } })
+            .expect("Unable to count"),
+        1
+    );
+    let (id, found) = people
+        .find_one(&doc! { "name": { "$in": ["cid", "dan"] } })
+        .expect("Unable to find")
+        .expect("cid is there");
+    assert_eq!((id, found.name.as_str()), (ids[1], "cid"));
+    assert!(people
+        .find_one(&doc! { "name": "eve" })
+        .expect("Unable to find")
+        .is_none());
+
+    assert_eq!(
+        people
+            .update_one(
+                &doc! { "age": { "$gt": 30 } },
+                &doc! { "$inc": { "age": 1 } }
+            )
+            .expect("Unable to update"),
+        1
+    );
+    assert_eq!(
+        people
+            .update_many(&doc! {}, &doc! { "$push": { "tags": "x" } })
+            .expect("Unable to update"),
+        4
+    );
+    assert_eq!(
+        people.get(&ids[0]).expect("Unable to get"),
+        Some(person("bob", 36, &["a", "b", "x"]))
+    );
+
+    // an update that doesn't fit `Person` fails and leaves every value untouched
+    let error = people
+        .update_many(&doc! {}, &doc! { "$set": { "age": "old" } })
+        .expect_err("age has to be a number");
+    assert!(error.to_string().contains("age"), "{error}");
+    let rusqlite::Error::UserFunctionError(error) = error else {
+        panic!("not a user function error: {error}");
+    };
+    assert!(error.downcast_ref::<dbson::Error>().is_some(), "{error}");
+    assert_eq!(
+        people
+            .count(&doc! { "age": { "$type": "string" } })
+            .expect("Unable to count"),
+        0
+    );
+    let error = people
+        .count(&doc! { "$where": "true" })
+        .expect_err("$where isn't supported");
+    let rusqlite::Error::UserFunctionError(error) = error else {
+        panic!("not a user function error: {error}");
+    };
+    assert!(
+        error.downcast_ref::<dbson::filter::FilterError>().is_some(),
+        "{error}"
+    );
+
+    assert_eq!(
+        people
+            .replace_one(&doc! { "name": "cid" }, &person("cy", 1, &[]))
+            .expect("Unable to replace"),
+        1
+    );
+    assert_eq!(
+        people.get(&ids[1]).expect("Unable to get"),
+        Some(person("cy", 1, &[]))
+    );
+    assert_eq!(
+        people
+            .delete_one(&doc! { "tags": { "$size": 2 } })
+            .expect("Unable to delete"),
+        1
+    );
+    assert_eq!(people.get(&ann).expect("Unable to get"), None);
+    assert_eq!(
+        people
+            .delete_many(&doc! { "age": { "$lt": 50 } })
+            .expect("Unable to delete"),
+        2
+    );
+    assert_eq!(people.count(&doc! {}).expect("Unable to count"), 1);
+
+    // integer ids are assigned by sqlite, and the table can be reopened
+    let numbers: Collection<Vec<i32>, i64> =
+        Collection::new(&conn, "numbers").expect("Unable to create collection");
+    let ids = numbers
+        .insert_many(&[vec![1], vec![2, 3]])
+        .expect("Unable to insert");
+    assert_eq!(ids, vec![1, 2]);
+    let numbers: Collection<Vec<i32>, i64> =
+        Collection::new(&conn, "numbers").expect("Unable to open collection");
+    assert_eq!(numbers.get(&2).expect("Unable to get"), Some(vec![2, 3]));
+    let raw: i64 = conn
+        .query_row(
+            "select bson_extract(data, '$[1]') from numbers where id = 2",
+            [],
+            |row| row.get(0),
+        )
+        .expect("Unable to query");
+    assert_eq!(raw, 3);
+}
+
+#[cfg(feature = "rusqlite-functions")]
+#[test]
+pub fn rusqlite_collection_index_test() {
+    use bson::doc;
+    use dbson::filter::{Dialect, Index, SqlFilter};
+    use dbson::rusqlite::Collection;
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open sqlite connection");
+    let users: Collection<bson::Document> =
+        Collection::new(&conn, "users").expect("Unable to create collection");
+    users
+        .insert_many(&[
+            doc! { "email": "ann@example.com", "city": "Oslo", "age": 25 },
+            doc! { "email": "bob@example.com", "city": "Oslo", "age": 35, "nick": "b" },
+            doc! { "city": "Rome", "age": 45 },
+        ])
+        .expect("Unable to insert");
+    assert_eq!(
+        users
+            .create_index(&Index::ascending("email").unique().sparse())
+            .expect("Unable to create index"),
+        "email_1"
+    );
+    assert_eq!(
+        users
+            .create_index(&Index::ascending("city").and_descending("age"))
+            .expect("Unable to create index"),
+        "city_1_age_-1"
+    );
+    users
+        .create_index(&Index::ascending("nick").name("by_nick"))
+        .expect("Unable to create index");
+    // creating an existing index does nothing
+    users
+        .create_index(&Index::ascending("nick").name("by_nick"))
+        .expect("Unable to create index");
+    assert_eq!(
+        users.list_index_names().expect("Unable to list indexes"),
+        vec!["by_nick", "city_1_age_-1", "email_1"]
+    );
+
+    // the compiled filters read the fields with the indexed expressions
+    let plan = |filter: bson::Document| {
+        let compiled = SqlFilter::new(&filter, "data", Dialect::Sqlite).expect("valid filter");
+        let query = format!(
+            "EXPLAIN QUERY PLAN SELECT rowid, id, data FROM users WHERE rowid > ?{} AND {} \
+             ORDER BY rowid LIMIT ?{}",
+            compiled.params.len() + 1,
+            compiled.sql,
+            compiled.params.len() + 2,
+        );
+        let mut params: Vec<Box<dyn rusqlite::ToSql>> = compiled
+            .params
+            .into_iter()
+            .map(|param| Box::new(param) as Box<dyn rusqlite::ToSql>)
+            .collect();
+        params.push(Box::new(0));
+        params.push(Box::new(100));
+        conn.prepare(&query)
+            .expect("Unable to prepare")
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                row.get::<_, String>(3)
+            })
+            .expect("Unable to query")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Unable to read plan")
+            .join("\n")
+    };
+    let by_email = plan(doc! { "email": "bob@example.com" });
+    assert!(by_email.contains("USING INDEX users_email_1"), "{by_email}");
+    let by_city = plan(doc! { "city": "Oslo", "age": { "$gt": 30 } });
+    assert!(
+        by_city.contains("USING INDEX users_city_1_age_-1"),
+        "{by_city}"
+    );
+    let (_, bob) = users
+        .find_one(&doc! { "city": "Oslo", "age": { "$gt": 30 } })
+        .expect("Unable to find")
+        .expect("bob is there");
+    assert_eq!(bob.get_str("email"), Ok("bob@example.com"));
+
+    // unique indexes reject duplicates, sparse ones skip the values without the field
+    let error = users
+        .insert_one(&doc! { "email": "ann@example.com" })
+        .expect_err("the email is taken");
+    assert!(error.to_string().contains("UNIQUE"), "{error}");
+    users
+        .insert_one(&doc! { "city": "Oslo" })
+        .expect("values without an email aren't indexed");
+    let error = users
+        .update_many(&doc! {}, &doc! { "$set": { "email": "same@example.com" } })
+        .expect_err("the emails would be equal");
+    assert!(error.to_string().contains("UNIQUE"), "{error}");
+    assert_eq!(
+        users
+            .count(&doc! { "email": "same@example.com" })
+            .expect("Unable to count"),
+        0
+    );
+    let error = Collection::<bson::Document>::new(&conn, "dupes")
+        .and_then(|dupes| {
+            dupes.insert_many(&[doc! { "a": 1 }, doc! { "a": 1 }])?;
+            dupes.create_index(&Index::ascending("a").unique())
+        })
+        .expect_err("the values already have duplicates");
+    assert!(error.to_string().contains("UNIQUE"), "{error}");
+
+    assert!(users.create_index(&Index::ascending("a\"b")).is_err());
+    users.drop_index("by_nick").expect("Unable to drop index");
+    users.drop_index("by_nick").expect("Unable to drop index");
+    assert_eq!(
+        users.list_index_names().expect("Unable to list indexes"),
+        vec!["city_1_age_-1", "email_1"]
+    );
+}
+
+#[cfg(all(feature = "sqlx-sqlite", feature = "rusqlite-functions"))]
+#[tokio::test]
+pub async fn sqlx_collection_test() {
+    use sqlx::sqlite::SqlitePoolOptions;
+    // with the bson functions the filters run in sqlite, without them in Rust
+    let plain = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Unable to open sqlite pool");
+    sqlx_collection(plain, false).await;
+    let registered = SqlitePoolOptions::new()
+        .max_connections(1)
+        .after_connect(|conn, _| Box::pin(dbson::sqlx::register_functions(conn)))
+        .connect("sqlite::memory:")
+        .await
+        .expect("Unable to open sqlite pool");
+    sqlx_collection(registered, true).await;
+}
+
+#[cfg(all(feature = "sqlx-sqlite", feature = "rusqlite-functions"))]
+async fn sqlx_collection(pool: sqlx::SqlitePool, registered: bool) {
+    use bson::doc;
+    use dbson::filter::Index;
+    use dbson::sqlx::Collection;
+    use futures_util::TryStreamExt;
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Clone)]
+    struct Person {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+    let person = |name: &str, age, tags: &[&str]| Person {
+        name: name.into(),
+        age,
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+    };
+    let people: Collection<sqlx::Sqlite, Person> = Collection::new(pool.clone(), "my \"people\"")
+        .await
+        .expect("Unable to create collection");
+    let ann = people
+        .insert_one(&person("ann", 25, &["a"]))
+        .await
+        .expect("Unable to insert");
+    let ids = people
+        .insert_many(&[
+            person("bob", 35, &["a", "b"]),
+            person("cid", 45, &["c"]),
+            person("dan", 55, &[]),
+        ])
+        .await
+        .expect("Unable to insert");
+    assert_eq!(ids.len(), 3);
+    // indexes are built from the bson functions
+    let index = people
+        .create_index(&Index::ascending("name").unique())
+        .await;
+    match registered {
+        true => assert_eq!(index.expect("Unable to create index"), "name_1"),
+        false => assert!(matches!(index, Err(sqlx::Error::Configuration(_)))),
+    }
+    assert_eq!(
+        people.get(&ann).await.expect("Unable to get"),
+        Some(person("ann", 25, &["a"]))
+    );
+    assert_eq!(people.count(&doc! {}).await.expect("Unable to count"), 4);
+    assert_eq!(
+        people
+            .count(&doc! { "age": { "$gte": 35 }, "tags": { "$all": ["a"] } })
+            .await
+            .expect("Unable to count"),
+        1
+    );
+
+    let names: Vec<String> = people
+        .find(&doc! { "age": { "$gt": 20 } })
+        .expect("Unable to find")
+        .map_ok(|(_, found)| found.name)
+        .try_collect()
+        .await
+        .expect("Unable to read");
+    assert_eq!(names, vec!["ann", "bob", "cid", "dan"]);
+    // array fields match on any of their elements, whether the filter runs in sql or in Rust
+    let names: Vec<String> = people
+        .find(&doc! { "tags": "a", "age": { "$ne": 25 } })
+        .expect("Unable to find")
+        .map_ok(|(_, found)| found.name)
+        .try_collect()
+        .await
+        .expect("Unable to read");
+    assert_eq!(names, vec!["bob"]);
+    assert_eq!(
+        people
+            .count(&doc! { "tags": { "$nin": ["a", "c"] } })
+            .await
+            .expect("Unable to count"),
+        1
+    );
+    let (id, found) = people
+        .find_one(&doc! { "name": { "$in": ["cid", "dan"] } })
+        .await
+        .expect("Unable to find")
+        .expect("cid is there");
+    assert_eq!((id, found.name.as_str()), (ids[1], "cid"));
+    assert!(people
+        .find_one(&doc! { "name": "eve" })
+        .await
+        .expect("Unable to find")
+        .is_none());
+    assert!(people.find(&doc! { "$where": "true" }).is_err());
+
+    assert_eq!(
+        people
+            .update_one(
+                &doc! { "age": { "$gt": 30 } },
+                &doc! { "$inc": { "age": 1 } }
+            )
+            .await
+            .expect("Unable to update"),
+        1
+    );
+    assert_eq!(
+        people
+            .update_many(&doc! {}, &doc! { "$push": { "tags": "x" } })
+            .await
+            .expect("Unable to update"),
+        4
+    );
+    assert_eq!(
+        people.get(&ids[0]).await.expect("Unable to get"),
+        Some(person("bob", 36, &["a", "b", "x"]))
+    );
+
+    // an update that doesn't fit `Person` fails and leaves every value untouched
+    people
+        .update_many(&doc! {}, &doc! { "$set": { "age": "old" } })
+        .await
+        .expect_err("age has to be a number");
+    assert_eq!(
+        people
+            .count(&doc! { "age": { "$type": "string" } })
+            .await
+            .expect("Unable to count"),
+        0
+    );
+
+    assert_eq!(
+        people
+            .replace_one(&doc! { "name": "cid" }, &person("cy", 1, &[]))
+            .await
+            .expect("Unable to replace"),
+        1
+    );
+    assert_eq!(
+        people.get(&ids[1]).await.expect("Unable to get"),
+        Some(person("cy", 1, &[]))
+    );
+    assert_eq!(
+        people
+            .delete_one(&doc! { "tags": { "$size": 2 } })
+            .await
+            .expect("Unable to delete"),
+        1
+    );
+    assert_eq!(people.get(&ann).await.expect("Unable to get"), None);
+    assert_eq!(
+        people
+            .delete_many(&doc! { "age": { "$lt": 50 } })
+            .await
+            .expect("Unable to delete"),
+        2
+    );
+    assert_eq!(people.count(&doc! {}).await.expect("Unable to count"), 1);
+
+    // integer ids are assigned by sqlite, and streams read past a batch of skipped rows
+    let numbers: Collection<sqlx::Sqlite, bson::Document, i64> = Collection::new(pool, "numbers")
+        .await
+        .expect("Unable to create collection");
+    let values: Vec<bson::Document> = (0..250).map(|n| doc! { "n": n }).collect();
+    let ids = numbers
+        .insert_many(&values)
+        .await
+        .expect("Unable to insert");
+    assert_eq!(ids, (1..=250).collect::<Vec<i64>>());
+    let found: Vec<(i64, bson::Document)> = numbers
+        .find(&doc! { "n": { "$gte": 240 } })
+        .expect("Unable to find")
+        .try_collect()
+        .await
+        .expect("Unable to read");
+    assert_eq!(found.len(), 10);
+    assert_eq!(found[0], (241, doc! { "n": 240 }));
+    assert_eq!(
+        numbers
+            .count(&doc! { "n": { "$mod": [2, 0] } })
+            .await
+            .expect("Unable to count"),
+        125
+    );
+}
+
+/// Keys `"1"` and `"2"`, encrypting with `current`.
+struct TestKeys {
+    current: &'static str,
+}
+
+impl dbson::encryption::KeyProvider for TestKeys {
+    fn current_key_id(&self) -> Result<String, dbson::codec::BoxError> {
+        Ok(self.current.into())
+    }
+
+    fn key(&self, id: &str) -> Result<dbson::encryption::Key, dbson::codec::BoxError> {
+        match id {
+            "1" => Ok([1; 32]),
+            "2" => Ok([2; 32]),
+            id => Err(format!("no key {id}").into()),
+        }
+    }
+}
+
+#[test]
+pub fn encrypted_dbson_test() {
+    use dbson::encryption::{EncryptedDBson, XChaCha20Poly1305};
+    let keys = TestKeys { current: "1" };
+    let value = (String::from("secret"), vec![1, 2, 3]);
+
+    let aes = EncryptedDBson::new(value.clone());
+    let blob = aes.encrypt_with(&keys).expect("Unable to encrypt");
+    assert_eq!(&blob[..7], b"\xFFDBE\x01\x011");
+    assert_eq!(dbson::encryption::key_id(&blob), Some("1"));
+    assert!(!blob.windows(6).any(|w| w == b"secret"));
+    // neither the value nor the associated data end up in logs
+    let debug = format!("{:?}", aes.clone().with_associated_data(b"users/1"));
+    assert_eq!(
+        debug,
+        "EncryptedDBson { inner: <redacted>, associated_data: <7 bytes> }"
+    );
+    // a fresh nonce every time
+    assert_ne!(blob, aes.encrypt_with(&keys).unwrap());
+    let decrypted = EncryptedDBson::<(String, Vec<i32>)>::decrypt_with(&keys, &blob, &[])
+        .expect("Unable to decrypt");
+    assert_eq!(decrypted.into_inner(), value);
+
+    type ChaCha = EncryptedDBson<(String, Vec<i32>), XChaCha20Poly1305>;
+    let blob = ChaCha::with_cipher(value.clone())
+        .encrypt_with(&keys)
+        .expect("Unable to encrypt");
+    assert_eq!(blob[4], 2);
+    let plaintext = dbson::DBson::new(&value).to_vec().unwrap();
+    assert_eq!(blob.len(), 4 + 2 + 1 + 24 + plaintext.len() + 16);
+    assert_eq!(
+        ChaCha::decrypt_with(&keys, &blob, &[])
+            .unwrap()
+            .into_inner(),
+        value
+    );
+
+    // values written with an older key stay readable after the current key changes
+    let rotated = TestKeys { current: "2" };
+    assert_eq!(
+        ChaCha::decrypt_with(&rotated, &blob, &[])
+            .unwrap()
+            .into_inner(),
+        value
+    );
+
+    // any change to the blob, header included, fails authentication
+    for i in [4 + 2, 4 + 3, blob.len() - 1] {
+        let mut tampered = blob.clone();
+        tampered[i] ^= 1;
+        let error = ChaCha::decrypt_with(&keys, &tampered, &[]).unwrap_err();
+        assert!(matches!(error, dbson::Error::Decrypt { .. }), "{error}");
+    }
+    let mut other_key = blob.clone();
+    other_key[6] = b'2';
+    assert!(ChaCha::decrypt_with(&keys, &other_key, &[]).is_err());
+
+    // associated data binds a value to its row
+    let row = ChaCha::with_cipher(value.clone()).with_associated_data(b"users/1".to_vec());
+    let blob = row.encrypt_with(&keys).unwrap();
+    assert!(ChaCha::decrypt_with(&keys, &blob, &[]).is_err());
+    let error = ChaCha::decrypt_with(&keys, &blob, b"users/2").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "failed to decrypt `(alloc::string::String, alloc::vec::Vec<i32>)` with key `1`: \
+         unable to decrypt with key `1`"
+    );
+    let decrypted = ChaCha::decrypt_with(&keys, &blob, b"users/1").unwrap();
+    assert_eq!(decrypted.associated_data(), b"users/1");
+
+    // unknown keys and blobs that aren't encrypted
+    let unknown = TestKeys { current: "3" };
+    let error = row.encrypt_with(&unknown).unwrap_err();
+    assert!(matches!(error, dbson::Error::Encrypt { .. }));
+    assert_eq!(
+        std::error::Error::source(&error).unwrap().to_string(),
+        "no key 3"
+    );
+    let plain = dbson::DBson::new(value).to_vec().unwrap();
+    assert_eq!(dbson::encryption::key_id(&plain), None);
+    let error = ChaCha::decrypt_with(&keys, &plain, &[]).unwrap_err();
+    assert!(matches!(error, dbson::Error::Decrypt { key_id: None, .. }));
+}
+
+#[test]
+pub fn rusqlite_encryption_test() {
+    use dbson::codec::{Compressed, MsgPack, Zstd};
+    use dbson::encryption::{EncryptedDBson, XChaCha20Poly1305};
+    dbson::encryption::set_key_provider(TestKeys { current: "1" });
+    type Notes = EncryptedDBson<Vec<String>, XChaCha20Poly1305, Compressed<Zstd, MsgPack, 0>>;
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open in memory connection");
+    conn.execute(
+        "create table users (id integer primary key, email blob, notes blob)",
+        [],
+    )
+    .expect("unable to create table");
+    let notes = vec!["the same note".to_string(); 50];
+    conn.execute(
+        "insert into users values (1, ?1, ?2)",
+        rusqlite::params![
+            EncryptedDBson::new("ann@example.com".to_string()),
+            Notes::with_cipher(notes.clone()),
+        ],
+    )
+    .expect("unable to insert");
+    let (email, stored_notes): (EncryptedDBson<String>, Notes) = conn
+        .query_row("select email, notes from users", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .expect("unable to query");
+    assert_eq!(email.into_inner(), "ann@example.com");
+    assert_eq!(stored_notes.into_inner(), notes);
+    // compressed before it's encrypted
+    let len: usize = conn
+        .query_row("select length(notes) from users", [], |row| row.get(0))
+        .unwrap();
+    assert!(len < 100, "{len}");
+
+    // a blob bound to row 1 can't be read from row 2
+    let email = EncryptedDBson::new("bob@example.com".to_string()).with_associated_data(b"users/1");
+    conn.execute("update users set email = ?1 where id = 1", [&email])
+        .unwrap();
+    conn.execute("insert into users select 2, email, notes from users", [])
+        .unwrap();
+    let blobs: Vec<(i64, Vec<u8>)> = conn
+        .prepare("select id, email from users order by id")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let read = |(id, blob): &(i64, Vec<u8>)| {
+        EncryptedDBson::<String>::decrypt(blob, format!("users/{id}").as_bytes())
+            .map(EncryptedDBson::into_inner)
+    };
+    assert_eq!(read(&blobs[0]).unwrap(), "bob@example.com");
+    assert!(read(&blobs[1]).is_err());
+    // FromSql reads without associated data
+    let error = conn
+        .query_row("select email from users", [], |row| {
+            row.get::<_, EncryptedDBson<String>>(0)
+        })
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        rusqlite::Error::FromSqlConversionFailure(..)
+    ));
+}
+
+#[tokio::test]
+pub async fn sqlx_encryption_test() {
+    use dbson::encryption::{Aes256Gcm, EncryptedDBson};
+    // the same provider as the rusqlite test, which may run at the same time
+    dbson::encryption::set_key_provider(TestKeys { current: "1" });
+    type Secret = EncryptedDBson<std::collections::HashMap<String, i64>, Aes256Gcm>;
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:")
+        .await
+        .expect("Unable to open sqlite pool");
+    sqlx::query("create table secrets (data blob)")
+        .execute(&pool)
+        .await
+        .expect("unable to execute");
+    let secret = std::collections::HashMap::from([("pin".to_string(), 1234)]);
+    sqlx::query("insert into secrets values (?1)")
+        .bind(Secret::new(secret.clone()))
+        .execute(&pool)
+        .await
+        .expect("unable to insert");
+    let stored: Secret = sqlx::query_scalar("select data from secrets")
+        .fetch_one(&pool)
+        .await
+        .expect("unable to query");
+    assert_eq!(stored.into_inner(), secret);
+    let blob: Vec<u8> = sqlx::query_scalar("select data from secrets")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(dbson::encryption::key_id(&blob), Some("1"));
+}
+
+#[test]
+pub fn key_ring_test() {
+    use dbson::encryption::{EncryptedDBson, KeyProvider, KeyRing};
+    let ring = std::sync::Arc::new(KeyRing::new("a", [1; 32]));
+    ring.add("b", [2; 32]);
+    assert_eq!(ring.current_key_id().unwrap(), "a");
+    assert_eq!(ring.key_ids(), ["a", "b"]);
+    let old = EncryptedDBson::new(1).encrypt_with(&ring).unwrap();
+
+    ring.set_current("b").unwrap();
+    assert!(ring.set_current("c").is_err());
+    let new = EncryptedDBson::new(2).encrypt_with(&ring).unwrap();
+    assert_eq!(dbson::encryption::key_id(&new), Some("b"));
+    for (blob, value) in [(&old, 1), (&new, 2)] {
+        let decrypted = EncryptedDBson::<i32>::decrypt_with(&ring, blob, &[]).unwrap();
+        assert_eq!(decrypted.into_inner(), value);
+    }
+    assert_eq!(
+        format!("{ring:?}"),
+        r#"KeyRing { current: "b", keys: ["a", "b"] }"#
+    );
+
+    // a blob moves to the current key, keeping its cipher and associated data
+    let blob = EncryptedDBson::<_, dbson::encryption::XChaCha20Poly1305>::with_cipher(3)
+        .with_associated_data(b"row")
+        .encrypt_with(&KeyRing::new("a", [1; 32]))
+        .unwrap();
+    assert!(dbson::encryption::reencrypt(&ring, &blob, b"other").is_err());
+    let moved = dbson::encryption::reencrypt(&ring, &blob, b"row")
+        .unwrap()
+        .expect("the blob is on an old key");
+    assert_eq!(dbson::encryption::key_id(&moved), Some("b"));
+    assert_eq!(moved[4], 2);
+    assert_eq!(
+        dbson::encryption::reencrypt(&ring, &moved, b"row").unwrap(),
+        None
+    );
+    ring.remove("a").unwrap();
+    assert!(ring.remove("b").is_err());
+    let decrypted = EncryptedDBson::<i32>::decrypt_with(&ring, &moved, b"row").unwrap();
+    assert_eq!(decrypted.into_inner(), 3);
+}
+
+#[test]
+pub fn rusqlite_reencryption_test() {
+    use dbson::encryption::{EncryptedDBson, KeyRing, Reencrypted, Reencryption};
+    let ring = KeyRing::new("1", [1; 32]);
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open in memory connection");
+    conn.execute_batch("create table secrets (id integer primary key, data blob)")
+        .unwrap();
+    for id in 1..=25_i64 {
+        let blob = EncryptedDBson::new(id)
+            .with_associated_data(format!("secrets/{id}"))
+            .encrypt_with(&ring)
+            .unwrap();
+        conn.execute("insert into secrets values (?1, ?2)", (id, blob))
+            .unwrap();
+    }
+    conn.execute("insert into secrets values (26, null)", [])
+        .unwrap();
+    // a plaintext blob stops the re-encryption half way
+    conn.execute(
+        "update secrets set data = ?1 where id = 15",
+        [dbson::DBson::new(15)],
+    )
+    .unwrap();
+
+    ring.rotate("2", [2; 32]);
+    let reencryption = Reencryption::new("secrets", "data")
+        .batch_size(10)
+        .associated_data(|id| format!("secrets/{id}").into_bytes());
+    let error = dbson::rusqlite::reencrypt(&conn, &reencryption, &ring).unwrap_err();
+    assert!(
+        matches!(error, rusqlite::Error::FromSqlConversionFailure(..)),
+        "{error}"
+    );
+    let checkpoint: i64 = conn
+        .query_row("select last_id from dbson_reencryption", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(checkpoint, 10);
+    let key_ids = |conn: &rusqlite::Connection| -> Vec<String> {
+        conn.prepare("select data from secrets where data is not null order by id")
+            .unwrap()
+            .query_map([], |row| row.get::<_, Vec<u8>>(0))
+            .unwrap()
+            .map(|blob| {
+                dbson::encryption::key_id(&blob.unwrap())
+                    .unwrap_or("-")
+                    .to_string()
+            })
+            .collect()
+    };
+    let ids = key_ids(&conn);
+    assert!(ids[..10].iter().all(|id| id == "2"));
+    assert!(ids[10..].iter().all(|id| id != "2"));
+
+    // resumes after the first batch, which stays on the new key
+    conn.execute("delete from secrets where id = 15", [])
+        .unwrap();
+    let done = dbson::rusqlite::reencrypt(&conn, &reencryption, &ring).unwrap();
+    assert_eq!(
+        done,
+        Reencrypted {
+            rewritten: 14,
+            up_to_date: 0
+        }
+    );
+    assert!(key_ids(&conn).iter().all(|id| id == "2"));
+    let checkpoints: i64 = conn
+        .query_row("select count(*) from dbson_reencryption", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(checkpoints, 0);
+
+    // everything is on the current key, the old one can go
+    ring.remove("1").unwrap();
+    let done = dbson::rusqlite::reencrypt(&conn, &reencryption, &ring).unwrap();
+    assert_eq!(done.up_to_date, 24);
+    let blob: Vec<u8> = conn
+        .query_row("select data from secrets where id = 7", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    let value = EncryptedDBson::<i64>::decrypt_with(&ring, &blob, b"secrets/7").unwrap();
+    assert_eq!(value.into_inner(), 7);
+}
+
+#[tokio::test]
+pub async fn sqlx_reencryption_test() {
+    use dbson::encryption::{EncryptedDBson, KeyRing, Reencryption};
+    let ring = std::sync::Arc::new(KeyRing::new("1", [1; 32]));
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:")
+        .await
+        .expect("Unable to open sqlite pool");
+    sqlx::query("create table secrets (id integer primary key, data blob)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    for id in 1..=12_i64 {
+        let blob = EncryptedDBson::new(format!("secret {id}"))
+            .encrypt_with(&ring)
+            .unwrap();
+        sqlx::query("insert into secrets values (?1, ?2)")
+            .bind(id)
+            .bind(blob)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    // a plaintext blob stops the re-encryption in the second batch
+    sqlx::query("update secrets set data = ?1 where id = 8")
+        .bind(dbson::DBson::new(8).to_vec().unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    ring.rotate("2", [2; 32]);
+    let reencryption = Reencryption::new("secrets", "data").batch_size(5);
+    dbson::sqlx::reencrypt(&pool, &reencryption, &ring)
+        .await
+        .expect_err("id 8 isn't encrypted");
+    let checkpoint: i64 = sqlx::query_scalar("select last_id from dbson_reencryption")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(checkpoint, 5);
+    // the failed batch is rolled back, so only the first one is on the new key
+    let blobs: Vec<Vec<u8>> = sqlx::query_scalar("select data from secrets order by id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    let key_ids: Vec<_> = blobs
+        .iter()
+        .map(|blob| dbson::encryption::key_id(blob).unwrap_or("-"))
+        .collect();
+    assert_eq!(
+        key_ids,
+        ["2", "2", "2", "2", "2", "1", "1", "-", "1", "1", "1", "1"]
+    );
+
+    // resumes after the first batch
+    sqlx::query("update secrets set data = ?1 where id = 8")
+        .bind(EncryptedDBson::new("secret 8").encrypt_with(&ring).unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+    let done = dbson::sqlx::reencrypt(&pool, &reencryption, &ring)
+        .await
+        .expect("Unable to re-encrypt");
+    assert_eq!((done.rewritten, done.up_to_date), (6, 1));
+    let checkpoints: i64 = sqlx::query_scalar("select count(*) from dbson_reencryption")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(checkpoints, 0);
+    ring.remove("1").unwrap();
+    let blobs: Vec<Vec<u8>> = sqlx::query_scalar("select data from secrets order by id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    for (i, blob) in blobs.iter().enumerate() {
+        assert_eq!(dbson::encryption::key_id(blob), Some("2"));
+        let secret = EncryptedDBson::<String>::decrypt_with(&ring, blob, &[]).unwrap();
+        assert_eq!(secret.into_inner(), format!("secret {}", i + 1));
+    }
+    let done = dbson::sqlx::reencrypt(&pool, &reencryption, &ring)
+        .await
+        .unwrap();
+    assert_eq!((done.rewritten, done.up_to_date), (0, 12));
+}
+
+#[test]
+pub fn field_encryption_test() {
+    use dbson::encryption::{Aes256GcmSiv, Encrypted, XChaCha20Poly1305};
+    use dbson::filter::{Dialect, Index, SqlFilter};
+    // the same provider as the other encryption tests, which may run at the same time
+    dbson::encryption::set_key_provider(TestKeys { current: "1" });
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Account {
+        login: String,
+        #[serde(with = "dbson::encrypted::deterministic")]
+        email: String,
+        #[serde(with = "dbson::encrypted")]
+        balance: i64,
+        pin: Encrypted<u16, XChaCha20Poly1305>,
+    }
+    let account = |i: i64| Account {
+        login: format!("user{i}"),
+        email: format!("user{i}@example.com"),
+        balance: i * 100,
+        pin: Encrypted::with_cipher(1000 + i as u16),
+    };
+
+    // encrypted fields are binary subtype 6, the others stay as they are
+    let first = bson::to_document(&account(1)).unwrap();
+    let second = bson::to_document(&account(1)).unwrap();
+    assert_eq!(first.get_str("login").unwrap(), "user1");
+    for field in ["email", "balance", "pin"] {
+        let Some(bson::Bson::Binary(binary)) = first.get(field) else {
+            panic!("{field} isn't a binary: {first}");
+        };
+        assert_eq!(binary.subtype, bson::spec::BinarySubtype::Encrypted);
+        assert_eq!(dbson::encryption::key_id(&binary.bytes), Some("1"));
+    }
+    // only the deterministic field encrypts the same way twice
+    assert_eq!(first.get("email"), second.get("email"));
+    assert_ne!(first.get("balance"), second.get("balance"));
+    assert_ne!(first.get("pin"), second.get("pin"));
+    let email = Encrypted::<_, Aes256GcmSiv>::with_cipher("user1@example.com");
+    assert_eq!(first.get("email"), Some(&email.to_bson().unwrap()));
+    let decoded: Account = bson::from_document(first.clone()).unwrap();
+    assert_eq!(decoded, account(1));
+    assert!(format!("{decoded:?}").contains("pin: Encrypted(<redacted>)"));
+
+    // a binary of another subtype isn't decrypted
+    let mut plain = first.clone();
+    if let Some(bson::Bson::Binary(binary)) = plain.get_mut("balance") {
+        binary.subtype = bson::spec::BinarySubtype::Generic;
+    }
+    let error = bson::from_document::<Account>(plain).unwrap_err();
+    assert!(
+        error.to_string().contains("expected binary subtype 6"),
+        "{error}"
+    );
+
+    // stored in a collection, plain fields and deterministic ones can be filtered on
+    let conn = rusqlite::Connection::open_in_memory().expect("Unable to open in memory connection");
+    dbson::rusqlite::register_functions(&conn).unwrap();
+    let accounts = dbson::rusqlite::Collection::<Account, i64>::new(&conn, "accounts").unwrap();
+    let values: Vec<_> = (1..=20).map(account).collect();
+    accounts.insert_many(&values).unwrap();
+    accounts.create_index(&Index::ascending("email")).unwrap();
+    let filter = bson::doc! { "email": Encrypted::<_, Aes256GcmSiv>::with_cipher("user7@example.com").to_bson().unwrap() };
+    let (id, found) = accounts.find_one(&filter).unwrap().expect("user7 by email");
+    assert_eq!((id, found), (7, account(7)));
+    let compiled = SqlFilter::new(&filter, "data", Dialect::Sqlite).expect("valid filter");
+    assert!(compiled.residual.is_none());
+    assert_eq!(compiled.sql, "bson_extract(data, '$.email') = ?1");
+    conn.execute_batch("ANALYZE").unwrap();
+    let plan = conn
+        .prepare(&format!(
+            "explain query plan select id from accounts where {}",
+            compiled.sql
+        ))
+        .expect("unable to prepare")
+        .query_map(rusqlite::params_from_iter(&compiled.params), |row| {
+            row.get::<_, String>(3)
+        })
+        .expect("unable to query")
+        .collect::<Result<Vec<_>, _>>()
+        .expect("unable to read plan")
+        .join("\n");
+    assert!(plan.contains("USING INDEX accounts_email_1"), "{plan}");
+    assert!(!plan.contains("SCAN"), "{plan}");
+    assert!(!plan.contains("MULTI-INDEX"), "{plan}");
+    let emails = ["user3@example.com", "user9@example.com"].map(|email| {
+        Encrypted::<_, Aes256GcmSiv>::with_cipher(email)
+            .to_bson()
+            .unwrap()
+    });
+    let filter = bson::doc! { "email": { "$in": emails.to_vec() } };
+    assert_eq!(accounts.count(&filter).unwrap(), 2);
+    assert_eq!(accounts.count(&bson::doc! { "login": "user3" }).unwrap(), 1);
+    let binaries: i64 = conn
+        .query_row(
+            "select count(*) from accounts where bson_type(data, '$.balance') = 'binData'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(binaries, 20);
+}